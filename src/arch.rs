@@ -0,0 +1,89 @@
+use super::*;
+use std::fs;
+use std::path::Path;
+
+/// Target architecture of the EFI application
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Arch {
+    #[default]
+    #[clap(name = "x86_64")]
+    X86_64,
+    #[clap(name = "aarch64")]
+    Aarch64,
+}
+
+impl Arch {
+    /// Name of the architecture as accepted by `--arch`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+        }
+    }
+
+    /// Detect the architecture from the machine field of a PE executable
+    pub fn detect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut file = fs::File::open(path)?;
+        match read_pe_machine(&mut file)? {
+            IMAGE_FILE_MACHINE_AMD64 => Ok(Arch::X86_64),
+            IMAGE_FILE_MACHINE_ARM64 => Ok(Arch::Aarch64),
+            machine => Err(Error::msg(format!(
+                "Unsupported PE machine type {:#06x}",
+                machine
+            ))),
+        }
+    }
+
+    /// Default qemu executable for this architecture
+    pub fn qemu_path(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "qemu-system-x86_64",
+            Arch::Aarch64 => "qemu-system-aarch64",
+        }
+    }
+
+    /// Path where the firmware expects a bootloader on removable media
+    pub fn boot_path(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "EFI/Boot/BootX64.efi",
+            Arch::Aarch64 => "EFI/Boot/BootAA64.efi",
+        }
+    }
+
+    /// Name of the virtio-serial controller device on the machine used for this architecture
+    pub fn virtio_serial_device(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "virtio-serial-pci",
+            Arch::Aarch64 => "virtio-serial-device",
+        }
+    }
+
+    /// Name of the virtio-gpu device on the machine used for this architecture
+    pub fn virtio_gpu_device(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "virtio-gpu-pci",
+            Arch::Aarch64 => "virtio-gpu-device",
+        }
+    }
+
+    /// Name of the pvpanic device on the machine used for this architecture
+    pub fn pvpanic_device(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "pvpanic",
+            Arch::Aarch64 => "pvpanic-pci",
+        }
+    }
+
+    /// Arguments qemu needs to emulate a machine suitable for this architecture
+    pub fn qemu_args(&self) -> Vec<String> {
+        match self {
+            Arch::X86_64 => Vec::new(),
+            Arch::Aarch64 => vec![
+                "-machine".to_string(),
+                "virt".to_string(),
+                "-cpu".to_string(),
+                "cortex-a57".to_string(),
+            ],
+        }
+    }
+}
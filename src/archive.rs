@@ -0,0 +1,133 @@
+//! Extraction of tar and zip archives into an image
+use super::*;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+/// Extract the tar (optionally gzip-compressed) or zip archive at `archive` into the directory
+/// `dest` of the image
+///
+/// The directory structure of the archive is preserved. Entries that would end up outside of
+/// `dest` are rejected and links are skipped, since FAT can not represent them.
+pub fn extract_archive(image: &mut EfiImage, archive: &Path, dest: &Path) -> Result<()> {
+    let read_error =
+        |err: std::io::Error| Error::msg(format!("Unable to read {}: {}", archive.display(), err));
+    let mut file = fs::File::open(archive).map_err(read_error)?;
+    let mut magic = [0u8; 4];
+    let len = file.read(&mut magic).map_err(read_error)?;
+    file.seek(SeekFrom::Start(0)).map_err(read_error)?;
+    match &magic[..len] {
+        [b'P', b'K', 3, 4] => extract_zip(image, file, dest),
+        [0x1f, 0x8b, ..] => extract_tar(image, flate2::read::GzDecoder::new(file), dest),
+        _ => extract_tar(image, file, dest),
+    }
+    .map_err(|err| Error::msg(format!("Unable to extract {}: {}", archive.display(), err)))
+}
+
+fn extract_tar<R: Read>(image: &mut EfiImage, reader: R, dest: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry_path(dest, &entry.path()?)?;
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => image.create_dir(&path)?,
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents)?;
+                image.set_file_contents(&path, contents)?;
+            }
+            // Extended headers are applied to the following entry by the tar crate
+            tar::EntryType::XGlobalHeader | tar::EntryType::XHeader => {}
+            _ => warn(format!(
+                "Skipping {} in archive, only files and directories are supported",
+                path.display()
+            ))?,
+        }
+    }
+    Ok(())
+}
+
+fn extract_zip(image: &mut EfiImage, file: fs::File, dest: &Path) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(file)?;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let path = entry_path(dest, Path::new(entry.name()))?;
+        if entry.is_dir() {
+            image.create_dir(&path)?;
+        } else {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            image.set_file_contents(&path, contents)?;
+        }
+    }
+    Ok(())
+}
+
+/// Path of an archive entry named `name` in the image when extracting to `dest`
+///
+/// Absolute names and names containing `..` are rejected.
+pub fn entry_path(dest: &Path, name: &Path) -> Result<PathBuf> {
+    let mut path = dest.to_path_buf();
+    for component in name.components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::CurDir => {}
+            _ => {
+                return Err(Error::msg(format!(
+                    "Archive entry {} is outside of the destination",
+                    name.display()
+                )))
+            }
+        }
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_path() {
+        assert_eq!(
+            entry_path(Path::new("EFI"), Path::new("./Drivers/a.efi")).unwrap(),
+            Path::new("EFI/Drivers/a.efi")
+        );
+        assert!(entry_path(Path::new("EFI"), Path::new("../a.efi")).is_err());
+        assert!(entry_path(Path::new("EFI"), Path::new("a/../../b")).is_err());
+        assert!(entry_path(Path::new(""), Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn test_extract_tar() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let archive_path = temp_dir.path().join("tree.tar");
+        let mut builder = tar::Builder::new(fs::File::create(&archive_path).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        builder
+            .append_data(&mut header, "empty/", std::io::empty())
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        builder
+            .append_data(&mut header, "Drivers/a.efi", &b"data"[..])
+            .unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        let image_path = temp_dir.path().join("image.fat");
+        let mut image = EfiImage::new(&image_path, 10 * 0x10_0000).unwrap();
+        extract_archive(&mut image, &archive_path, Path::new("EFI")).unwrap();
+        assert_eq!(image.read_file("EFI/Drivers/a.efi").unwrap(), b"data");
+        drop(image);
+        let disk = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&image_path)
+            .unwrap();
+        let fs = fatfs::FileSystem::new(disk, fatfs::FsOptions::new()).unwrap();
+        assert!(fs.root_dir().open_dir("EFI/empty").is_ok());
+    }
+}
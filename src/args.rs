@@ -1,6 +1,16 @@
 use super::*;
 use clap::Parser;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// Output format of the run report
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Human-readable messages
+    #[default]
+    Text,
+    /// A JSON report printed to stdout when qemu exits
+    Json,
+}
 
 /// Command line arguments for uefi-run
 #[derive(Parser, Debug, Default, PartialEq)]
@@ -13,48 +23,895 @@ use std::path::PathBuf;
 )]
 pub struct Args {
     /// Bios image
-    #[clap(long, short = 'b', default_value = "OVMF.fd")]
-    pub bios_path: String,
+    ///
+    /// If not provided, the firmware is taken from `OVMF_PATH` (`AAVMF_PATH` for aarch64), then
+    /// from `OVMF.fd` in the current directory and finally from the locations used by common
+    /// distributions. If given multiple times, the image is run with each firmware in turn.
+    #[clap(long, short = 'b')]
+    pub bios_path: Vec<String>,
     /// Path to qemu executable
-    #[clap(long, short = 'q', default_value = "qemu-system-x86_64")]
-    pub qemu_path: String,
+    ///
+    /// Defaults to the qemu system emulator of the target architecture.
+    #[clap(long, short = 'q')]
+    pub qemu_path: Option<String>,
+    /// Architecture of the EFI executable
+    ///
+    /// If not provided, the architecture is detected from the EFI executable and defaults to
+    /// x86_64 if detection fails.
+    #[clap(long, short = 'a', value_enum)]
+    pub arch: Option<Arch>,
+    /// UEFI variable store template (e.g. `OVMF_VARS.fd`)
+    ///
+    /// A copy of the variable store is attached as flash device. The bios image has to be the
+    /// matching code part of the firmware (e.g. `OVMF_CODE.fd`).
+    #[clap(long, value_name = "VARS")]
+    pub vars: Option<PathBuf>,
+    /// Attach the code part of the firmware given with `--vars` writable instead of read-only
+    ///
+    /// The firmware file itself is modified if the guest writes to it.
+    #[clap(long, requires = "vars")]
+    pub bios_writable: bool,
+    /// Enroll an X.509 certificate (DER or PEM) as PK, KEK and db and enable secure boot
+    #[clap(long, value_name = "CERT", requires = "vars")]
+    pub enroll_key: Option<PathBuf>,
     /// Size of the image in MiB
-    #[clap(long, short = 's', default_value_t = 10)]
+    ///
+    /// Size of the image in MiB. The suffixes `K`, `M` and `G` can be used to specify the size in
+    /// KiB, MiB or GiB instead.
+    #[clap(long, short = 's', default_value = "10", value_parser = parse_size)]
     pub size: u64,
+    /// FAT type of the image (12, 16 or 32)
+    ///
+    /// By default FAT32 is used for images of at least 33280 KiB, which is the smallest size it
+    /// supports, and FAT12 or FAT16 for smaller ones.
+    #[clap(long, visible_alias = "fat-type", value_parser = parse_fat_type)]
+    pub fat: Option<FatType>,
+    /// Number of entries in the root directory of FAT12/16 images (a multiple of 16)
+    ///
+    /// The default of 512 entries can be exhausted when many files are added to the root of the
+    /// image. Long file names take up several entries.
+    #[clap(long, value_name = "N")]
+    pub root_entries: Option<u16>,
+    /// Number of file allocation tables in the image (1 or 2, 2 by default)
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..=2))]
+    pub num_fats: Option<u8>,
+    /// Fail if a name in the image does not fit the 8.3 format, for firmware without long file
+    /// name support
+    ///
+    /// Long file name entries are still written, but such firmware finds every file under its
+    /// short name. The build stamp of `--stamp` does not fit the format.
+    #[clap(long)]
+    pub short_names: bool,
     /// Additional files to be added to the efi image
     ///
     /// Additional files to be added to the efi image. If no inner location is provided, it will
-    /// default to the root of the image with the same name as the provided file.
-    #[clap(long, short = 'f')]
-    pub add_file: Vec<String>,
+    /// default to the root of the image with the same name as the provided file. The syntax is
+    /// `outer[:inner[:attributes]]` where attributes is a comma separated list of `ro`, `hidden`,
+    /// `system` and `archive`. If the inner location ends with `/`, the file is placed in that
+    /// directory under its original name.
+    #[clap(long, short = 'f', value_parser = parse_add_file)]
+    pub add_file: Vec<(PathBuf, PathBuf, FileAttributes)>,
+    /// Extract a tar (optionally gzip-compressed) or zip archive into the image
+    ///
+    /// The syntax is `archive[:dest]`. The directory structure of the archive is preserved below
+    /// `dest`, which defaults to the root of the image.
+    #[clap(long, value_name = "ARCHIVE[:DEST]", value_parser = parse_add_archive)]
+    pub add_archive: Vec<(PathBuf, PathBuf)>,
+    /// Copy a host directory with its subdirectories into the image
+    ///
+    /// The syntax is `outer[:inner]`. The inner location defaults to a directory of the same name
+    /// in the root of the image. Symlinks are followed.
+    #[clap(long, value_name = "OUTER[:INNER]", value_parser = parse_add_dir)]
+    pub add_dir: Vec<(PathBuf, PathBuf)>,
+    /// Build a UEFI-bootable ISO embedding the image and exit
+    ///
+    /// Requires `xorriso`, `genisoimage` or `mkisofs`. With `--boot-iso` the ISO is booted as
+    /// CD-ROM instead of exiting.
+    #[clap(long, value_name = "PATH")]
+    pub output_iso: Option<PathBuf>,
+    /// Boot the ISO built with `--output-iso` instead of the FAT image
+    #[clap(long, requires = "output_iso")]
+    pub boot_iso: bool,
+    /// Write the built image to stdout and exit instead of running qemu
+    ///
+    /// Messages are only written to stderr, so the image can be piped to another program.
+    #[clap(long, conflicts_with_all = ["launch_only", "boot_iso", "print_config"])]
+    pub stdout_image: bool,
+    /// Create the image and other temporary files in this directory
+    ///
+    /// By default the temporary directory of the system is used, which may be too small for big
+    /// images.
+    #[clap(long, value_name = "DIR")]
+    pub temp_dir: Option<PathBuf>,
+    /// Keep the temporary directories with the image and other generated files and print their
+    /// paths
+    #[clap(long)]
+    pub keep: bool,
+    /// Attach a blank data disk of the given size (e.g. `64M`), created in the temporary directory
+    ///
+    /// With `SIZE:LABEL` the disk is formatted with FAT and gets the volume label `LABEL`. May be
+    /// given multiple times.
+    #[clap(long, value_name = "SIZE[:LABEL]", value_parser = parse_data_disk)]
+    pub data_disk: Vec<DataDisk>,
+    /// Write a JSON manifest with the path, size and SHA-256 of every file in the image
+    #[clap(long, value_name = "PATH")]
+    pub manifest: Option<PathBuf>,
+    /// Build the image at this path and keep it after qemu exits
+    ///
+    /// If the file exists, it has to be a FAT image. The application and additional files are
+    /// added to it, which allows using a prepared ESP.
+    #[clap(long, value_name = "PATH")]
+    pub image: Option<PathBuf>,
+    /// Do not replace a `startup.nsh` that already exists in the image given with `--image`
+    #[clap(long, requires = "image")]
+    pub keep_existing_startup: bool,
+    /// Use the contents of this file as `startup.nsh` instead of the default script
+    ///
+    /// The default script runs `run.efi` after the 5 second delay of the EFI shell.
+    #[clap(long, value_name = "PATH", conflicts_with_all = ["boot", "load_driver"])]
+    pub startup_nsh: Option<PathBuf>,
+    /// Do not write a `startup.nsh`, e.g. to start the application from the EFI shell by hand
+    #[clap(
+        long,
+        conflicts_with_all = ["boot", "load_driver", "startup_nsh", "keep_existing_startup"]
+    )]
+    pub no_startup_nsh: bool,
+    /// Run the existing image given with `--image` without adding anything to it
+    ///
+    /// The EFI executable is optional in this case and only used to detect the architecture.
+    #[clap(long, requires = "image", conflicts_with_all = ["keep_existing_startup", "output_iso"])]
+    pub launch_only: bool,
+    /// Read the image back after building it and fail if the filesystem is inconsistent or any
+    /// added file can not be read
+    #[clap(long)]
+    pub verify_image: bool,
+    /// Write BUILDINFO.txt with the version of uefi-run, the time and the SHA-256 of the EFI
+    /// executable to the root of the image
+    ///
+    /// The time is taken from `--source-date-epoch` if given, so the image stays reproducible.
+    #[clap(long)]
+    pub stamp: bool,
     /// EFI Executable
-    pub efi_exe: String,
+    #[clap(required_unless_present_any = ["qemu_version", "launch_only"])]
+    pub efi_exe: Option<String>,
     /// Additional arguments for qemu
+    ///
+    /// `{esp}` is replaced with the path of the image, e.g. to attach it with a custom `-drive`.
     pub qemu_args: Vec<String>,
+    /// Only pass the additional arguments and those of explicitly given options to qemu
+    ///
+    /// The firmware, the ESP, the machine arguments of the architecture and `-net none` are left
+    /// out, so the additional arguments have to attach the firmware and the ESP themselves. The
+    /// ESP can be referred to as `{esp}`.
+    #[clap(long, conflicts_with_all = ["drive", "floppy", "data_disk", "boot_iso"])]
+    pub no_default_args: bool,
     /// Load the application as a bootloader instead of in an EFI shell
     ///
     /// This effectively skips the 5 second startup delay.
     #[clap(long, short = 'd')]
     pub boot: bool,
+    /// Install the application both as bootloader and as `run.efi` started by `startup.nsh`
+    ///
+    /// This supports firmware that may take either path.
+    #[clap(long, conflicts_with = "boot")]
+    pub boot_and_run: bool,
+    /// Load a driver from the EFI shell before the application is started
+    ///
+    /// Drivers are copied to the driver directory and loaded in the order they are given.
+    #[clap(long, value_name = "DRIVER", conflicts_with = "boot")]
+    pub load_driver: Vec<PathBuf>,
+    /// Directory in the image the drivers are copied to
+    #[clap(long, value_name = "DIR", default_value = DEFAULT_DRIVER_DIR)]
+    pub driver_dir: PathBuf,
+    /// Attach an additional disk image (raw or qcow2)
+    ///
+    /// Drives get the id `driveN` with their index (see `--esp-index`). The id can be set
+    /// with `<IMAGE>,id=<name>`. To attach the drive with an explicit `-device` (e.g.
+    /// `-device nvme,drive=<name>,serial=1`) add `,if=none`.
+    #[clap(long, value_name = "IMAGE")]
+    pub drive: Vec<String>,
+    /// Pass a host PCI device (e.g. `01:00.0` or `0000:01:00.0`) through to the guest (Linux only)
+    ///
+    /// The device has to be bound to the vfio-pci driver, which requires an IOMMU and usually
+    /// root. It is not usable by the host while it is bound to vfio-pci.
+    #[clap(long, value_name = "BDF", value_parser = parse_pci_address)]
+    pub vfio_pci: Vec<String>,
+    /// Use a configuration that works without root
+    ///
+    /// The guest gets user-mode networking and runs with KVM only if `/dev/kvm` is accessible,
+    /// otherwise with TCG. Options that need privileges (tap interfaces, PCI passthrough and
+    /// negative nice values) are rejected.
+    #[clap(long, conflicts_with_all = ["tap", "vfio_pci"])]
+    pub unprivileged: bool,
+    /// Attach a USB device (e.g. `usb-kbd` or `usb-storage,drive=<id>`)
+    #[clap(long, value_name = "DEVICE")]
+    pub usb_device: Vec<String>,
+    /// USB controller the USB devices are attached to
+    #[clap(long, value_enum, default_value_t)]
+    pub usb_controller: UsbController,
+    /// Emulated VGA card (e.g. `std`, `cirrus`, `virtio` or `none`)
+    #[clap(long)]
+    pub vga: Option<String>,
+    /// Use a virtio-gpu device as display instead of a VGA card
+    #[clap(long, conflicts_with = "vga")]
+    pub virtio_gpu: bool,
+    /// Add a pvpanic device and exit with `--panic-exit-code` when the guest reports a panic
+    ///
+    /// Panics are detected over QMP, after which qemu is stopped.
+    #[clap(long)]
+    pub pvpanic: bool,
+    /// Add an isa-debug-exit device at I/O port 0xf4 and exit with the value the guest writes to
+    /// it (x86_64 only)
+    ///
+    /// qemu exits with `(value << 1) | 1`, which is translated back. A normal shutdown still
+    /// exits with 0. Writing 0 can not be told apart from qemu failing with exit code 1, so it
+    /// is reported as exit code 1. Guests signal success by shutting down.
+    #[clap(long)]
+    pub debug_exit: bool,
+    /// Exit code used when the guest panicked
+    #[clap(long, requires = "pvpanic", default_value_t = GUEST_PANIC_EXIT_CODE, value_name = "CODE")]
+    pub panic_exit_code: i32,
+    /// Resume from a snapshot stored in a qcow2 drive
+    ///
+    /// Snapshots can only be stored in qcow2 images, so a qcow2 image has to be attached with
+    /// `--drive`. The EFI system partition is attached read-only.
+    #[clap(long, value_name = "TAG")]
+    pub loadvm: Option<String>,
+    /// Attach the EFI system partition read-only
+    #[clap(long)]
+    pub esp_readonly: bool,
+    /// Attach a raw image as floppy drive (x86_64 only)
+    #[clap(long, value_name = "IMAGE")]
+    pub floppy: Vec<String>,
+    /// Drive index of the EFI system partition
+    ///
+    /// Drives added with `--drive` take the remaining indices in the order they are given.
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    pub esp_index: usize,
+    /// Attach the EFI system partition to this interface instead of the default one
+    ///
+    /// With `nvme` it is the namespace of an NVMe controller, which exercises the NVMe driver of
+    /// the firmware.
+    #[clap(
+        long,
+        value_enum,
+        value_name = "INTERFACE",
+        conflicts_with = "boot_iso"
+    )]
+    pub esp_interface: Option<EspInterface>,
+    /// Handle discard (TRIM) requests of the guest on the ESP and all other disks
+    ///
+    /// With `on` or `unmap` discarded ranges are freed in the image files if their host
+    /// filesystem supports sparse files. This works for raw and qcow2 images; CD-ROMs are not
+    /// affected. By default discard requests are ignored.
+    #[clap(long, value_enum, value_name = "MODE")]
+    pub discard: Option<DiscardMode>,
+    /// Only boot from the ESP: boot from disks only, disable network boot and the boot menu
+    ///
+    /// Drives added with `--drive` are disks as well and remain possible boot targets if the
+    /// ESP fails to boot.
+    #[clap(long)]
+    pub esp_only: bool,
+    /// Boot splash shown by the firmware (24-bit BMP)
+    ///
+    /// The image is passed over fw_cfg and enables the boot menu. SeaBIOS shows it, OVMF shows its
+    /// built-in logo instead.
+    #[clap(long, value_name = "BMP", conflicts_with = "esp_only")]
+    pub splash: Option<String>,
+    /// Timestamp (seconds since the Unix epoch) to use for all files in the image
+    ///
+    /// By default the current time is used, which makes the image differ between builds.
+    #[clap(long, env = "SOURCE_DATE_EPOCH", value_name = "SECONDS")]
+    pub source_date_epoch: Option<u64>,
+    /// Exit instead of rebooting when the guest resets
+    #[clap(long)]
+    pub no_reboot: bool,
+    /// Let the guest reboot this many times and exit at the next reboot
+    ///
+    /// Reboots are detected over QMP. This is useful for firmware updates that take several
+    /// reboots to complete.
+    #[clap(long, value_name = "N", conflicts_with = "no_reboot")]
+    pub reboot_count: Option<usize>,
+    /// Pause the guest instead of exiting when it shuts down
+    #[clap(long)]
+    pub no_shutdown: bool,
+    /// Give qemu's human monitor on stdio, so the guest can be inspected (e.g. with
+    /// `info registers`)
+    ///
+    /// qemu keeps running once the guest shut down until `quit` is entered. Nothing else may use
+    /// stdio, so this can not be combined with `-nographic` or `stdio` targets of `--serial`,
+    /// `--virtio-console` and `--debugcon`.
+    #[clap(long, conflicts_with_all = ["serial_timestamps", "phase_markers"])]
+    pub interactive_monitor: bool,
+    /// Action to take when the guest watchdog fires (e.g. reset, shutdown, poweroff, pause)
+    #[clap(long)]
+    pub watchdog_action: Option<String>,
+    /// Pin qemu to the given host CPUs (e.g. `0-3,6`, Linux only)
+    // Fully qualified, so clap parses one list instead of collecting multiple values
+    #[clap(long, value_name = "CPULIST", value_parser = parse_cpu_list)]
+    pub cpu_affinity: Option<::std::vec::Vec<usize>>,
+    /// Do not provide ACPI tables to the firmware, to test boot paths without ACPI
+    #[clap(long)]
+    pub no_acpi: bool,
+    /// CPU model of the guest with optional features (e.g. `host,+x2apic`)
+    #[clap(long, value_name = "MODEL")]
+    pub cpu: Option<String>,
+    /// Read the CPU model and features from a file
+    ///
+    /// The file contains a single `-cpu` value. It may be split across lines and `#` starts a
+    /// comment, e.g. a line `Skylake-Server` followed by lines `+avx512f` and `-hle`.
+    #[clap(long, value_name = "FILE", conflicts_with = "cpu")]
+    pub cpu_file: Option<PathBuf>,
+    /// Terminate qemu once it used this many seconds of CPU time (Unix only)
+    ///
+    /// Unlike a wall-clock timeout this only counts time qemu actually runs. Exceeding the limit
+    /// is reported with exit code 152.
+    #[clap(long, value_name = "SECONDS")]
+    pub cpu_time_limit: Option<u64>,
+    /// Stop qemu if it did not exit after this many seconds
+    ///
+    /// qemu is stopped the same way as on Ctrl-C and the exit code is 124 like for coreutils'
+    /// `timeout`.
+    #[clap(long, value_name = "SECONDS")]
+    pub timeout: Option<f64>,
+    /// Start qemu again up to N times if it is killed by a signal within a second of starting and
+    /// before the guest wrote any output
+    ///
+    /// This works around host problems in CI. The image is reused and exits of the guest are
+    /// never retried. qemu's stdout is forwarded through uefi-run to detect guest output. If
+    /// qemu still crashes, the exit code is 128 plus the signal number.
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    pub relaunch_on_crash: usize,
+    /// Limit the memory qemu may use, in MiB or with a `K`, `M` or `G` suffix (Linux with cgroup
+    /// v2 only)
+    ///
+    /// While qemu runs, uefi-run moves itself to a leaf cgroup and places qemu in a sibling of
+    /// it. The cgroup uefi-run is started in has to be delegated to the user, e.g. with
+    /// `systemd-run --user --scope -p Delegate=yes`. Exceeding the limit is reported with exit
+    /// code 137.
+    #[clap(long, value_name = "SIZE", value_parser = parse_size)]
+    pub memory_limit: Option<u64>,
+    /// Run qemu with this nice value (-20 to 19) to keep it from starving the host
+    ///
+    /// On Windows the value selects a priority class. Negative values usually require elevated
+    /// privileges.
+    #[clap(
+        long,
+        value_name = "N",
+        allow_negative_numbers = true,
+        value_parser = clap::value_parser!(i32).range(-20..=19)
+    )]
+    pub nice: Option<i32>,
+    /// Run qemu with a minimal environment instead of the environment of uefi-run
+    ///
+    /// Only `PATH` and the variables set with `--env-qemu` are passed on.
+    #[clap(long)]
+    pub clear_env: bool,
+    /// Set an environment variable for qemu
+    #[clap(long, value_name = "KEY=VALUE", value_parser = parse_env_var)]
+    pub env_qemu: Vec<(String, String)>,
+    /// Enable core dumps for qemu and report when it crashes (Unix only)
+    #[clap(long)]
+    pub qemu_core_dump: bool,
+    /// Interval in milliseconds in which uefi-run checks whether qemu exited or it was told to
+    /// terminate
+    #[clap(long, default_value_t = 500)]
+    pub poll_interval_ms: u64,
+    /// Time in milliseconds to wait before qemu is started
+    ///
+    /// This gives services qemu depends on (e.g. swtpm) time to come up.
+    #[clap(long, default_value_t = 0, value_name = "MS")]
+    pub startup_delay_ms: u64,
+    /// Run a shell command before qemu is started and abort if it fails
+    ///
+    /// The path of the image is passed in `UEFI_RUN_IMAGE`. The command runs before the startup
+    /// delay, so services it starts in the background have time to come up.
+    #[clap(long, value_name = "CMD")]
+    pub pre_launch: Option<String>,
+    /// Run a shell command after qemu exited
+    ///
+    /// The path of the image is passed in `UEFI_RUN_IMAGE` and the exit code in
+    /// `UEFI_RUN_EXIT_CODE`. A failure is reported as warning.
+    #[clap(long, value_name = "CMD")]
+    pub post_exit: Option<String>,
+    /// Guest memory, e.g. `2G` or `size=2G,slots=4,maxmem=8G` for memory hotplug
+    ///
+    /// Plain numbers are MiB like for qemu's `-m`.
+    #[clap(long, value_name = "SPEC", value_parser = parse_memory)]
+    pub memory: Option<QemuMemory>,
+    /// Plug a DIMM of this size into a memory slot given with `--memory`
+    ///
+    /// Each DIMM is backed by its own RAM memory backend, which exercises the ACPI memory hotplug
+    /// paths of the firmware.
+    #[clap(long, value_name = "SIZE", value_parser = parse_size, requires = "memory")]
+    pub memory_hotplug: Vec<u64>,
+    /// Add a NUMA node (e.g. `nodeid=0,cpus=0-1,mem=512M`)
+    ///
+    /// Add a NUMA node to the guest. Supported properties are `nodeid`, `cpus`, `mem`, `memdev`
+    /// and `initiator`. The nodes have to match the CPUs configured with `-smp` in the additional
+    /// qemu arguments and the memory configured with `--memory`.
+    #[clap(long, value_name = "NODE", value_parser = parse_numa_node)]
+    pub numa: Vec<String>,
+    /// Give the guest user-mode networking with DHCP and DNS but no inbound forwarding
+    ///
+    /// By default the guest has no network device.
+    #[clap(long)]
+    pub net_dns_only: bool,
+    /// Connect the guest to an existing tap interface with a virtio network card (Linux only)
+    ///
+    /// The interface has to be created (and usually bridged) beforehand, which typically
+    /// requires root or `CAP_NET_ADMIN`. No scripts are run to configure it.
+    #[clap(long, value_name = "IFNAME", conflicts_with = "net_dns_only")]
+    pub tap: Option<String>,
+    /// MAC address of the network card (e.g. `52:54:00:12:34:56`)
+    ///
+    /// By default qemu assigns the same address to every guest.
+    #[clap(long, value_parser = parse_mac)]
+    pub mac: Option<String>,
+    /// Give the network card a random locally administered MAC address
+    ///
+    /// With `--seed` the address is derived from the seed, so it is the same on every run.
+    #[clap(long, conflicts_with = "mac")]
+    pub random_mac: bool,
+    /// Seed qemu's guest random number generator for reproducible runs (qemu 4.0 and later)
+    ///
+    /// By default qemu seeds it randomly.
+    #[clap(long, value_name = "N")]
+    pub seed: Option<u64>,
+    /// Kernel to be booted by the firmware
+    #[clap(long)]
+    pub kernel: Option<String>,
+    /// Pass this device tree blob to the guest instead of the one qemu generates for the virt
+    /// machine (aarch64 only)
+    #[clap(long, value_name = "PATH")]
+    pub dtb: Option<String>,
+    /// Boot the kernel with qemu's built-in loader instead of through the firmware
+    ///
+    /// This bypasses UEFI entirely: no firmware is passed to qemu and the EFI executable is only
+    /// available to the kernel on the ESP. Useful to compare UEFI and direct boot of the same
+    /// payload.
+    #[clap(long, requires = "kernel", conflicts_with_all = ["bios_path", "vars"])]
+    pub direct_kernel: bool,
+    /// Initial ramdisk for the kernel
+    #[clap(long, requires = "kernel")]
+    pub initrd: Option<String>,
+    /// Pack a directory into a cpio archive and pass it to the kernel as initial ramdisk
+    #[clap(
+        long,
+        value_name = "DIR",
+        requires = "kernel",
+        conflicts_with = "initrd"
+    )]
+    pub initrd_from_dir: Option<PathBuf>,
+    /// Share a copy of this directory with the guest and update it when files on the host change
+    ///
+    /// The copy is exported read-only over virtio-9p with the mount tag `uefi-run`. The firmware
+    /// has no 9p driver, so the guest needs its own (e.g. a Linux kernel mounting it with
+    /// `mount -t 9p -o trans=virtio uefi-run /mnt`). Changes are picked up every poll interval.
+    #[clap(long, value_name = "DIR")]
+    pub watch_dir: Option<PathBuf>,
+    /// Kernel command line
+    #[clap(long, requires = "kernel", value_name = "CMDLINE")]
+    pub append: Option<String>,
+    /// Connect the first serial port to the given target
+    ///
+    /// Besides the targets of `--virtio-console`, `ringbuf:<bytes>` keeps the last output in
+    /// memory. It is printed once qemu exited or included in the JSON report. The size has to be
+    /// a power of two. `none` disables the serial port and any other target is taken as the path
+    /// of a file the output is written to. Use `stdio` to see the output of the application,
+    /// e.g. of `Print()`, on the terminal.
+    #[clap(long, value_name = "TARGET", value_parser = parse_serial_target)]
+    pub serial: Option<String>,
+    /// Attach a virtio console to the given target
+    ///
+    /// The target is `stdio`, `file:<path>`, `unix:<path>` or `tcp:<host>:<port>`. Sockets are
+    /// opened in server mode without waiting for a client.
+    #[clap(long, value_name = "TARGET", value_parser = parse_chardev_target)]
+    pub virtio_console: Option<String>,
+    /// Collect structured results the guest writes to the virtio-serial port
+    /// `org.uefi-run.results` through a Unix socket created at this path (Unix only)
+    ///
+    /// The guest writes JSON values, e.g. one per line. They are included in the report.
+    #[clap(long, value_name = "PATH")]
+    pub results_channel: Option<PathBuf>,
+    /// Write the firmware debug log to the given target (x86_64 only)
+    ///
+    /// OVMF writes its debug messages to the ISA debug console at I/O port 0x402, separate from
+    /// the serial output of the application. The target is given like for `--virtio-console`.
+    #[clap(long, value_name = "TARGET", value_parser = parse_chardev_target)]
+    pub debugcon: Option<String>,
+    /// Real time clock configuration of the guest (e.g. `base=localtime,clock=vm`)
+    #[clap(long, value_name = "RTC")]
+    pub rtc: Option<String>,
+    /// Bind the guest clock to the host clock in UTC (same as `--rtc base=utc,clock=host`)
+    #[clap(long, conflicts_with_all = ["rtc", "clock"])]
+    pub rtc_host: bool,
+    /// Clock driving the real time clock of the guest
+    ///
+    /// `host` follows the host time, `vm` stops while the guest is paused and `rt` follows the
+    /// host monotonic clock. By default qemu uses `host`.
+    #[clap(long, value_enum)]
+    pub clock: Option<RtcClock>,
+    /// Let the real time clock catch up on ticks lost while the guest was not scheduled (x86_64
+    /// only)
+    #[clap(long)]
+    pub rtc_driftfix: bool,
+    /// Prefix every line of qemu's output, e.g. the serial console on stdio, with the host time
+    ///
+    /// The time is given in seconds since the Unix epoch like the start time in the JSON report.
+    #[clap(long)]
+    pub serial_timestamps: bool,
+    /// Enable qemu's debug log for the given items (e.g. `int,cpu_reset`), written to stderr
+    ///
+    /// Run `qemu-system-x86_64 -d help` for the available items.
+    #[clap(long, value_name = "ITEMS")]
+    pub qemu_debug: Option<String>,
+    /// Write markers to the debug log when qemu is spawned, writes its first serial output and
+    /// exits
+    ///
+    /// This helps aligning qemu's debug log with the timeline of uefi-run.
+    #[clap(long, requires = "qemu_debug")]
+    pub phase_markers: bool,
+    /// Copy everything qemu prints to stdout and stderr to this file while still showing it
+    #[clap(long, value_name = "PATH", conflicts_with = "interactive_monitor")]
+    pub log_qemu_tee: Option<PathBuf>,
+    /// Suppress warnings printed by qemu
+    #[clap(long)]
+    pub quiet_qemu: bool,
+    /// Save a screenshot (PPM) of the display before qemu exits
+    #[clap(long, value_name = "PATH")]
+    pub screenshot: Option<PathBuf>,
+    /// Monitor qemu over QMP to report the run state of the guest when it exits
+    #[clap(long)]
+    pub qmp: bool,
+    /// Write the exit code, followed by the exit reason if known, to the given file before exiting
+    #[clap(long, value_name = "PATH")]
+    pub exit_code_file: Option<PathBuf>,
+    /// Print diagnostics, like the `startup.nsh` in the image, to stderr
+    #[clap(long, short = 'v')]
+    pub verbose: bool,
+    /// Output format of the run report
+    #[clap(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+    /// Attach metadata (e.g. `commit=1a2b3c`) to the JSON report. May be given multiple times.
+    #[clap(long, value_name = "KEY=VALUE", value_parser = parse_label)]
+    pub label: Vec<(String, String)>,
+    /// Treat warnings as errors
+    ///
+    /// Warnings are emitted when the architecture of the EFI executable or the qemu version can
+    /// not be detected, when QMP or a screenshot is not available, when the firmware has to be
+    /// replaced by one found on the host and when the exit code file can not be written.
+    #[clap(long)]
+    pub strict: bool,
+    /// Print the version of qemu and exit
+    #[clap(long)]
+    pub qemu_version: bool,
+    /// Print the effective configuration and the qemu command line and exit
+    ///
+    /// Honors `--format`. Files created for each run, like the image, are shown by placeholders.
+    #[clap(long)]
+    pub print_config: bool,
 }
 
-impl Args {
-    /// Parse `--add-file` arguments into `(outer, inner)` tuples of `PathBuf`
-    pub fn parse_add_file_args(&self) -> impl Iterator<Item = Result<(PathBuf, PathBuf)>> + '_ {
-        self.add_file.iter().map(|file| {
-            // Split the argument to get the inner and outer files
-            file.split_once(':')
-                .map(|(x, y)| Ok((PathBuf::from(x), PathBuf::from(y))))
-                .unwrap_or_else(|| {
-                    let outer = PathBuf::from(&file);
-                    let inner = PathBuf::from(&file)
-                        .file_name()
-                        .ok_or_else(|| Error::msg("Invalid --add-file argument"))?
-                        .into();
-                    Ok((outer, inner))
-                })
+/// Parse an `--add-file` argument into an `(outer, inner, attributes)` tuple
+pub fn parse_add_file(file: &str) -> Result<(PathBuf, PathBuf, FileAttributes)> {
+    // Split the argument to get the inner and outer files and the attributes
+    let mut parts = file.splitn(3, ':');
+    let outer = PathBuf::from(parts.next().unwrap_or_default());
+    let file_name = || {
+        outer
+            .file_name()
+            .ok_or_else(|| Error::msg(format!("Invalid file to add: {}", file)))
+    };
+    // Inner paths use `/` as separator on every host
+    let inner = match parts.next().map(|inner| inner.replace('\\', "/")) {
+        // A trailing separator denotes the directory the file is placed in
+        Some(inner) if is_dir_path(&inner) => PathBuf::from(inner).join(file_name()?),
+        Some(inner) => PathBuf::from(inner),
+        None => file_name()?.into(),
+    };
+    let attributes = match parts.next() {
+        Some(attributes) => parse_file_attributes(attributes)?,
+        None => FileAttributes::empty(),
+    };
+    Ok((outer, inner, attributes))
+}
+
+/// Whether a path ends with a separator and thereby denotes a directory
+///
+/// Both `/` and `\\` are separators on every host.
+pub fn is_dir_path(path: &str) -> bool {
+    path.ends_with(['/', '\\'])
+}
+
+/// Parse a comma separated list of FAT file attributes like `ro,hidden`
+pub fn parse_file_attributes(list: &str) -> Result<FileAttributes> {
+    list.split(',')
+        .map(|attribute| match attribute.trim() {
+            "ro" | "readonly" => Ok(FileAttributes::READ_ONLY),
+            "hidden" => Ok(FileAttributes::HIDDEN),
+            "system" => Ok(FileAttributes::SYSTEM),
+            "archive" => Ok(FileAttributes::ARCHIVE),
+            _ => Err(Error::msg(format!("Invalid file attribute: {}", attribute))),
         })
+        .collect()
+}
+
+/// Parse an image size like `10`, `512K` or `1G` into bytes (MiB if no suffix is given)
+pub fn parse_size(size: &str) -> Result<u64> {
+    let invalid = || Error::msg(format!("Invalid size: {}", size));
+    let size = size.trim();
+    let (number, unit) = match size.char_indices().last() {
+        Some((index, 'K')) | Some((index, 'k')) => (&size[..index], 0x400),
+        Some((index, 'M')) | Some((index, 'm')) => (&size[..index], 0x10_0000),
+        Some((index, 'G')) | Some((index, 'g')) => (&size[..index], 0x4000_0000),
+        _ => (size, 0x10_0000),
+    };
+    number
+        .trim_end()
+        .parse::<u64>()
+        .map_err(|_| invalid())?
+        .checked_mul(unit)
+        .ok_or_else(invalid)
+}
+
+/// Parse a FAT type given as `12`, `16` or `32`
+pub fn parse_fat_type(fat_type: &str) -> Result<FatType> {
+    match fat_type.trim_start_matches("fat").trim_start_matches("FAT") {
+        "12" => Ok(FatType::Fat12),
+        "16" => Ok(FatType::Fat16),
+        "32" => Ok(FatType::Fat32),
+        _ => Err(Error::msg(format!("Invalid FAT type: {}", fat_type))),
+    }
+}
+
+/// Translate a serial target like `ringbuf:65536` or a console target into a qemu character
+/// device backend
+pub fn parse_serial_target(target: &str) -> Result<String> {
+    match target.strip_prefix("ringbuf:") {
+        Some(size) => match size.parse::<u64>() {
+            Ok(size) if size.is_power_of_two() => Ok(format!("ringbuf,size={}", size)),
+            _ => Err(Error::msg(format!(
+                "Invalid ring buffer size {}: expected a power of two",
+                size
+            ))),
+        },
+        None if target == "none" => Ok(target.to_string()),
+        None => parse_chardev_target(target).or_else(|err| {
+            // Anything that does not look like a console target is a file path
+            match target.split_once(':') {
+                Some(("file" | "unix" | "tcp", _)) => Err(err),
+                _ if target.is_empty() => Err(err),
+                _ => Ok(format!("file,path={}", escape_option_value(target))),
+            }
+        }),
+    }
+}
+
+/// Escape a value of a qemu option list, in which commas are doubled
+fn escape_option_value(value: &str) -> String {
+    value.replace(',', ",,")
+}
+
+/// Translate a console target like `file:console.log` into a qemu character device backend
+pub fn parse_chardev_target(target: &str) -> Result<String> {
+    let invalid = || Error::msg(format!("Invalid console target: {}", target));
+    match target.split_once(':') {
+        None if target == "stdio" => Ok("stdio".to_string()),
+        Some(("file", path)) if !path.is_empty() => {
+            Ok(format!("file,path={}", escape_option_value(path)))
+        }
+        Some(("unix", path)) if !path.is_empty() => Ok(format!(
+            "socket,path={},server=on,wait=off",
+            escape_option_value(path)
+        )),
+        Some(("tcp", address)) => {
+            let (host, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+            port.parse::<u16>().map_err(|_| invalid())?;
+            Ok(format!(
+                "socket,host={},port={},server=on,wait=off",
+                host, port
+            ))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Parse a PCI address like `01:00.0` into the full `domain:bus:device.function` form
+pub fn parse_pci_address(address: &str) -> Result<String> {
+    let invalid = || Error::msg(format!("Invalid PCI address: {}", address));
+    let parts = address.split(':').collect::<Vec<_>>();
+    let (domain, bus, slot) = match parts[..] {
+        [bus, slot] => ("0", bus, slot),
+        [domain, bus, slot] => (domain, bus, slot),
+        _ => return Err(invalid()),
+    };
+    let (device, function) = slot.split_once('.').ok_or_else(invalid)?;
+    let parse = |value: &str, max: u32| {
+        u32::from_str_radix(value, 16)
+            .ok()
+            .filter(|&value| value <= max)
+            .ok_or_else(invalid)
+    };
+    Ok(format!(
+        "{:04x}:{:02x}:{:02x}.{:x}",
+        parse(domain, 0xffff)?,
+        parse(bus, 0xff)?,
+        parse(device, 0x1f)?,
+        parse(function, 0x7)?
+    ))
+}
+
+/// Parse a MAC address given with `--mac`
+pub fn parse_mac(mac: &str) -> Result<String> {
+    check_mac(mac)?;
+    Ok(mac.to_lowercase())
+}
+
+/// Parse a `--data-disk` argument of the form `size[:label]`
+pub fn parse_data_disk(arg: &str) -> Result<DataDisk> {
+    let (size, label) = match arg.split_once(':') {
+        Some((size, label)) => (size, Some(label.to_string())),
+        None => (arg, None),
+    };
+    Ok(DataDisk {
+        size: parse_size(size)?,
+        label,
+    })
+}
+
+/// Parse an `--add-archive` argument into the archive and its destination in the image
+pub fn parse_add_archive(arg: &str) -> Result<(PathBuf, PathBuf)> {
+    let (archive, dest) = arg.split_once(':').unwrap_or((arg, ""));
+    if archive.is_empty() {
+        return Err(Error::msg(format!(
+            "Invalid --add-archive argument: {}",
+            arg
+        )));
+    }
+    Ok((PathBuf::from(archive), PathBuf::from(dest)))
+}
+
+/// Parse an `--add-dir` argument like `assets:EFI/Assets` into the host and image directories
+pub fn parse_add_dir(arg: &str) -> Result<(PathBuf, PathBuf)> {
+    let invalid = || Error::msg(format!("Invalid --add-dir argument: {}", arg));
+    let (outer, inner) = match arg.split_once(':') {
+        Some((outer, inner)) => (PathBuf::from(outer), PathBuf::from(inner)),
+        None => {
+            let outer = PathBuf::from(arg);
+            let name = outer.file_name().ok_or_else(invalid)?.into();
+            (outer, name)
+        }
+    };
+    if outer.as_os_str().is_empty() {
+        return Err(invalid());
+    }
+    Ok((outer, inner))
+}
+
+/// Parse an environment variable assignment like `KEY=VALUE`
+pub fn parse_env_var(var: &str) -> Result<(String, String)> {
+    match var.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(Error::msg(format!(
+            "Invalid environment variable {}: expected KEY=VALUE",
+            var
+        ))),
+    }
+}
+
+/// Parse a `KEY=VALUE` label given with `--label`
+pub fn parse_label(label: &str) -> Result<(String, String)> {
+    match label.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(Error::msg(format!(
+            "Invalid label {}: expected KEY=VALUE",
+            label
+        ))),
+    }
+}
+
+/// Parse a memory specification like `2G` or `size=2G,slots=4,maxmem=8G`
+pub fn parse_memory(spec: &str) -> Result<QemuMemory> {
+    let invalid = |reason: &str| Error::msg(format!("Invalid memory {}: {}", spec, reason));
+    let mut size = None;
+    let mut slots = None;
+    let mut maxmem = None;
+    for (index, property) in spec.split(',').enumerate() {
+        match property.split_once('=') {
+            Some(("size", value)) => size = Some(parse_size(value)?),
+            Some(("slots", value)) => {
+                slots = Some(
+                    value
+                        .parse::<u32>()
+                        .map_err(|_| invalid("slots must be a number"))?,
+                )
+            }
+            Some(("maxmem", value)) => maxmem = Some(parse_size(value)?),
+            Some((key, _)) => return Err(invalid(&format!("unknown property {}", key))),
+            None if index == 0 => size = Some(parse_size(property)?),
+            None => return Err(invalid("expected key=value")),
+        }
+    }
+    Ok(QemuMemory {
+        size: size.ok_or_else(|| invalid("the size is missing"))?,
+        slots,
+        maxmem,
+    })
+}
+
+/// Validate a NUMA node specification like `nodeid=0,cpus=0-1,mem=512M`
+pub fn parse_numa_node(node: &str) -> Result<String> {
+    let node = node.strip_prefix("node,").unwrap_or(node);
+    let invalid = |reason: &str| Error::msg(format!("Invalid NUMA node {}: {}", node, reason));
+    let mut has_mem = false;
+    let mut has_memdev = false;
+    for property in node.split(',') {
+        let (key, value) = property
+            .split_once('=')
+            .ok_or_else(|| invalid("expected key=value"))?;
+        match key {
+            "nodeid" | "initiator" => {
+                value
+                    .parse::<u32>()
+                    .map_err(|_| invalid("node ids must be numbers"))?;
+            }
+            "cpus" => {
+                parse_cpu_list(value)?;
+            }
+            "mem" => {
+                parse_size(value)?;
+                has_mem = true;
+            }
+            "memdev" => has_memdev = true,
+            _ => return Err(invalid(&format!("unknown property {}", key))),
+        }
+    }
+    if has_mem && has_memdev {
+        return Err(invalid("mem and memdev are mutually exclusive"));
+    }
+    Ok(node.to_string())
+}
+
+/// Read a CPU file into a `-cpu` value
+pub fn read_cpu_file(path: &Path) -> Result<String> {
+    parse_cpu_spec(&std::fs::read_to_string(path)?)
+}
+
+/// Parse the contents of a CPU file into a `-cpu` value
+///
+/// Comments are removed and the remaining model and features are joined with commas.
+pub fn parse_cpu_spec(spec: &str) -> Result<String> {
+    let parts = spec
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .flat_map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>();
+    if parts.is_empty() {
+        return Err(Error::msg("CPU file does not contain a CPU model"));
+    }
+    Ok(parts.join(","))
+}
+
+/// Parse a CPU list like `0-3,6` into the list of CPU indices
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let invalid = || Error::msg(format!("Invalid CPU list: {}", list));
+    let mut cpus = Vec::new();
+    for item in list.split(',') {
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let first = first.trim().parse::<usize>().map_err(|_| invalid())?;
+                let last = last.trim().parse::<usize>().map_err(|_| invalid())?;
+                if first > last {
+                    return Err(invalid());
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(item.trim().parse::<usize>().map_err(|_| invalid())?),
+        }
     }
+    Ok(cpus)
 }
 
 #[cfg(test)]
@@ -62,27 +919,195 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_parse_add_file_args() {
-        let mut args = Args::default();
-        args.add_file = vec![
-            "/full/path/to/outer:/full/path/to/inner".to_string(),
-            "/full/path/to/outer:inner".to_string(),
-            "outer:inner".to_string(),
-            "/full/path/to/outer".to_string(),
-            "outer".to_string(),
+    fn test_parse_add_file() {
+        let args = [
+            "/full/path/to/outer:/full/path/to/inner",
+            "/full/path/to/outer:inner",
+            "outer:inner",
+            "/full/path/to/outer",
+            "outer",
+            "outer:inner:ro,hidden",
+            "/full/path/to/outer:dir/",
+            "outer:EFI/Boot/:ro",
+            "outer:dir\\sub\\file.efi",
+            "outer:EFI\\Boot\\",
         ];
         #[rustfmt::skip]
         let expected = vec![
-            (PathBuf::from("/full/path/to/outer"), PathBuf::from("/full/path/to/inner")),
-            (PathBuf::from("/full/path/to/outer"), PathBuf::from("inner")),
-            (PathBuf::from("outer"), PathBuf::from("inner")),
-            (PathBuf::from("/full/path/to/outer"), PathBuf::from("outer")),
-            (PathBuf::from("outer"), PathBuf::from("outer")),
+            (PathBuf::from("/full/path/to/outer"), PathBuf::from("/full/path/to/inner"), FileAttributes::empty()),
+            (PathBuf::from("/full/path/to/outer"), PathBuf::from("inner"), FileAttributes::empty()),
+            (PathBuf::from("outer"), PathBuf::from("inner"), FileAttributes::empty()),
+            (PathBuf::from("/full/path/to/outer"), PathBuf::from("outer"), FileAttributes::empty()),
+            (PathBuf::from("outer"), PathBuf::from("outer"), FileAttributes::empty()),
+            (PathBuf::from("outer"), PathBuf::from("inner"), FileAttributes::READ_ONLY | FileAttributes::HIDDEN),
+            (PathBuf::from("/full/path/to/outer"), PathBuf::from("dir/outer"), FileAttributes::empty()),
+            (PathBuf::from("outer"), PathBuf::from("EFI/Boot/outer"), FileAttributes::READ_ONLY),
+            (PathBuf::from("outer"), PathBuf::from("dir/sub/file.efi"), FileAttributes::empty()),
+            (PathBuf::from("outer"), PathBuf::from("EFI/Boot/outer"), FileAttributes::empty()),
         ];
         let actual = args
-            .parse_add_file_args()
-            .map(|x| x.unwrap())
+            .iter()
+            .map(|x| parse_add_file(x).unwrap())
             .collect::<Vec<_>>();
         assert_eq!(actual, expected);
+        assert!(parse_add_file("outer:inner:bogus").is_err());
+        assert!(parse_add_file("").is_err());
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0").unwrap(), vec![0]);
+        assert_eq!(parse_cpu_list("0-3,6").unwrap(), vec![0, 1, 2, 3, 6]);
+        assert_eq!(parse_cpu_list("1, 4-5").unwrap(), vec![1, 4, 5]);
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+
+    #[test]
+    fn test_parse_cpu_spec() {
+        assert_eq!(
+            parse_cpu_spec("# Standard profile\nSkylake-Server,\n  +avx512f, # needed\n  -hle\n")
+                .unwrap(),
+            "Skylake-Server,+avx512f,-hle"
+        );
+        assert_eq!(parse_cpu_spec("host").unwrap(), "host");
+        assert!(parse_cpu_spec("# nothing\n\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_pci_address() {
+        assert_eq!(parse_pci_address("01:00.0").unwrap(), "0000:01:00.0");
+        assert_eq!(parse_pci_address("0000:3B:1f.7").unwrap(), "0000:3b:1f.7");
+        assert!(parse_pci_address("01:20.0").is_err());
+        assert!(parse_pci_address("01:00.8").is_err());
+        assert!(parse_pci_address("01:00").is_err());
+        assert!(parse_pci_address("0:0:0:0.0").is_err());
+    }
+
+    #[test]
+    fn test_parse_add_dir() {
+        assert_eq!(
+            parse_add_dir("assets:EFI/Assets").unwrap(),
+            (PathBuf::from("assets"), PathBuf::from("EFI/Assets"))
+        );
+        assert_eq!(
+            parse_add_dir("data/assets").unwrap(),
+            (PathBuf::from("data/assets"), PathBuf::from("assets"))
+        );
+        assert!(parse_add_dir(":EFI").is_err());
+    }
+
+    #[test]
+    fn test_parse_env_var() {
+        assert_eq!(
+            parse_env_var("QEMU_AUDIO_DRV=none").unwrap(),
+            ("QEMU_AUDIO_DRV".to_string(), "none".to_string())
+        );
+        assert_eq!(
+            parse_env_var("A=b=c").unwrap(),
+            ("A".to_string(), "b=c".to_string())
+        );
+        assert!(parse_env_var("DISPLAY").is_err());
+        assert!(parse_env_var("=x").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("10").unwrap(), 10 * 0x10_0000);
+        assert_eq!(parse_size("512K").unwrap(), 512 * 0x400);
+        assert_eq!(parse_size("3M").unwrap(), 3 * 0x10_0000);
+        assert_eq!(parse_size("1G").unwrap(), 0x4000_0000);
+        assert_eq!(parse_size(" 10K").unwrap(), 10 * 0x400);
+        assert_eq!(parse_size("2 M ").unwrap(), 2 * 0x10_0000);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("1T").is_err());
+    }
+
+    #[test]
+    fn test_parse_chardev_target() {
+        assert_eq!(parse_chardev_target("stdio").unwrap(), "stdio");
+        assert_eq!(
+            parse_chardev_target("file:out/console.log").unwrap(),
+            "file,path=out/console.log"
+        );
+        assert_eq!(
+            parse_chardev_target("unix:/tmp/console.sock").unwrap(),
+            "socket,path=/tmp/console.sock,server=on,wait=off"
+        );
+        assert_eq!(
+            parse_chardev_target("tcp:127.0.0.1:4444").unwrap(),
+            "socket,host=127.0.0.1,port=4444,server=on,wait=off"
+        );
+        assert_eq!(
+            parse_chardev_target("file:logs,old/console.log").unwrap(),
+            "file,path=logs,,old/console.log"
+        );
+        assert_eq!(
+            parse_chardev_target("unix:/tmp/a,b.sock").unwrap(),
+            "socket,path=/tmp/a,,b.sock,server=on,wait=off"
+        );
+        assert!(parse_chardev_target("tcp:localhost").is_err());
+        assert!(parse_chardev_target("file:").is_err());
+        assert!(parse_chardev_target("pty").is_err());
+    }
+
+    #[test]
+    fn test_parse_serial_target() {
+        assert_eq!(
+            parse_serial_target("ringbuf:65536").unwrap(),
+            "ringbuf,size=65536"
+        );
+        assert_eq!(parse_serial_target("stdio").unwrap(), "stdio");
+        assert_eq!(parse_serial_target("none").unwrap(), "none");
+        assert_eq!(
+            parse_serial_target("serial.log").unwrap(),
+            "file,path=serial.log"
+        );
+        assert_eq!(
+            parse_serial_target("serial,1.log").unwrap(),
+            "file,path=serial,,1.log"
+        );
+        assert!(parse_serial_target("ringbuf:1000").is_err());
+        assert!(parse_serial_target("tcp:localhost").is_err());
+    }
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(
+            parse_memory("2G").unwrap(),
+            QemuMemory {
+                size: 0x8000_0000,
+                slots: None,
+                maxmem: None,
+            }
+        );
+        assert_eq!(
+            parse_memory("size=512,slots=2,maxmem=4G").unwrap(),
+            QemuMemory {
+                size: 0x2000_0000,
+                slots: Some(2),
+                maxmem: Some(0x1_0000_0000),
+            }
+        );
+        assert!(parse_memory("slots=2").is_err());
+        assert!(parse_memory("1G,slots=x").is_err());
+        assert!(parse_memory("1G,foo=bar").is_err());
+    }
+
+    #[test]
+    fn test_parse_numa_node() {
+        assert_eq!(
+            parse_numa_node("nodeid=0,cpus=0-1,mem=512M").unwrap(),
+            "nodeid=0,cpus=0-1,mem=512M"
+        );
+        assert_eq!(
+            parse_numa_node("node,nodeid=1,memdev=ram1").unwrap(),
+            "nodeid=1,memdev=ram1"
+        );
+        assert!(parse_numa_node("nodeid=x").is_err());
+        assert!(parse_numa_node("nodeid=0,foo=bar").is_err());
+        assert!(parse_numa_node("nodeid=0,mem=1G,memdev=ram0").is_err());
     }
 }
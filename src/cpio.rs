@@ -0,0 +1,164 @@
+//! Generation of initial ramdisks in the `newc` cpio format
+use super::*;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// File type bits of the cpio mode field
+const MODE_DIR: u32 = 0o040_000;
+const MODE_FILE: u32 = 0o100_000;
+const MODE_SYMLINK: u32 = 0o120_000;
+
+/// Pack the contents of `dir` into a `newc` cpio archive
+///
+/// Entries are written in sorted order with uid and gid 0, so the archive only depends on the
+/// contents, permissions and modification times of the files.
+pub fn write_cpio<W: Write>(dir: &Path, out: &mut W) -> Result<()> {
+    let mut writer = CpioWriter { out, ino: 0 };
+    writer.add_dir(dir, "")?;
+    writer.write_entry("TRAILER!!!", 0, 0, &[])?;
+    Ok(())
+}
+
+struct CpioWriter<'a, W: Write> {
+    out: &'a mut W,
+    ino: u32,
+}
+
+impl<W: Write> CpioWriter<'_, W> {
+    /// Add the entries of a directory recursively. `prefix` is the archive path of `dir`.
+    fn add_dir(&mut self, dir: &Path, prefix: &str) -> Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name();
+            let name = name
+                .to_str()
+                .ok_or_else(|| Error::msg("Invalid filename encoding"))?;
+            let archive_path = if prefix.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            let metadata = fs::symlink_metadata(entry.path())?;
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |mtime| mtime.as_secs() as u32);
+            if metadata.file_type().is_symlink() {
+                let target = fs::read_link(entry.path())?;
+                let target = target
+                    .to_str()
+                    .ok_or_else(|| Error::msg("Invalid symlink target encoding"))?;
+                self.write_entry(
+                    &archive_path,
+                    MODE_SYMLINK | 0o777,
+                    mtime,
+                    target.as_bytes(),
+                )?;
+            } else if metadata.is_dir() {
+                let mode = MODE_DIR | permissions(&metadata, 0o755);
+                self.write_entry(&archive_path, mode, mtime, &[])?;
+                self.add_dir(&entry.path(), &archive_path)?;
+            } else if metadata.is_file() {
+                let mode = MODE_FILE | permissions(&metadata, 0o644);
+                self.write_entry(&archive_path, mode, mtime, &fs::read(entry.path())?)?;
+            }
+            // Other file types (devices, sockets, ...) can not be reproduced and are skipped
+        }
+        Ok(())
+    }
+
+    fn write_entry(&mut self, name: &str, mode: u32, mtime: u32, data: &[u8]) -> Result<()> {
+        self.ino += 1;
+        let nlink = if mode & MODE_DIR == MODE_DIR { 2 } else { 1 };
+        let name_size = name.len() + 1;
+        let header = format!(
+            "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            self.ino,
+            mode,
+            0, // uid
+            0, // gid
+            nlink,
+            mtime,
+            data.len(),
+            0, // devmajor
+            0, // devminor
+            0, // rdevmajor
+            0, // rdevminor
+            name_size,
+            0, // check
+        );
+        self.out.write_all(header.as_bytes())?;
+        self.out.write_all(name.as_bytes())?;
+        self.out.write_all(&[0])?;
+        self.pad(header.len() + name_size)?;
+        self.out.write_all(data)?;
+        self.pad(data.len())?;
+        Ok(())
+    }
+
+    /// Pad to a multiple of four bytes
+    fn pad(&mut self, len: usize) -> Result<()> {
+        let padding = (4 - len % 4) % 4;
+        self.out.write_all(&[0; 3][..padding])?;
+        Ok(())
+    }
+}
+
+/// Permission bits of a file
+#[cfg(unix)]
+fn permissions(metadata: &fs::Metadata, _default: u32) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+/// Permission bits of a file
+#[cfg(not(unix))]
+fn permissions(_metadata: &fs::Metadata, default: u32) -> u32 {
+    default
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_cpio() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(root.join("init"), b"#!/bin/sh\n").unwrap();
+        fs::write(root.join("bin/tool"), b"tool").unwrap();
+
+        let mut archive = Vec::new();
+        write_cpio(&root, &mut archive).unwrap();
+        // Every entry starts at a multiple of four bytes
+        assert_eq!(archive.len() % 4, 0);
+
+        let mut names = Vec::new();
+        let mut offset = 0;
+        loop {
+            let header = std::str::from_utf8(&archive[offset..offset + 110]).unwrap();
+            assert_eq!(&header[..6], "070701");
+            let field = |index: usize| {
+                usize::from_str_radix(&header[6 + index * 8..14 + index * 8], 16).unwrap()
+            };
+            let (file_size, name_size) = (field(6), field(11));
+            let name =
+                std::str::from_utf8(&archive[offset + 110..offset + 110 + name_size - 1]).unwrap();
+            let data_start = (offset + 110 + name_size + 3) & !3;
+            if name == "TRAILER!!!" {
+                break;
+            }
+            if name == "bin/tool" {
+                assert_eq!(&archive[data_start..data_start + file_size], b"tool");
+                assert_eq!(field(1) as u32 & MODE_FILE, MODE_FILE);
+            }
+            names.push(name.to_string());
+            offset = (data_start + file_size + 3) & !3;
+        }
+        assert_eq!(names, ["bin", "bin/tool", "init"]);
+    }
+}
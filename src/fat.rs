@@ -0,0 +1,211 @@
+//! Minimal raw access to FAT directory entries
+//!
+//! `fatfs` does not expose everything that can be stored in a directory entry, so some fields are
+//! patched directly on the disk image.
+use super::*;
+use std::io::{Read, Seek, SeekFrom};
+
+/// Size of a directory entry in bytes
+const DIR_ENTRY_SIZE: u64 = 32;
+/// Offset of the attribute byte in a directory entry
+pub(crate) const DIR_ENTRY_ATTR_OFFSET: u64 = 11;
+/// Attribute value marking a long file name entry
+const ATTR_LFN: u8 = 0x0f;
+
+/// Geometry of a FAT filesystem as described by its boot sector
+struct Geometry {
+    bytes_per_sector: u64,
+    sectors_per_cluster: u64,
+    fat_offset: u64,
+    fat_bits: u8,
+    root_dir: RootDir,
+    data_offset: u64,
+}
+
+/// Location of the root directory
+enum RootDir {
+    /// Fixed region used by FAT12 and FAT16 (`offset`, `entries`)
+    Region(u64, u64),
+    /// Cluster chain used by FAT32
+    Cluster(u32),
+}
+
+impl Geometry {
+    fn read<D: Read + Seek>(disk: &mut D) -> Result<Self> {
+        let mut boot_sector = [0u8; 512];
+        disk.seek(SeekFrom::Start(0))?;
+        disk.read_exact(&mut boot_sector)?;
+        let u16_at =
+            |offset: usize| u16::from_le_bytes([boot_sector[offset], boot_sector[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                boot_sector[offset],
+                boot_sector[offset + 1],
+                boot_sector[offset + 2],
+                boot_sector[offset + 3],
+            ])
+        };
+
+        let bytes_per_sector = u64::from(u16_at(11));
+        let sectors_per_cluster = u64::from(boot_sector[13]);
+        let reserved_sectors = u64::from(u16_at(14));
+        let fats = u64::from(boot_sector[16]);
+        let root_entries = u64::from(u16_at(17));
+        let total_sectors = match u16_at(19) {
+            0 => u64::from(u32_at(32)),
+            sectors => u64::from(sectors),
+        };
+        let sectors_per_fat = match u16_at(22) {
+            0 => u64::from(u32_at(36)),
+            sectors => u64::from(sectors),
+        };
+        if bytes_per_sector == 0 || sectors_per_cluster == 0 {
+            return Err(Error::msg("Invalid FAT boot sector"));
+        }
+
+        let root_dir_sectors = (root_entries * DIR_ENTRY_SIZE).div_ceil(bytes_per_sector);
+        let root_dir_sector = reserved_sectors + fats * sectors_per_fat;
+        let data_sector = root_dir_sector + root_dir_sectors;
+        let clusters = total_sectors.saturating_sub(data_sector) / sectors_per_cluster;
+        let fat_bits = if clusters < 4085 {
+            12
+        } else if clusters < 65525 {
+            16
+        } else {
+            32
+        };
+        let root_dir = if fat_bits == 32 {
+            RootDir::Cluster(u32_at(44))
+        } else {
+            RootDir::Region(root_dir_sector * bytes_per_sector, root_entries)
+        };
+
+        Ok(Self {
+            bytes_per_sector,
+            sectors_per_cluster,
+            fat_offset: reserved_sectors * bytes_per_sector,
+            fat_bits,
+            root_dir,
+            data_offset: data_sector * bytes_per_sector,
+        })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + u64::from(cluster - 2) * self.cluster_size()
+    }
+
+    /// Follow the FAT to the next cluster of a chain
+    fn next_cluster<D: Read + Seek>(&self, disk: &mut D, cluster: u32) -> Result<Option<u32>> {
+        let cluster = u64::from(cluster);
+        let mut buf = [0u8; 4];
+        let (next, end) = match self.fat_bits {
+            12 => {
+                disk.seek(SeekFrom::Start(self.fat_offset + cluster + cluster / 2))?;
+                disk.read_exact(&mut buf[..2])?;
+                let value = u32::from(u16::from_le_bytes([buf[0], buf[1]]));
+                let next = if cluster % 2 == 1 {
+                    value >> 4
+                } else {
+                    value & 0xfff
+                };
+                (next, 0xff8)
+            }
+            16 => {
+                disk.seek(SeekFrom::Start(self.fat_offset + cluster * 2))?;
+                disk.read_exact(&mut buf[..2])?;
+                (u32::from(u16::from_le_bytes([buf[0], buf[1]])), 0xfff8)
+            }
+            _ => {
+                disk.seek(SeekFrom::Start(self.fat_offset + cluster * 4))?;
+                disk.read_exact(&mut buf)?;
+                (u32::from_le_bytes(buf) & 0x0fff_ffff, 0x0fff_fff8)
+            }
+        };
+        Ok(if next < 2 || next >= end {
+            None
+        } else {
+            Some(next)
+        })
+    }
+
+    /// Disk offsets of all directory entry slots in the chain starting at `cluster`
+    fn chain_entries<D: Read + Seek>(&self, disk: &mut D, cluster: u32) -> Result<Vec<u64>> {
+        let entries_per_cluster = self.cluster_size() / DIR_ENTRY_SIZE;
+        let mut offsets = Vec::new();
+        let mut next = Some(cluster);
+        while let Some(cluster) = next {
+            let start = self.cluster_offset(cluster);
+            offsets.extend((0..entries_per_cluster).map(|i| start + i * DIR_ENTRY_SIZE));
+            next = self.next_cluster(disk, cluster)?;
+        }
+        Ok(offsets)
+    }
+}
+
+/// Convert a short name as returned by `fatfs` (e.g. `RUN.EFI`) to its on-disk form
+pub(crate) fn raw_short_name(name: &[u8]) -> [u8; 11] {
+    let mut raw = [b' '; 11];
+    let (base, ext) = match name.iter().position(|&c| c == b'.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &[][..]),
+    };
+    raw[..base.len().min(8)].copy_from_slice(&base[..base.len().min(8)]);
+    raw[8..8 + ext.len().min(3)].copy_from_slice(&ext[..ext.len().min(3)]);
+    // 0xE5 marks deleted entries, so a leading 0xE5 is stored as 0x05.
+    if raw[0] == 0xe5 {
+        raw[0] = 0x05;
+    }
+    raw
+}
+
+/// Find the disk offset of the directory entry at `path`, given as a list of raw short names
+pub(crate) fn find_dir_entry<D: Read + Seek>(disk: &mut D, path: &[[u8; 11]]) -> Result<u64> {
+    let geometry = Geometry::read(disk)?;
+    let mut entries = match geometry.root_dir {
+        RootDir::Region(offset, count) => (0..count).map(|i| offset + i * DIR_ENTRY_SIZE).collect(),
+        RootDir::Cluster(cluster) => geometry.chain_entries(disk, cluster)?,
+    };
+
+    for (depth, name) in path.iter().enumerate() {
+        let mut found = None;
+        for &offset in &entries {
+            let mut entry = [0u8; DIR_ENTRY_SIZE as usize];
+            disk.seek(SeekFrom::Start(offset))?;
+            disk.read_exact(&mut entry)?;
+            match entry[0] {
+                0 => break,
+                0xe5 => continue,
+                _ if entry[DIR_ENTRY_ATTR_OFFSET as usize] == ATTR_LFN => continue,
+                _ if entry[..11] == name[..] => {
+                    let cluster_hi = u32::from(u16::from_le_bytes([entry[20], entry[21]]));
+                    let cluster_lo = u32::from(u16::from_le_bytes([entry[26], entry[27]]));
+                    found = Some((offset, cluster_hi << 16 | cluster_lo));
+                    break;
+                }
+                _ => continue,
+            }
+        }
+        let (offset, cluster) = found.ok_or_else(|| Error::msg("Directory entry not found"))?;
+        if depth + 1 == path.len() {
+            return Ok(offset);
+        }
+        entries = geometry.chain_entries(disk, cluster)?;
+    }
+    Err(Error::msg("Empty path"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw_short_name() {
+        assert_eq!(&raw_short_name(b"RUN.EFI"), b"RUN     EFI");
+        assert_eq!(&raw_short_name(b"EFI"), b"EFI        ");
+        assert_eq!(&raw_short_name(b"BOOTX64.EFI"), b"BOOTX64 EFI");
+    }
+}
@@ -0,0 +1,264 @@
+//! Locating UEFI firmware images installed on the host
+use super::*;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Firmware image looked up in the current directory
+pub const DEFAULT_FIRMWARE: &str = "OVMF.fd";
+
+/// Messages qemu prints when it is unable to load the firmware given with `-bios`
+pub(crate) const FIRMWARE_LOAD_ERRORS: &[&str] =
+    &["could not load PC BIOS", "Could not find ROM image"];
+
+/// Directories in which common distributions and package managers install firmware images
+pub fn firmware_search_dirs(arch: Arch) -> Vec<PathBuf> {
+    let dirs: &[&str] = match arch {
+        Arch::X86_64 => &[
+            "/usr/share/ovmf",
+            "/usr/share/OVMF",
+            "/usr/share/ovmf/x64",
+            "/usr/share/edk2/x64",
+            "/usr/share/edk2/ovmf",
+            "/usr/share/edk2-ovmf/x64",
+            "/usr/share/qemu",
+            "/usr/local/share/qemu",
+            "/opt/homebrew/share/qemu",
+        ],
+        Arch::Aarch64 => &[
+            "/usr/share/qemu-efi-aarch64",
+            "/usr/share/AAVMF",
+            "/usr/share/edk2/aarch64",
+            "/usr/share/edk2-armvirt/aarch64",
+            "/usr/share/qemu",
+            "/usr/local/share/qemu",
+            "/opt/homebrew/share/qemu",
+        ],
+    };
+    dirs.iter().map(PathBuf::from).collect()
+}
+
+/// Whether a file name looks like a firmware image usable for the architecture
+fn is_firmware_name(arch: Arch, name: &str) -> bool {
+    let prefixes: &[&str] = match arch {
+        Arch::X86_64 => &["OVMF", "edk2-x86_64-code"],
+        Arch::Aarch64 => &["QEMU_EFI", "AAVMF_CODE", "edk2-aarch64-code"],
+    };
+    // Variable stores are no firmware and secure boot builds need a matching variable store
+    name.ends_with(".fd")
+        && prefixes.iter().any(|prefix| name.starts_with(prefix))
+        && !name.contains("VARS")
+        && !name.contains("vars")
+        && !name.contains(".secboot")
+        && !name.contains("-secure-")
+}
+
+/// Sort key of a firmware image. Greater keys are preferred.
+///
+/// 4M builds come first, then images with higher version numbers in their name and finally newer
+/// images.
+fn firmware_rank(path: &Path) -> (bool, Vec<u64>, std::time::SystemTime) {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let is_4m = name.contains("4M");
+    // Numbers that are part of the architecture or size are no version numbers
+    let version = name
+        .replace("x86_64", "")
+        .replace("aarch64", "")
+        .replace("AA64", "")
+        .replace("4M", "")
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|number| number.parse().ok())
+        .collect();
+    let modified = path
+        .metadata()
+        .and_then(|metadata| metadata.modified())
+        .unwrap_or(std::time::UNIX_EPOCH);
+    (is_4m, version, modified)
+}
+
+/// Firmware images found in `dirs`, best candidates first
+fn firmware_candidates_in(arch: Arch, dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut candidates = dirs
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| is_firmware_name(arch, name))
+        })
+        .collect::<Vec<_>>();
+    // Stable sort, so the order of the search directories breaks ties
+    candidates.sort_by_key(|path| std::cmp::Reverse(firmware_rank(path)));
+    candidates
+}
+
+/// Firmware images installed on the host, best candidates first
+pub fn firmware_candidates(arch: Arch) -> Vec<PathBuf> {
+    firmware_candidates_in(arch, &firmware_search_dirs(arch))
+}
+
+/// Best firmware image installed on the host
+pub fn find_firmware(arch: Arch) -> Option<PathBuf> {
+    firmware_candidates(arch).into_iter().next()
+}
+
+/// Environment variable holding the firmware path for an architecture
+pub fn firmware_env_var(arch: Arch) -> &'static str {
+    match arch {
+        Arch::X86_64 => "OVMF_PATH",
+        Arch::Aarch64 => "AAVMF_PATH",
+    }
+}
+
+/// Determine the firmware passed to qemu
+///
+/// The precedence is: the explicitly configured path, the environment variable returned by
+/// `firmware_env_var`, `OVMF.fd` in the current directory and finally the system search paths. If
+/// nothing is found, `OVMF.fd` is returned so qemu can look it up in its data directory.
+pub fn resolve_firmware(bios_path: Option<&str>, arch: Arch) -> String {
+    resolve_firmware_from(bios_path, std::env::var_os(firmware_env_var(arch)), arch)
+}
+
+fn resolve_firmware_from(bios_path: Option<&str>, env: Option<OsString>, arch: Arch) -> String {
+    if let Some(bios_path) = bios_path {
+        return bios_path.to_string();
+    }
+    if let Some(env) = env.filter(|env| !env.is_empty()) {
+        return env.to_string_lossy().into_owned();
+    }
+    if Path::new(DEFAULT_FIRMWARE).is_file() {
+        return DEFAULT_FIRMWARE.to_string();
+    }
+    match find_firmware(arch) {
+        Some(firmware) => {
+            eprintln!("Using firmware {}", firmware.display());
+            firmware.display().to_string()
+        }
+        None => DEFAULT_FIRMWARE.to_string(),
+    }
+}
+
+/// Commands installing the firmware on common systems
+fn install_commands(arch: Arch) -> &'static [&'static str] {
+    match arch {
+        Arch::X86_64 => &[
+            "Debian/Ubuntu: apt install ovmf",
+            "Fedora: dnf install edk2-ovmf",
+            "Arch Linux: pacman -S edk2-ovmf",
+            "macOS: brew install qemu",
+        ],
+        Arch::Aarch64 => &[
+            "Debian/Ubuntu: apt install qemu-efi-aarch64",
+            "Fedora: dnf install edk2-aarch64",
+            "Arch Linux: pacman -S edk2-aarch64",
+            "macOS: brew install qemu",
+        ],
+    }
+}
+
+/// Error returned when qemu is unable to load the firmware and none was found on the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwareNotFound {
+    /// Firmware path passed to qemu
+    pub bios_path: String,
+    /// Architecture the firmware is needed for
+    pub arch: Arch,
+}
+
+impl fmt::Display for FirmwareNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "qemu was unable to load the UEFI firmware {} and no firmware was found on this system.",
+            self.bios_path
+        )?;
+        writeln!(f, "Searched paths:")?;
+        writeln!(f, "  {}", Path::new(&self.bios_path).display())?;
+        for path in firmware_search_dirs(self.arch) {
+            writeln!(f, "  {}", path.display())?;
+        }
+        write!(
+            f,
+            "Install the firmware or pass its location with --bios-path or {}:",
+            firmware_env_var(self.arch)
+        )?;
+        for command in install_commands(self.arch) {
+            write!(f, "\n  {}", command)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for FirmwareNotFound {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_firmware_not_found_message() {
+        let message = FirmwareNotFound {
+            bios_path: DEFAULT_FIRMWARE.to_string(),
+            arch: Arch::Aarch64,
+        }
+        .to_string();
+        assert!(message.contains("  OVMF.fd\n"));
+        assert!(message.contains("  /usr/share/qemu-efi-aarch64\n"));
+        assert!(message.contains("apt install qemu-efi-aarch64"));
+        assert!(!message.contains("OVMF.fd\n  /usr/share/ovmf"));
+        assert!(message.contains("--bios-path or AAVMF_PATH"));
+    }
+
+    #[test]
+    fn test_firmware_candidates() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dirs = [temp_dir.path().join("old"), temp_dir.path().join("new")];
+        for dir in &dirs {
+            std::fs::create_dir(dir).unwrap();
+        }
+        for name in [
+            "OVMF.fd",
+            "OVMF_CODE_4M.fd",
+            "OVMF_VARS_4M.fd",
+            "OVMF_CODE_4M.secboot.fd",
+            "README",
+        ] {
+            std::fs::write(dirs[0].join(name), b"").unwrap();
+        }
+        std::fs::write(dirs[1].join("edk2-x86_64-code.fd"), b"").unwrap();
+        std::fs::write(dirs[1].join("OVMF-202302.fd"), b"").unwrap();
+        let names = firmware_candidates_in(Arch::X86_64, &dirs)
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names[0], "OVMF_CODE_4M.fd");
+        assert_eq!(names[1], "OVMF-202302.fd");
+        assert_eq!(names.len(), 4);
+        assert!(firmware_candidates_in(Arch::Aarch64, &dirs).is_empty());
+    }
+
+    #[test]
+    fn test_resolve_firmware_precedence() {
+        let env = || Some(OsString::from("/ci/OVMF.fd"));
+        assert_eq!(
+            resolve_firmware_from(Some("custom.fd"), env(), Arch::X86_64),
+            "custom.fd"
+        );
+        assert_eq!(
+            resolve_firmware_from(None, env(), Arch::X86_64),
+            "/ci/OVMF.fd"
+        );
+        assert_ne!(
+            resolve_firmware_from(None, Some(OsString::new()), Arch::X86_64),
+            ""
+        );
+    }
+}
@@ -1,69 +1,426 @@
 use super::*;
-use std::ffi::OsStr;
 use std::fs;
-use std::io::Write;
-use std::path::Path;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
 
-/// Default startup script that just runs `run.efi`
+/// Default startup script that runs `run.efi` from the first filesystem (`fs0:`..`fs9:`)
+/// containing it
 pub const DEFAULT_STARTUP_NSH: &[u8] = include_bytes!("startup.nsh");
 
+/// Path of the build stamp written with `build_info`
+pub const BUILD_INFO_PATH: &str = "BUILDINFO.txt";
+
+/// Default directory drivers are copied to
+pub const DEFAULT_DRIVER_DIR: &str = "EFI/Drivers";
+
+/// Startup script that loads the given drivers in order before running `run.efi`
+///
+/// The driver paths are relative to the root of the filesystem containing `run.efi`.
+pub fn startup_nsh(drivers: &[PathBuf]) -> Result<String> {
+    let mut loads = String::new();
+    for driver in drivers {
+        let components = driver
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(
+                    name.to_str()
+                        .ok_or_else(|| Error::msg("Invalid driver path encoding")),
+                ),
+                _ => None,
+            })
+            .collect::<Result<Vec<_>>>()?;
+        loads.push_str(&format!("    load {}\n", components.join("\\")));
+    }
+    Ok(String::from_utf8_lossy(DEFAULT_STARTUP_NSH).replacen(
+        "    fs%i:\n",
+        &format!("    fs%i:\n{}", loads),
+        1,
+    ))
+}
+
+/// Build stamp identifying the image as `key=value` lines
+///
+/// It records the version of uefi-run, `timestamp` (seconds since the Unix epoch) and the name
+/// and SHA-256 of the EFI executable.
+pub fn build_info(efi_exe_name: &str, efi_exe: &[u8], timestamp: u64) -> String {
+    format!(
+        "uefi-run-version={}\ntimestamp={}\nefi-exe={}\nefi-exe-sha256={}\n",
+        env!("CARGO_PKG_VERSION"),
+        timestamp,
+        efi_exe_name,
+        sha256_hex(efi_exe)
+    )
+}
+
+/// Hex encoded SHA-256 of `contents`
+fn sha256_hex(contents: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(contents)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Options for creating a new image
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EfiImageOptions {
+    /// Size of the image in bytes
+    pub size: u64,
+    /// Timestamp in seconds since the Unix epoch that is used for all files and directories
+    /// instead of the current time. This makes the image reproducible.
+    pub timestamp: Option<u64>,
+    /// FAT type of the filesystem
+    ///
+    /// If not provided, FAT32 is used for images of at least `FAT32_MIN_SIZE` bytes and `fatfs`
+    /// picks FAT12 or FAT16 for smaller ones.
+    pub fat_type: Option<FatType>,
+    /// Number of entries in the root directory of FAT12/16 filesystems (512 by default)
+    ///
+    /// It has to be a multiple of 16, so the root directory fills whole sectors. FAT32 root
+    /// directories grow as needed.
+    pub root_entries: Option<u16>,
+    /// Number of file allocation tables (1 or 2). By default the FAT is mirrored.
+    pub fats: Option<u8>,
+    /// Volume label of up to 11 ASCII characters. It is stored in upper case.
+    pub volume_label: Option<String>,
+    /// Fail if a file or directory name does not fit the 8.3 format
+    ///
+    /// `fatfs` always writes long file names, but parsers without VFAT support still find every
+    /// file under its short name.
+    pub short_names: bool,
+}
+
+/// File placed in an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Path inside the image with `/` as separator
+    pub path: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Hex encoded SHA-256 of the contents
+    pub sha256: String,
+}
+
+impl ManifestEntry {
+    /// Entry for a file at `path` as returned by `image_path`
+    fn new(path: String, contents: &[u8]) -> Self {
+        Self {
+            path,
+            size: contents.len() as u64,
+            sha256: sha256_hex(contents),
+        }
+    }
+
+    /// Machine-readable representation of the entry
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "path": self.path,
+            "size": self.size,
+            "sha256": self.sha256,
+        })
+    }
+}
+
 /// Handle to a FAT filesystem used as an EFI partition
 pub struct EfiImage {
     fs: fatfs::FileSystem<fs::File>,
+    /// Second handle to the image for fields `fatfs` cannot modify
+    disk: fs::File,
+    /// Files written to the image
+    manifest: Vec<ManifestEntry>,
+    /// Only accept names in 8.3 format
+    short_names: bool,
 }
 
 impl EfiImage {
     /// Create a new image at the given path
     pub fn new<P: AsRef<Path>>(path: P, size: u64) -> Result<Self> {
-        // Create regular file and truncate it to size.
+        Self::with_options(
+            path,
+            &EfiImageOptions {
+                size,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Create a new image at the given path with the given options
+    pub fn with_options<P: AsRef<Path>>(path: P, options: &EfiImageOptions) -> Result<Self> {
+        // Create regular file
         let file = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create_new(true)
             .open(&path)?;
-        file.set_len(size)?;
+        Self::from_file(file, options)
+    }
+
+    /// Create a new image in an empty file opened for reading and writing
+    pub fn from_file(file: fs::File, options: &EfiImageOptions) -> Result<Self> {
+        file.set_len(options.size)?;
 
         // Create FAT fs and open it
-        fatfs::format_volume(&file, fatfs::FormatVolumeOptions::new())?;
-        let fs = fatfs::FileSystem::new(file, fatfs::FsOptions::new())?;
+        let mut format_options = fatfs::FormatVolumeOptions::new();
+        if let Some(fat_type) = options.fat_type.or_else(|| default_fat_type(options)) {
+            format_options = format_options.fat_type(fat_type);
+        }
+        if let Some(root_entries) = options.root_entries {
+            if root_entries == 0 || root_entries % 16 != 0 {
+                return Err(Error::msg(format!(
+                    "Invalid number of root directory entries {}: expected a multiple of 16",
+                    root_entries
+                )));
+            }
+            format_options = format_options.max_root_dir_entries(root_entries);
+        }
+        if let Some(fats) = options.fats {
+            if !(1..=2).contains(&fats) {
+                return Err(Error::msg(format!(
+                    "Invalid number of FATs {}: expected 1 or 2",
+                    fats
+                )));
+            }
+            format_options = format_options.fats(fats);
+        }
+        if let Some(label) = &options.volume_label {
+            if label.len() > 11 || !label.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+                return Err(Error::msg(format!(
+                    "Invalid volume label {}: expected up to 11 ASCII characters",
+                    label
+                )));
+            }
+            let mut volume_label = [b' '; 11];
+            volume_label[..label.len()].copy_from_slice(label.to_ascii_uppercase().as_bytes());
+            format_options = format_options.volume_label(volume_label);
+        }
+        let mut result = fatfs::format_volume(&file, format_options.clone());
+        if result.is_err() && options.fat_type.is_none() && options.size >= MIN_IMAGE_SIZE {
+            // fatfs fails to pick a FAT type for some geometries, so try each one explicitly
+            for fat_type in [FatType::Fat16, FatType::Fat12, FatType::Fat32] {
+                (&file).seek(SeekFrom::Start(0))?;
+                result = fatfs::format_volume(&file, format_options.clone().fat_type(fat_type));
+                if result.is_ok() {
+                    break;
+                }
+            }
+        }
+        result.map_err(|err| match options.fat_type {
+            Some(fat_type) => Error::msg(format!(
+                "Unable to format image of {} bytes as {}: {}",
+                options.size,
+                fat_type_size_hint(fat_type),
+                err
+            )),
+            None if options.size < MIN_IMAGE_SIZE => Error::msg(format!(
+                "Image size of {} bytes is too small, at least {} KiB are required",
+                options.size,
+                MIN_IMAGE_SIZE / 1024
+            )),
+            None => Error::msg(format!(
+                "Unable to format image of {} bytes ({}), try another size or FAT type: {}, {}, {}",
+                options.size,
+                err,
+                fat_type_size_hint(FatType::Fat12),
+                fat_type_size_hint(FatType::Fat16),
+                fat_type_size_hint(FatType::Fat32)
+            )),
+        })?;
+        let disk = file.try_clone()?;
+        let fs = fatfs::FileSystem::new(file, fs_options(options.timestamp))?;
+
+        // fatfs silently falls back to another FAT type if the size does not fit
+        if let Some(fat_type) = options.fat_type {
+            if fs.fat_type() != fat_type {
+                return Err(Error::msg(format!(
+                    "Image size of {} bytes is not valid for {}",
+                    options.size,
+                    fat_type_size_hint(fat_type)
+                )));
+            }
+        }
+        if options.root_entries.is_some() && fs.fat_type() == FatType::Fat32 {
+            warn("The number of root directory entries has no effect on FAT32")?;
+        }
+
+        Ok(Self {
+            fs,
+            disk,
+            manifest: Vec::new(),
+            short_names: options.short_names,
+        })
+    }
+
+    /// Open an existing image to add files to it
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_options(path, &Default::default())
+    }
+
+    /// Open an existing image to add files to it with the given options
+    ///
+    /// Only the timestamp and the short name check of the options are used.
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: &EfiImageOptions) -> Result<Self> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            return Err(Error::msg(format!(
+                "{} is a directory, not an image",
+                path.display()
+            )));
+        }
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|err| {
+                Error::msg(format!("Unable to open image {}: {}", path.display(), err))
+            })?;
+        let disk = file.try_clone()?;
+        let fs = fatfs::FileSystem::new(file, fs_options(options.timestamp))
+            .map_err(|err| Error::msg(format!("{} is not a FAT image: {}", path.display(), err)))?;
+        Ok(Self {
+            fs,
+            disk,
+            manifest: Vec::new(),
+            short_names: options.short_names,
+        })
+    }
 
-        Ok(Self { fs })
+    /// Whether a file exists in the image
+    pub fn file_exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.read_file(path).is_ok()
+    }
+
+    /// Read the contents of a file in the image, e.g. one written by the guest after the run
+    pub fn read_file<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>> {
+        let path = image_path(path.as_ref())?;
+        let mut contents = Vec::new();
+        self.fs
+            .root_dir()
+            .open_file(&path)
+            .and_then(|mut file| file.read_to_end(&mut contents))
+            .map_err(|err| {
+                Error::msg(format!("Unable to read {} from the image: {}", path, err))
+            })?;
+        Ok(contents)
     }
 
     /// Add file to the image
     fn add_file<P: AsRef<Path>>(&mut self, path: P) -> Result<fatfs::File<'_, fs::File>> {
-        let path = path.as_ref();
-        let file_name = path
-            .file_name()
-            .ok_or_else(|| Error::msg("Invalid path"))?
-            .to_str()
-            .ok_or_else(|| Error::msg("Invalid filename encoding"))?;
+        let path = image_path(path.as_ref())?;
+        let (dir_path, file_name) = path.rsplit_once('/').unwrap_or(("", &path));
+        if file_name.is_empty() {
+            return Err(Error::msg("Invalid path"));
+        }
+        self.check_name(file_name)?;
         let mut dir = self.fs.root_dir();
-        if let Some(dir_path) = path.parent() {
-            for dir_path_component in dir_path.iter() {
-                if dir_path_component == OsStr::new(&std::path::MAIN_SEPARATOR.to_string()) {
-                    continue;
-                }
-                let dir_path_component = dir_path_component
-                    .to_str()
-                    .ok_or_else(|| Error::msg("Cannot convert path to string"))?;
-                dir = dir.create_dir(dir_path_component)?;
-            }
+        for dir_path_component in dir_path.split('/').filter(|name| !name.is_empty()) {
+            self.check_name(dir_path_component)?;
+            dir = dir.create_dir(dir_path_component)?;
         }
         let mut file = dir.create_file(file_name)?;
         file.truncate()?;
         Ok(file)
     }
 
+    /// Create a directory and its parents unless they exist
+    pub fn create_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let mut dir = self.fs.root_dir();
+        for component in image_path(path.as_ref())?.split('/') {
+            if !component.is_empty() {
+                self.check_name(component)?;
+                dir = dir.create_dir(component)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail if `name` is not in 8.3 format and only short names are accepted
+    fn check_name(&self, name: &str) -> Result<()> {
+        if self.short_names && !is_short_name(name) {
+            return Err(Error::msg(format!(
+                "{} does not fit the 8.3 format (up to 8 characters, optionally followed by a dot \
+                 and up to 3 characters)",
+                name
+            )));
+        }
+        Ok(())
+    }
+
     /// Copy file from host filesystem to the image
+    ///
+    /// If `dst` ends with a path separator, the file is placed in that directory under its
+    /// original name.
     pub fn copy_host_file<P1: AsRef<Path>, P2: AsRef<Path>>(
         &mut self,
         src: P1,
         dst: P2,
     ) -> Result<()> {
+        let (src, dst) = (src.as_ref(), dst.as_ref());
         let file_contents = fs::read(src)?;
-        let mut file = self.add_file(dst)?;
-        file.write_all(&file_contents)?;
+        let dst = match dst.to_str() {
+            Some(dir) if is_dir_path(dir) => dst.join(
+                src.file_name()
+                    .ok_or_else(|| Error::msg("Invalid source path"))?,
+            ),
+            _ => dst.to_path_buf(),
+        };
+        self.set_file_contents(dst, file_contents)
+    }
+
+    /// Copy a directory tree from the host filesystem to `dst` in the image
+    ///
+    /// Empty directories are created as well. Symlinks are followed, but a link back to one of
+    /// its parent directories is an error.
+    pub fn copy_host_dir<P1: AsRef<Path>, P2: AsRef<Path>>(
+        &mut self,
+        src: P1,
+        dst: P2,
+    ) -> Result<()> {
+        let mut parents = Vec::new();
+        self.copy_host_dir_tree(src.as_ref(), dst.as_ref(), &mut parents)
+    }
+
+    /// Copy `src` to `dst` recursively. `parents` holds the canonical paths of the directories
+    /// being copied to detect symlink loops.
+    fn copy_host_dir_tree(
+        &mut self,
+        src: &Path,
+        dst: &Path,
+        parents: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        let canonical = fs::canonicalize(src)
+            .map_err(|err| Error::msg(format!("Unable to read {}: {}", src.display(), err)))?;
+        if parents.contains(&canonical) {
+            return Err(Error::msg(format!(
+                "{} links back to {}",
+                src.display(),
+                canonical.display()
+            )));
+        }
+        self.create_dir(dst)?;
+        let mut entries = fs::read_dir(src)
+            .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+            .map_err(|err| Error::msg(format!("Unable to read {}: {}", src.display(), err)))?;
+        entries.sort_by_key(|entry| entry.file_name());
+        parents.push(canonical);
+        for entry in entries {
+            let path = entry.path();
+            let inner = dst.join(entry.file_name());
+            // Follow symlinks
+            let metadata = fs::metadata(&path)
+                .map_err(|err| Error::msg(format!("Unable to read {}: {}", path.display(), err)))?;
+            if metadata.is_dir() {
+                self.copy_host_dir_tree(&path, &inner, parents)?;
+            } else if metadata.is_file() {
+                self.copy_host_file(&path, &inner)?;
+            } else {
+                warn(format!(
+                    "Skipping {}, only files and directories are supported",
+                    path.display()
+                ))?;
+            }
+        }
+        parents.pop();
         Ok(())
     }
 
@@ -73,8 +430,650 @@ impl EfiImage {
         path: P,
         contents: B,
     ) -> Result<()> {
-        let mut file = self.add_file(path)?;
-        file.write_all(contents.as_ref())?;
+        let path = path.as_ref();
+        self.add_file(path)?.write_all(contents.as_ref())?;
+        let entry = ManifestEntry::new(image_path(path)?, contents.as_ref());
+        self.manifest.retain(|existing| existing.path != entry.path);
+        self.manifest.push(entry);
+        Ok(())
+    }
+
+    /// Files written to the image so far, in the order they were written
+    pub fn manifest(&self) -> &[ManifestEntry] {
+        &self.manifest
+    }
+
+    /// Set the FAT attributes (read-only, hidden, ...) of a file or directory in the image
+    pub fn set_file_attributes<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        attributes: FileAttributes,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        // Resolve the short names of all path components
+        let mut short_names = Vec::new();
+        let mut is_dir = false;
+        {
+            let mut dir = self.fs.root_dir();
+            for name in image_path(path)?.split('/').filter(|name| !name.is_empty()) {
+                let entry = dir
+                    .iter()
+                    .filter_map(|entry| entry.ok())
+                    .find(|entry| entry.file_name().eq_ignore_ascii_case(name))
+                    .ok_or_else(|| Error::msg(format!("{} not found in image", path.display())))?;
+                short_names.push(raw_short_name(entry.short_file_name_as_bytes()));
+                is_dir = entry.is_dir();
+                if is_dir {
+                    dir = entry.to_dir();
+                }
+            }
+        }
+
+        // Patch the attribute byte of the directory entry
+        let offset = find_dir_entry(&mut self.disk, &short_names)?;
+        let mut attributes = attributes - FileAttributes::DIRECTORY - FileAttributes::VOLUME_ID;
+        if is_dir {
+            attributes |= FileAttributes::DIRECTORY;
+        }
+        self.disk
+            .seek(SeekFrom::Start(offset + DIR_ENTRY_ATTR_OFFSET))?;
+        self.disk.write_all(&[attributes.bits()])?;
         Ok(())
     }
 }
+
+/// Re-open the image at `path` and check that it reads back cleanly
+///
+/// The whole directory tree is walked and every file is read. Each entry of `manifest` has to be
+/// present with the recorded size and contents.
+pub fn verify_image<P: AsRef<Path>>(path: P, manifest: &[ManifestEntry]) -> Result<()> {
+    let file = fs::File::open(path)?;
+    let fs = fatfs::FileSystem::new(file, fatfs::FsOptions::new())
+        .map_err(|err| Error::msg(format!("Image verification failed: {}", err)))?;
+    let mut found = Vec::new();
+    read_dir_tree(fs.root_dir(), Path::new(""), &mut found)
+        .map_err(|err| Error::msg(format!("Image verification failed: {}", err)))?;
+    for expected in manifest {
+        // FAT file names are case-insensitive
+        match found
+            .iter()
+            .find(|entry| entry.path.eq_ignore_ascii_case(&expected.path))
+        {
+            None => {
+                return Err(Error::msg(format!(
+                    "Image verification failed: {} is missing",
+                    expected.path
+                )))
+            }
+            Some(entry) if entry.size != expected.size || entry.sha256 != expected.sha256 => {
+                return Err(Error::msg(format!(
+                    "Image verification failed: {} does not match the written contents",
+                    expected.path
+                )))
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Read all files below `dir` and record them in `found`
+fn read_dir_tree(
+    dir: fatfs::Dir<'_, fs::File>,
+    dir_path: &Path,
+    found: &mut Vec<ManifestEntry>,
+) -> Result<()> {
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        let path = dir_path.join(&name);
+        if entry.is_dir() {
+            read_dir_tree(entry.to_dir(), &path, found)?;
+        } else {
+            let mut contents = Vec::new();
+            entry.to_file().read_to_end(&mut contents)?;
+            if contents.len() as u64 != entry.len() {
+                return Err(Error::msg(format!(
+                    "{} is {} bytes long but {} bytes could be read",
+                    path.display(),
+                    entry.len(),
+                    contents.len()
+                )));
+            }
+            found.push(ManifestEntry::new(image_path(&path)?, &contents));
+        }
+    }
+    Ok(())
+}
+
+/// Path inside an image with `/` as separator as expected by `fatfs`
+///
+/// Both `/` and `\` are taken as separators on every host, so an image has the same structure
+/// no matter where it was built. Leading separators and `.` components are dropped, paths
+/// containing `..` are rejected.
+fn image_path(path: &Path) -> Result<String> {
+    let names = path
+        .to_str()
+        .ok_or_else(|| Error::msg("Invalid filename encoding"))?
+        .split(['/', '\\'])
+        .filter(|name| !matches!(*name, "" | "."))
+        .collect::<Vec<_>>();
+    if names.contains(&"..") {
+        return Err(Error::msg(format!(
+            "Path {} in the image must not contain ..",
+            path.display()
+        )));
+    }
+    Ok(names.join("/"))
+}
+
+/// Whether `name` is a valid 8.3 name, ignoring case
+fn is_short_name(name: &str) -> bool {
+    let valid_part = |part: &str, max_len: usize| {
+        part.len() <= max_len
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c))
+    };
+    match name.split_once('.') {
+        Some((base, ext)) => !base.is_empty() && valid_part(base, 8) && valid_part(ext, 3),
+        None => !name.is_empty() && valid_part(name, 8),
+    }
+}
+
+/// Filesystem options using a fixed `timestamp` for all files if given
+fn fs_options(timestamp: Option<u64>) -> fatfs::FsOptions {
+    let mut fs_options = fatfs::FsOptions::new();
+    if let Some(timestamp) = timestamp {
+        // fatfs needs a time provider with static lifetime. Images are created rarely, so
+        // leaking it is acceptable.
+        let time_provider = Box::new(FixedTimeProvider(dos_date_time(timestamp)));
+        fs_options = fs_options.time_provider(Box::leak(time_provider));
+    }
+    fs_options
+}
+
+/// Smallest image size `fatfs` is able to format
+const MIN_IMAGE_SIZE: u64 = 21 * 1024;
+
+/// Smallest image size `fatfs` is able to format with FAT32
+pub const FAT32_MIN_SIZE: u64 = 33280 * 1024;
+
+/// FAT type used if the options do not give one
+///
+/// FAT32 is what the UEFI specification requires for system partitions, so it is used whenever
+/// the image is large enough, unless the options configure the FAT12/16 root directory.
+fn default_fat_type(options: &EfiImageOptions) -> Option<FatType> {
+    (options.size >= FAT32_MIN_SIZE && options.root_entries.is_none()).then_some(FatType::Fat32)
+}
+
+/// Describe the range of image sizes supported by a FAT type
+fn fat_type_size_hint(fat_type: FatType) -> &'static str {
+    match fat_type {
+        FatType::Fat12 => "FAT12 (supports about 21 KiB to 127 MiB)",
+        FatType::Fat16 => "FAT16 (supports about 4 MiB to 4 GiB)",
+        FatType::Fat32 => "FAT32 (requires at least about 33 MiB)",
+    }
+}
+
+/// Time provider that always returns the same point in time
+#[derive(Debug)]
+struct FixedTimeProvider(fatfs::DateTime);
+
+impl fatfs::TimeProvider for FixedTimeProvider {
+    fn get_current_date(&self) -> fatfs::Date {
+        self.0.date
+    }
+
+    fn get_current_date_time(&self) -> fatfs::DateTime {
+        self.0
+    }
+}
+
+/// Convert seconds since the Unix epoch (UTC) to a DOS timestamp
+///
+/// DOS timestamps can only represent the years 1980 to 2107, so timestamps outside of that range
+/// are clamped.
+fn dos_date_time(timestamp: u64) -> fatfs::DateTime {
+    let days = timestamp / 86400;
+    let seconds = timestamp % 86400;
+
+    // Convert days since the epoch to a civil date (see http://howardhinnant.github.io/date_algorithms.html)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let (date, time) = if year < 1980 {
+        ((1980, 1, 1), (0, 0, 0))
+    } else if year > 2107 {
+        ((2107, 12, 31), (23, 59, 59))
+    } else {
+        (
+            (year as u16, month as u16, day as u16),
+            (
+                (seconds / 3600) as u16,
+                (seconds / 60 % 60) as u16,
+                (seconds % 60) as u16,
+            ),
+        )
+    };
+    fatfs::DateTime {
+        date: fatfs::Date {
+            year: date.0,
+            month: date.1,
+            day: date.2,
+        },
+        time: fatfs::Time {
+            hour: time.0,
+            min: time.1,
+            sec: time.2,
+            millis: 0,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dos_date_time() {
+        let date_time = dos_date_time(1_700_000_000);
+        assert_eq!(
+            (
+                date_time.date.year,
+                date_time.date.month,
+                date_time.date.day
+            ),
+            (2023, 11, 14)
+        );
+        assert_eq!(
+            (date_time.time.hour, date_time.time.min, date_time.time.sec),
+            (22, 13, 20)
+        );
+        let date_time = dos_date_time(951_782_400);
+        assert_eq!(
+            (
+                date_time.date.year,
+                date_time.date.month,
+                date_time.date.day
+            ),
+            (2000, 2, 29)
+        );
+        let date_time = dos_date_time(0);
+        assert_eq!(
+            (
+                date_time.date.year,
+                date_time.date.month,
+                date_time.date.day
+            ),
+            (1980, 1, 1)
+        );
+    }
+
+    #[test]
+    fn test_copy_host_file_to_dir() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let src = temp_dir.path().join("driver.efi");
+        fs::write(&src, b"driver").unwrap();
+        let path = temp_dir.path().join("image.fat");
+        {
+            let mut image = EfiImage::new(&path, 0x10_0000).unwrap();
+            image.copy_host_file(&src, "EFI/Drivers/").unwrap();
+        }
+        let fs = fatfs::FileSystem::new(fs::File::open(&path).unwrap(), fatfs::FsOptions::new())
+            .unwrap();
+        assert!(fs.root_dir().open_file("EFI/Drivers/driver.efi").is_ok());
+    }
+
+    #[test]
+    fn test_manifest() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut image = EfiImage::new(temp_dir.path().join("image.fat"), 0x10_0000).unwrap();
+        image.set_file_contents("run.efi", b"old").unwrap();
+        image
+            .set_file_contents("/EFI/Boot/BootX64.efi", b"")
+            .unwrap();
+        image.set_file_contents("run.efi", b"abc").unwrap();
+        assert_eq!(
+            image.manifest(),
+            &[
+                ManifestEntry {
+                    path: "EFI/Boot/BootX64.efi".to_string(),
+                    size: 0,
+                    sha256: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                        .to_string(),
+                },
+                ManifestEntry {
+                    path: "run.efi".to_string(),
+                    size: 3,
+                    sha256: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                        .to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_info() {
+        let info = build_info("app.efi", b"abc", 1_700_000_000);
+        assert!(info.starts_with("uefi-run-version="));
+        assert!(info.contains("\ntimestamp=1700000000\nefi-exe=app.efi\n"));
+        assert!(info.ends_with(
+            "efi-exe-sha256=ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\n"
+        ));
+    }
+
+    #[test]
+    fn test_startup_nsh() {
+        assert_eq!(startup_nsh(&[]).unwrap().as_bytes(), DEFAULT_STARTUP_NSH);
+        let script = startup_nsh(&[
+            PathBuf::from("EFI/Drivers/fs.efi"),
+            PathBuf::from("EFI/Drivers/net.efi"),
+        ])
+        .unwrap();
+        assert!(script.contains(
+            "    fs%i:\n    load EFI\\Drivers\\fs.efi\n    load EFI\\Drivers\\net.efi\n    run.efi\n"
+        ));
+    }
+
+    #[test]
+    fn test_open_existing_image() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("image.fat");
+        let mut image = EfiImage::new(&path, 0x10_0000).unwrap();
+        image.set_file_contents("startup.nsh", b"custom").unwrap();
+        drop(image);
+
+        let mut image = EfiImage::open(&path).unwrap();
+        assert!(image.file_exists("/startup.nsh"));
+        assert!(!image.file_exists("run.efi"));
+        assert_eq!(image.read_file("startup.nsh").unwrap(), b"custom");
+        image.set_file_contents("EFI/app.efi", b"app").unwrap();
+        drop(image);
+        let image = EfiImage::open(&path).unwrap();
+        assert_eq!(image.read_file("EFI/app.efi").unwrap(), b"app");
+        assert!(image.read_file("EFI").is_err());
+        let err = EfiImage::open(temp_dir.path().join("missing.fat"))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("missing.fat"), "{}", err);
+        let err = EfiImage::open(temp_dir.path()).err().unwrap();
+        assert!(err.to_string().contains("is a directory"), "{}", err);
+    }
+
+    #[test]
+    fn test_verify_image() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("image.fat");
+        let mut image = EfiImage::new(&path, 0x10_0000).unwrap();
+        image
+            .set_file_contents("EFI/Boot/BootX64.efi", b"boot")
+            .unwrap();
+        image.set_file_contents("run.efi", b"run").unwrap();
+        let mut manifest = image.manifest().to_vec();
+        drop(image);
+        verify_image(&path, &manifest).unwrap();
+
+        manifest[1].sha256 = manifest[0].sha256.clone();
+        assert!(verify_image(&path, &manifest).is_err());
+        manifest[1].path = "missing.efi".to_string();
+        assert!(verify_image(&path, &manifest).is_err());
+    }
+
+    #[test]
+    fn test_reproducible_image() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let options = EfiImageOptions {
+            size: 0x10_0000,
+            timestamp: Some(1_700_000_000),
+            ..Default::default()
+        };
+        let paths = [temp_dir.path().join("a.fat"), temp_dir.path().join("b.fat")];
+        for path in &paths {
+            let mut image = EfiImage::with_options(path, &options).unwrap();
+            image
+                .set_file_contents("EFI/Boot/BootX64.efi", b"efi")
+                .unwrap();
+            image
+                .set_file_contents("startup.nsh", DEFAULT_STARTUP_NSH)
+                .unwrap();
+            // Make sure the timestamps differ if the current time was used
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(fs::read(&paths[0]).unwrap() == fs::read(&paths[1]).unwrap());
+    }
+
+    #[test]
+    fn test_fat_type_boundaries() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        #[rustfmt::skip]
+        let cases = vec![
+            (FatType::Fat12, 16 * 1024, false),
+            (FatType::Fat12, 32 * 1024, true),
+            (FatType::Fat12, 512 * 1024, true),
+            (FatType::Fat16, 1024 * 1024, false),
+            (FatType::Fat16, 8 * 1024 * 1024, true),
+            (FatType::Fat32, 16 * 1024 * 1024, false),
+            (FatType::Fat32, 34 * 1024 * 1024, true),
+        ];
+        for (i, (fat_type, size, valid)) in cases.into_iter().enumerate() {
+            let options = EfiImageOptions {
+                size,
+                fat_type: Some(fat_type),
+                ..Default::default()
+            };
+            let result = EfiImage::with_options(temp_dir.path().join(i.to_string()), &options);
+            match result {
+                Ok(image) => {
+                    assert!(valid, "{:?} with {} bytes should fail", fat_type, size);
+                    assert_eq!(image.fs.fat_type(), fat_type);
+                }
+                Err(err) => assert!(!valid, "{:?} with {} bytes failed: {}", fat_type, size, err),
+            }
+        }
+    }
+
+    #[test]
+    fn test_default_fat_type() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        for (size, fat_type) in [
+            (0x10_0000, FatType::Fat12),
+            (10 * 0x10_0000, FatType::Fat16),
+            (FAT32_MIN_SIZE - 0x400, FatType::Fat16),
+            (FAT32_MIN_SIZE, FatType::Fat32),
+            (64 * 0x10_0000, FatType::Fat32),
+        ] {
+            let image = EfiImage::new(temp_dir.path().join(size.to_string()), size).unwrap();
+            assert_eq!(image.fs.fat_type(), fat_type, "{} bytes", size);
+        }
+    }
+
+    #[test]
+    fn test_unusual_sizes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        for size in [512, 20 * 1024] {
+            let err = EfiImage::new(temp_dir.path().join(size.to_string()), size)
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains("at least 21 KiB"), "{}", err);
+        }
+        for size in [21 * 1024, 21 * 1024 + 1, 33 * 1024 * 1024 + 512] {
+            EfiImage::new(temp_dir.path().join(size.to_string()), size).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_short_names() {
+        assert!(is_short_name("startup.nsh"));
+        assert!(is_short_name("BootX64.efi"));
+        assert!(is_short_name("EFI"));
+        assert!(!is_short_name("BUILDINFO.txt"));
+        assert!(!is_short_name("run.efi.bak"));
+        assert!(!is_short_name("my app.efi"));
+        assert!(!is_short_name(".efi"));
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let options = EfiImageOptions {
+            size: 0x10_0000,
+            short_names: true,
+            ..Default::default()
+        };
+        let mut image =
+            EfiImage::with_options(temp_dir.path().join("image.fat"), &options).unwrap();
+        image
+            .set_file_contents("EFI/Boot/BootX64.efi", b"efi")
+            .unwrap();
+        let err = image
+            .set_file_contents("EFI/Boot/LongFileName.efi", b"efi")
+            .unwrap_err();
+        assert!(err.to_string().contains("8.3"), "{}", err);
+        assert!(image.create_dir("Applications").is_err());
+        assert!(!image.file_exists("EFI/Boot/LongFileName.efi"));
+    }
+
+    #[test]
+    fn test_path_separators() {
+        assert_eq!(
+            image_path(Path::new("dir\\sub\\file.efi")).unwrap(),
+            "dir/sub/file.efi"
+        );
+        assert_eq!(
+            image_path(Path::new("/dir/./file.efi")).unwrap(),
+            "dir/file.efi"
+        );
+        assert!(image_path(Path::new("dir/../file.efi")).is_err());
+        assert!(image_path(Path::new("..\\file.efi")).is_err());
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut image = EfiImage::new(temp_dir.path().join("image.fat"), 0x10_0000).unwrap();
+        image.set_file_contents("dir\\sub\\file.efi", b"a").unwrap();
+        image.set_file_contents("dir/sub/file.efi", b"b").unwrap();
+        assert_eq!(image.manifest().len(), 1);
+        assert_eq!(image.manifest()[0].path, "dir/sub/file.efi");
+        assert_eq!(image.read_file("dir\\sub\\file.efi").unwrap(), b"b");
+        let names = image
+            .fs
+            .root_dir()
+            .open_dir("dir/sub")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| !name.starts_with('.'))
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["file.efi"]);
+    }
+
+    #[test]
+    fn test_copy_host_dir() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let assets = temp_dir.path().join("assets");
+        fs::create_dir_all(assets.join("fonts")).unwrap();
+        fs::create_dir_all(assets.join("empty")).unwrap();
+        fs::write(assets.join("config.ini"), b"[app]").unwrap();
+        fs::write(assets.join("fonts/font.bin"), b"font").unwrap();
+        let mut image = EfiImage::new(temp_dir.path().join("image.fat"), 0x10_0000).unwrap();
+        image.copy_host_dir(&assets, "EFI/Assets").unwrap();
+        assert_eq!(image.read_file("EFI/Assets/config.ini").unwrap(), b"[app]");
+        assert_eq!(
+            image.read_file("EFI/Assets/fonts/font.bin").unwrap(),
+            b"font"
+        );
+        assert!(image.fs.root_dir().open_dir("EFI/Assets/empty").is_ok());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&assets, assets.join("fonts/loop")).unwrap();
+            let err = image.copy_host_dir(&assets, "Loop").unwrap_err();
+            assert!(err.to_string().contains("links back"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_root_entries() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let add_files = |name: &str, root_entries| {
+            let options = EfiImageOptions {
+                size: 8 * 0x10_0000,
+                fat_type: Some(FatType::Fat16),
+                root_entries,
+                ..Default::default()
+            };
+            let mut image = EfiImage::with_options(temp_dir.path().join(name), &options)?;
+            // Each file takes up two entries, one of them for the long file name
+            (0..300).try_for_each(|i| image.set_file_contents(format!("F{}.TXT", i), b""))
+        };
+        assert!(add_files("default.fat", None).is_err());
+        assert!(add_files("raised.fat", Some(1024)).is_ok());
+        assert!(add_files("invalid.fat", Some(1000)).is_err());
+    }
+
+    #[test]
+    fn test_fats() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        for fats in [1, 2] {
+            let path = temp_dir.path().join(format!("{}.fat", fats));
+            let options = EfiImageOptions {
+                size: 0x10_0000,
+                fats: Some(fats),
+                ..Default::default()
+            };
+            drop(EfiImage::with_options(&path, &options).unwrap());
+            // Number of FATs in the BIOS parameter block
+            assert_eq!(fs::read(&path).unwrap()[16], fats);
+        }
+        let options = EfiImageOptions {
+            size: 0x10_0000,
+            fats: Some(3),
+            ..Default::default()
+        };
+        assert!(EfiImage::with_options(temp_dir.path().join("3.fat"), &options).is_err());
+    }
+
+    #[test]
+    fn test_set_file_attributes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut image = EfiImage::new(temp_dir.path().join("image.fat"), 0x10_0000).unwrap();
+        image.set_file_contents("dir/file.txt", b"hello").unwrap();
+        image
+            .set_file_attributes(
+                "dir/file.txt",
+                FileAttributes::READ_ONLY | FileAttributes::HIDDEN,
+            )
+            .unwrap();
+        image
+            .set_file_attributes("dir", FileAttributes::HIDDEN)
+            .unwrap();
+
+        let root_dir = image.fs.root_dir();
+        let dir_entry = root_dir
+            .iter()
+            .map(|entry| entry.unwrap())
+            .find(|entry| entry.file_name() == "dir")
+            .unwrap();
+        assert_eq!(
+            dir_entry.attributes(),
+            FileAttributes::DIRECTORY | FileAttributes::HIDDEN
+        );
+        let file_entry = dir_entry
+            .to_dir()
+            .iter()
+            .map(|entry| entry.unwrap())
+            .find(|entry| entry.file_name() == "file.txt")
+            .unwrap();
+        assert_eq!(
+            file_entry.attributes(),
+            FileAttributes::READ_ONLY | FileAttributes::HIDDEN
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! El Torito ISO images embedding the FAT image as EFI system partition
+use super::*;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Command;
+
+/// Tools able to build an ISO with an EFI boot image, in order of preference
+const ISO_TOOLS: [&str; 3] = ["xorriso", "genisoimage", "mkisofs"];
+
+/// Path of the FAT image inside the ISO
+const ISO_BOOT_IMAGE: &str = "efiboot.img";
+
+/// Build a UEFI-bootable ISO at `output` with the FAT image `esp_image` as El Torito boot image
+///
+/// The first of `xorriso`, `genisoimage` and `mkisofs` found on the host is used.
+pub fn build_iso(esp_image: &Path, output: &Path) -> Result<()> {
+    for tool in ISO_TOOLS {
+        let output = match iso_command(tool, esp_image, output).output() {
+            Ok(output) => output,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(Error::msg(format!("Unable to run {}: {}", tool, err))),
+        };
+        if !output.status.success() {
+            return Err(Error::msg(format!(
+                "{} failed to build the ISO: {}",
+                tool,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        return Ok(());
+    }
+    Err(Error::msg(format!(
+        "Building an ISO requires one of {}",
+        ISO_TOOLS.join(", ")
+    )))
+}
+
+/// Command building an ISO containing only `esp_image` with `tool`
+fn iso_command(tool: &str, esp_image: &Path, output: &Path) -> Command {
+    let mut graft_point = std::ffi::OsString::from(format!("{}=", ISO_BOOT_IMAGE));
+    graft_point.push(esp_image);
+    let mut command = Command::new(tool);
+    if tool == "xorriso" {
+        command.args(["-as", "mkisofs"]);
+    }
+    command
+        .arg("-o")
+        .arg(output)
+        .args(["-V", "UEFI_RUN", "-e", ISO_BOOT_IMAGE, "-no-emul-boot"])
+        .arg("-graft-points")
+        .arg(graft_point);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iso_command() {
+        let command = iso_command("xorriso", Path::new("/tmp/image.fat"), Path::new("out.iso"));
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            &[
+                "-as",
+                "mkisofs",
+                "-o",
+                "out.iso",
+                "-V",
+                "UEFI_RUN",
+                "-e",
+                "efiboot.img",
+                "-no-emul-boot",
+                "-graft-points",
+                "efiboot.img=/tmp/image.fat"
+            ]
+        );
+    }
+}
@@ -1,10 +1,54 @@
 use anyhow::{Error, Result};
+pub use fatfs::{FatType, FileAttributes};
+
+mod arch;
+pub use arch::*;
+
+mod archive;
+pub use archive::*;
 
 mod args;
 pub use args::*;
 
+mod cpio;
+pub use cpio::*;
+
+mod fat;
+use fat::*;
+
+mod firmware;
+pub use firmware::*;
+
 mod image;
 pub use image::*;
 
+mod iso;
+pub use iso::*;
+
+mod pe;
+pub use pe::*;
+
+mod platform;
+use platform::*;
+pub use platform::{core_dump_location, kvm_accessible};
+
 mod qemu;
 pub use qemu::*;
+
+mod qmp;
+pub use qmp::*;
+
+mod results;
+pub use results::*;
+
+mod run;
+pub use run::*;
+
+mod share;
+pub use share::*;
+
+mod varstore;
+pub use varstore::*;
+
+mod warning;
+pub use warning::*;
@@ -1,5 +1,6 @@
 use clap::Parser;
-use std::path::PathBuf;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
@@ -8,103 +9,557 @@ use uefi_run::*;
 fn main() {
     // Parse command line
     let args = Args::parse();
+    set_strict(args.strict);
+    let exit_code_file = args.exit_code_file.as_deref();
+
+    // Catch a wrong path before anything is built
+    if let Some(efi_exe) = args.efi_exe.as_ref().filter(|_| !args.qemu_version) {
+        if let Err(err) = check_efi_exe(Path::new(efi_exe)) {
+            eprintln!("Error: {}", err);
+            exit(1, Some("error"), exit_code_file);
+        }
+    }
+
+    // Determine the target architecture. An explicit `--arch` always wins.
+    let arch = args.arch.unwrap_or_else(|| match &args.efi_exe {
+        Some(efi_exe) => Arch::detect(efi_exe).unwrap_or_else(|err| {
+            warn_or_exit(
+                format!(
+                    "Unable to detect architecture of EFI executable ({}), assuming x86_64",
+                    err
+                ),
+                exit_code_file,
+            );
+            Arch::X86_64
+        }),
+        None => Arch::X86_64,
+    });
+
+    // An accelerator given in the additional arguments takes precedence
+    let requested_kvm = args.qemu_args.iter().any(|arg| arg == "-enable-kvm")
+        || args
+            .qemu_args
+            .windows(2)
+            .any(|pair| pair[0] == "-accel" && pair[1].starts_with("kvm"));
+    let requested_accel = requested_kvm || args.qemu_args.iter().any(|arg| arg == "-accel");
+    if args.unprivileged {
+        if requested_kvm && !kvm_accessible() {
+            eprintln!("Error: KVM was requested, but /dev/kvm is not accessible");
+            exit(1, Some("error"), exit_code_file);
+        }
+        if args.nice.is_some_and(|nice| nice < 0) {
+            eprintln!("Error: Negative nice values require privileges and can not be used with --unprivileged");
+            exit(1, Some("error"), exit_code_file);
+        }
+    }
+
+    let mut drives = Vec::new();
+    for drive in &args.drive {
+        match QemuDriveConfig::parse(drive) {
+            Ok(drive) => drives.push(drive),
+            Err(err) => {
+                eprintln!("Error: Invalid drive {}: {}", drive, err);
+                exit(1, Some("error"), exit_code_file);
+            }
+        }
+    }
+    for floppy in &args.floppy {
+        match QemuDriveConfig::floppy(floppy) {
+            Ok(drive) => drives.push(drive),
+            // The errors (and strict warnings) name the floppy image already
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                exit(1, Some("error"), exit_code_file);
+            }
+        }
+    }
+    if let Some(splash) = &args.splash {
+        if let Err(err) = check_splash(splash) {
+            eprintln!("Error: {}", err);
+            exit(1, Some("error"), exit_code_file);
+        }
+    }
+    let usb_controller = args.usb_controller.device(arch).unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        exit(1, Some("error"), exit_code_file);
+    });
+
+    let mut qemu_config = QemuConfig {
+        qemu_path: args
+            .qemu_path
+            .clone()
+            .unwrap_or_else(|| arch.qemu_path().to_string()),
+        bios_path: if args.direct_kernel || args.no_default_args {
+            String::new()
+        } else {
+            resolve_firmware(args.bios_path.first().map(String::as_str), arch)
+        },
+        direct_kernel: args.direct_kernel,
+        bios_writable: args.bios_writable,
+        no_default_args: args.no_default_args,
+        no_reboot: args.no_reboot,
+        no_shutdown: args.no_shutdown,
+        interactive_monitor: args.interactive_monitor,
+        reboot_count: args.reboot_count,
+        watchdog_action: args.watchdog_action.clone(),
+        cpu_affinity: args.cpu_affinity.clone(),
+        clear_env: args.clear_env,
+        env: args.env_qemu.clone(),
+        core_dump: args.qemu_core_dump,
+        no_acpi: args.no_acpi,
+        accel: (args.unprivileged && !requested_accel)
+            .then(|| if kvm_accessible() { "kvm" } else { "tcg" }.to_string()),
+        cpu_time_limit: args.cpu_time_limit,
+        memory_limit: args.memory_limit,
+        nice: args.nice,
+        cpu: match &args.cpu_file {
+            Some(cpu_file) => Some(read_cpu_file(cpu_file).unwrap_or_else(|err| {
+                eprintln!("Error: Invalid CPU file {}: {}", cpu_file.display(), err);
+                exit(1, Some("error"), exit_code_file);
+            })),
+            None => args.cpu.clone(),
+        },
+        drives,
+        vga: args.vga.clone(),
+        virtio_gpu_device: args
+            .virtio_gpu
+            .then(|| arch.virtio_gpu_device().to_string()),
+        pvpanic_device: args.pvpanic.then(|| arch.pvpanic_device().to_string()),
+        debug_exit: args.debug_exit.then(DebugExitConfig::default),
+        loadvm: args.loadvm.clone(),
+        numa_nodes: args.numa.clone(),
+        seed: args.seed,
+        memory: args.memory,
+        memory_hotplug: args.memory_hotplug.clone(),
+        kernel: args.kernel.clone(),
+        dtb: args.dtb.clone(),
+        initrd: args.initrd.clone(),
+        append: args.append.clone(),
+        network: match &args.tap {
+            Some(ifname) => QemuNetwork::Tap(ifname.clone()),
+            None if args.net_dns_only || args.unprivileged => QemuNetwork::User,
+            None => QemuNetwork::None,
+        },
+        mac: if args.random_mac {
+            Some(random_mac(args.seed))
+        } else {
+            args.mac.clone()
+        },
+        esp_only: args.esp_only,
+        splash: args.splash.clone(),
+        vfio_devices: args.vfio_pci.clone(),
+        usb_devices: args.usb_device.clone(),
+        usb_controller: usb_controller.to_string(),
+        serial: args.serial.clone(),
+        virtio_console: args.virtio_console.clone(),
+        debugcon: args.debugcon.clone(),
+        rtc: if args.rtc_host {
+            Some("base=utc,clock=host".to_string())
+        } else {
+            args.rtc.clone()
+        },
+        rtc_clock: args.clock,
+        rtc_driftfix: args.rtc_driftfix,
+        serial_timestamps: args.serial_timestamps,
+        debug_log: args.qemu_debug.clone(),
+        phase_markers: args.phase_markers,
+        output_log: args.log_qemu_tee.clone(),
+        quiet: args.quiet_qemu,
+        screenshot: args.screenshot.clone(),
+        qmp: args.qmp,
+        ..Default::default()
+    };
+    qemu_config
+        .additional_args
+        .extend(args.qemu_args.iter().cloned());
+    if let QemuNetwork::Tap(ifname) = &qemu_config.network {
+        eprintln!(
+            "Using tap interface {} (it has to exist and be accessible, which usually requires \
+             root or CAP_NET_ADMIN)",
+            ifname
+        );
+    }
+    if qemu_config.debugcon.is_some() && arch != Arch::X86_64 {
+        eprintln!("Error: The firmware debug console is only available on x86_64");
+        exit(1, Some("error"), exit_code_file);
+    }
+    if qemu_config.interactive_monitor {
+        eprintln!(
+            "qemu keeps running after the guest shut down, enter `quit` in the monitor to exit"
+        );
+    }
+    if qemu_config.debug_exit.is_some() && arch != Arch::X86_64 {
+        eprintln!("Error: The isa-debug-exit device is only available on x86_64");
+        exit(1, Some("error"), exit_code_file);
+    }
+    if let Some(dtb) = &qemu_config.dtb {
+        if arch != Arch::Aarch64 {
+            eprintln!("Error: A device tree blob can only be passed on aarch64");
+            exit(1, Some("error"), exit_code_file);
+        }
+        if !Path::new(dtb).is_file() {
+            eprintln!("Error: The device tree blob {} does not exist", dtb);
+            exit(1, Some("error"), exit_code_file);
+        }
+    }
+    if !qemu_config.vfio_devices.is_empty() {
+        eprintln!(
+            "Passing through {} (requires an IOMMU and the vfio-pci driver; the host can not use \
+             the devices while they are bound to it)",
+            qemu_config.vfio_devices.join(", ")
+        );
+    }
+
+    // Print qemu version and exit
+    if args.qemu_version {
+        match qemu_config.version_string() {
+            Ok(version) => print!("{}", version),
+            Err(err) => {
+                eprintln!("Error: Failed to query qemu version: {}", err);
+                exit(1, Some("error"), exit_code_file);
+            }
+        }
+        return;
+    }
+    // Only `--launch-only` runs without an EFI executable
+    let efi_exe = match &args.efi_exe {
+        Some(efi_exe) => efi_exe.clone(),
+        None => args
+            .image
+            .as_ref()
+            .expect("EFI executable is required")
+            .display()
+            .to_string(),
+    };
+
+    // Select the argument syntax matching the installed qemu
+    if qemu_config.needs_qemu_version() {
+        match qemu_config.version() {
+            Ok(version) => qemu_config.qemu_version = Some(version),
+            Err(err) => warn_or_exit(
+                format!(
+                    "Unable to detect qemu version ({}), using legacy arguments",
+                    err
+                ),
+                exit_code_file,
+            ),
+        }
+    }
+
+    if qemu_config.seed.is_some() && !QemuCapabilities::for_version(qemu_config.qemu_version).seed {
+        warn_or_exit(
+            match qemu_config.qemu_version {
+                Some(version) => {
+                    format!("qemu {} does not support -seed, ignoring --seed", version)
+                }
+                None => "Unknown qemu version, ignoring --seed".to_string(),
+            },
+            exit_code_file,
+        );
+    }
+
+    if args.esp_interface == Some(EspInterface::Nvme) {
+        match qemu_config.supports_device("nvme") {
+            Ok(true) => {}
+            Ok(false) => {
+                eprintln!(
+                    "Error: {} does not provide an NVMe device",
+                    qemu_config.qemu_path
+                );
+                exit(1, Some("error"), exit_code_file);
+            }
+            Err(err) => warn_or_exit(
+                format!("Unable to check for the NVMe device of qemu ({})", err),
+                exit_code_file,
+            ),
+        }
+    }
+
+    let timeout = args.timeout.map(|seconds| {
+        Duration::try_from_secs_f64(seconds).unwrap_or_else(|_| {
+            eprintln!("Error: Invalid timeout of {} seconds", seconds);
+            exit(1, Some("error"), exit_code_file)
+        })
+    });
+
+    let startup_nsh = args.startup_nsh.as_ref().map(|path| {
+        std::fs::read(path).unwrap_or_else(|err| {
+            eprintln!("Error: Unable to read {}: {}", path.display(), err);
+            exit(1, Some("error"), exit_code_file)
+        })
+    });
 
     // Install termination signal handler. This ensures that the destructor of
-    // `temp_dir` which is constructed in the next step is really called and
-    // the files are cleaned up properly.
+    // the temporary directory holding the image is really called and the files
+    // are cleaned up properly.
     let terminating = Arc::new(AtomicBool::new(false));
     {
         let term = terminating.clone();
         ctrlc::set_handler(move || {
-            println!("uefi-run terminating...");
+            eprintln!("uefi-run terminating...");
             // Tell the main thread to stop waiting.
             term.store(true, Ordering::SeqCst);
         })
         .expect("Error setting termination handler");
     }
 
-    // Create temporary dir for the image file.
-    let temp_dir = tempfile::tempdir().expect("Unable to create temporary directory");
-    let temp_dir_path = PathBuf::from(temp_dir.path());
-
-    // Path to the image file
-    let image_file_path = {
-        let mut path_buf = temp_dir_path;
-        path_buf.push("image.fat");
-        path_buf
+    let mut options = RunOptions {
+        name: efi_exe.clone(),
+        image: ImageOptions {
+            efi_exe: PathBuf::from(&efi_exe),
+            arch,
+            boot_mode: if args.boot_and_run {
+                BootMode::Both
+            } else if args.boot {
+                BootMode::Bootloader
+            } else {
+                BootMode::Shell
+            },
+            image: EfiImageOptions {
+                size: args.size,
+                timestamp: args.source_date_epoch,
+                fat_type: args.fat,
+                root_entries: args.root_entries,
+                fats: args.num_fats,
+                volume_label: None,
+                short_names: args.short_names,
+            },
+            add_files: args.add_file.clone(),
+            archives: args.add_archive.clone(),
+            dirs: args.add_dir.clone(),
+            drivers: args.load_driver.clone(),
+            driver_dir: args.driver_dir.clone(),
+            manifest: args.manifest.clone(),
+            verify: args.verify_image,
+            verbose: args.verbose,
+            stamp: args.stamp,
+            keep_existing_startup: args.keep_existing_startup,
+            startup_nsh,
+            no_startup_nsh: args.no_startup_nsh,
+            path: args.image.clone(),
+            temp_dir: args.temp_dir.clone(),
+            iso: args.output_iso.clone(),
+        },
+        launch_only: args.launch_only,
+        esp_readonly: args.esp_readonly,
+        esp_index: args.esp_index,
+        esp_interface: args.esp_interface,
+        discard: args.discard,
+        vars_template: args.vars.clone(),
+        enroll_key: args.enroll_key.clone(),
+        initrd_dir: args.initrd_from_dir.clone(),
+        watch_dir: args.watch_dir.clone(),
+        keep_temp_dir: args.keep,
+        data_disks: args.data_disk.clone(),
+        results_channel: args.results_channel.clone(),
+        boot_iso: args.boot_iso,
+        qemu: qemu_config,
+        panic_exit_code: args.panic_exit_code,
+        poll_interval: Duration::from_millis(args.poll_interval_ms),
+        timeout,
+        relaunch_on_crash: args.relaunch_on_crash,
+        startup_delay: Duration::from_millis(args.startup_delay_ms),
+        pre_launch: args.pre_launch.clone(),
+        post_exit: args.post_exit.clone(),
+        labels: args.label.iter().cloned().collect(),
     };
 
-    {
-        let mut image =
-            EfiImage::new(&image_file_path, args.size * 0x10_0000).expect("Failed to create image");
+    // Print the effective configuration and exit
+    if args.print_config {
+        let image_path = options
+            .image
+            .path
+            .as_ref()
+            .map_or_else(|| "<image>".to_string(), |path| path.display().to_string());
+        match args.format {
+            OutputFormat::Text => {
+                let qemu_config = options.qemu_config(&image_path);
+                println!("EFI executable: {}", options.image.efi_exe.display());
+                println!("Architecture: {}", options.image.arch.name());
+                println!(
+                    "qemu command line: {} {}",
+                    qemu_config.qemu_path,
+                    qemu_config.args().join(" ")
+                );
+            }
+            OutputFormat::Json => println!("{}", options.to_json(&image_path)),
+        }
+        exit(0, None, exit_code_file);
+    }
 
-        // Create EFI executable
-        if args.boot {
-            // Copy the application to where the firmware expects a bootloader.
-            image.copy_host_file(&args.efi_exe, "EFI/Boot/BootX64.efi")
-        } else {
-            // Use startup.nsh to start the application from the EFI shell.
-            image
-                .copy_host_file(&args.efi_exe, "run.efi")
-                .and_then(|_| image.set_file_contents("startup.nsh", DEFAULT_STARTUP_NSH))
+    // Only build the image and write it to stdout
+    if args.stdout_image {
+        if let Err(err) = write_image_to_stdout(&options) {
+            eprintln!("Error: {}", err);
+            exit(1, Some("error"), exit_code_file);
         }
-        .expect("Failed to copy EFI executable");
+        exit(0, None, exit_code_file);
+    }
 
-        // Create user provided additional files
-        for (outer, inner) in args.parse_add_file_args().map(|x| x.unwrap()) {
-            // Copy the file into the image
-            image
-                .copy_host_file(outer, inner)
-                .expect("Failed to copy user-defined file");
+    // Only build the ISO
+    if options.image.iso.is_some() && !options.boot_iso {
+        if let Err(err) = build_image_only(&options) {
+            eprintln!("Error: {}", err);
+            exit(1, Some("error"), exit_code_file);
         }
+        exit(0, None, exit_code_file);
     }
 
-    let mut qemu_config = QemuConfig {
-        qemu_path: args.qemu_path,
-        bios_path: args.bios_path,
-        drives: vec![QemuDriveConfig {
-            file: image_file_path.to_str().unwrap().to_string(),
-            media: "disk".to_string(),
-            format: "raw".to_string(),
-        }],
-        ..Default::default()
+    // Build the image and run qemu
+    let reports = if args.bios_path.len() > 1 {
+        run_with_each_firmware(&options, &args.bios_path, &terminating)
+    } else {
+        run_with_firmware_search(&mut options, &terminating).map(|report| vec![report])
     };
-    qemu_config
-        .additional_args
-        .extend(args.qemu_args.iter().cloned());
+    let reports = match reports {
+        Ok(reports) => reports,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            exit(1, Some("error"), exit_code_file);
+        }
+    };
+    for report in &reports {
+        match args.format {
+            OutputFormat::Text => {
+                if reports.len() > 1 {
+                    eprintln!("Firmware {}: exit code {}", report.name, report.exit_code);
+                }
+                if let Some(serial) = &report.serial {
+                    print!("{}", serial);
+                }
+                for result in report.results.iter().flatten() {
+                    println!("{}", result);
+                }
+                if let Some(run_state) = &report.run_state {
+                    eprintln!("Guest run state at exit: {}", run_state);
+                }
+                if args.rtc_host {
+                    eprintln!("qemu ran for {:.3} seconds", report.duration.as_secs_f64());
+                }
+            }
+            OutputFormat::Json => println!("{}", report.to_json()),
+        }
+    }
+
+    // Fail with the first failing run
+    let report = reports
+        .iter()
+        .find(|report| report.exit_code != 0)
+        .or_else(|| reports.last())
+        .expect("At least one run was made");
+    exit(
+        report.exit_code,
+        report.run_state.as_deref(),
+        exit_code_file,
+    );
+}
 
-    // Run qemu
-    let mut qemu_process = qemu_config.run().expect("Failed to start qemu");
+/// Emit a warning and exit if warnings are treated as errors
+fn warn_or_exit(message: String, exit_code_file: Option<&Path>) {
+    if let Err(err) = warn(message) {
+        eprintln!("Error: {}", err);
+        exit(1, Some("error"), exit_code_file);
+    }
+}
 
-    // Wait for qemu to exit or signal.
-    let mut qemu_exit_code;
-    loop {
-        qemu_exit_code = qemu_process.wait(Duration::from_millis(500));
-        if qemu_exit_code.is_some() || terminating.load(Ordering::SeqCst) {
+/// Build the image once and run it with each firmware in turn
+///
+/// The reports are named after the firmware.
+fn run_with_each_firmware(
+    options: &RunOptions,
+    bios_paths: &[String],
+    terminating: &AtomicBool,
+) -> anyhow::Result<Vec<RunReport>> {
+    let temp_dir = create_temp_dir(options.image.temp_dir.as_deref())?;
+    let (_temp_dir, temp_path) = keep_temp_dir(temp_dir, options.keep_temp_dir);
+    let image = prepare_image(options, &temp_path)?;
+    let mut reports = Vec::new();
+    for bios_path in bios_paths {
+        let mut options = options.clone();
+        options.name = bios_path.clone();
+        options.qemu.bios_path = bios_path.clone();
+        reports.push(run_image(&options, &image, terminating)?);
+        if terminating.load(Ordering::SeqCst) {
             break;
         }
     }
+    Ok(reports)
+}
+
+/// Build the image without running it, e.g. to embed it in an ISO
+///
+/// The image is removed again unless an image path is given.
+fn build_image_only(options: &RunOptions) -> anyhow::Result<()> {
+    let temp_dir = create_temp_dir(options.image.temp_dir.as_deref())?;
+    let (_temp_dir, temp_path) = keep_temp_dir(temp_dir, options.keep_temp_dir);
+    prepare_image(options, &temp_path)?;
+    Ok(())
+}
 
-    // The above loop may have been broken by a signal
-    if qemu_exit_code.is_none() {
-        // In this case we wait for qemu to exit for one second
-        qemu_exit_code = qemu_process.wait(Duration::from_secs(1));
+/// Build the image and write it to stdout
+///
+/// The image is removed afterwards unless an image path is given.
+fn write_image_to_stdout(options: &RunOptions) -> anyhow::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    if stdout.is_terminal() {
+        return Err(anyhow::Error::msg(
+            "Refusing to write the image to a terminal, redirect stdout to a file or pipe",
+        ));
     }
+    let temp_dir = create_temp_dir(options.image.temp_dir.as_deref())?;
+    let (_temp_dir, temp_path) = keep_temp_dir(temp_dir, options.keep_temp_dir);
+    let image = prepare_image(options, &temp_path)?;
+    std::io::copy(&mut std::fs::File::open(image)?, &mut stdout)?;
+    stdout.flush()?;
+    Ok(())
+}
 
-    // Qemu may still be running
-    if qemu_exit_code.is_none() {
-        // In this case we need to kill it
-        qemu_process
-            .kill()
-            .or_else(|e| match e.kind() {
-                // Not running anymore
-                std::io::ErrorKind::InvalidInput => Ok(()),
-                _ => Err(e),
-            })
-            .expect("Unable to kill qemu process");
-        qemu_exit_code = qemu_process.wait(Duration::from_secs(1));
+/// Exit with the given code after recording it in `exit_code_file`
+///
+/// The file contains the exit code on the first line and the reason, if known, on the second.
+fn exit(code: i32, reason: Option<&str>, exit_code_file: Option<&Path>) -> ! {
+    if let Some(exit_code_file) = exit_code_file {
+        let mut contents = format!("{}\n", code);
+        if let Some(reason) = reason {
+            contents.push_str(reason);
+            contents.push('\n');
+        }
+        if let Err(err) = std::fs::write(exit_code_file, contents) {
+            let message = format!(
+                "Unable to write exit code to {} ({})",
+                exit_code_file.display(),
+                err
+            );
+            if let Err(err) = warn(message) {
+                eprintln!("Error: {}", err);
+                std::process::exit(if code == 0 { 1 } else { code });
+            }
+        }
     }
+    std::process::exit(code);
+}
 
-    let exit_code = qemu_exit_code.expect("qemu should have exited by now but did not");
-    std::process::exit(exit_code);
+/// Run the EFI application, retrying with firmware found on the host if qemu can not load the
+/// configured one
+fn run_with_firmware_search(
+    options: &mut RunOptions,
+    terminating: &AtomicBool,
+) -> anyhow::Result<RunReport> {
+    match run_uefi_app(options, terminating) {
+        Err(err) if err.is::<FirmwareNotFound>() => {
+            let firmware = match find_firmware(options.image.arch) {
+                Some(firmware) if firmware != Path::new(&options.qemu.bios_path) => firmware,
+                _ => return Err(err),
+            };
+            warn(format!(
+                "qemu was unable to load the firmware {}, retrying with {}",
+                options.qemu.bios_path,
+                firmware.display()
+            ))
+            .map_err(|_| err)?;
+            options.qemu.bios_path = firmware.display().to_string();
+            run_uefi_app(options, terminating)
+        }
+        result => result,
+    }
 }
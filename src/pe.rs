@@ -0,0 +1,68 @@
+use super::*;
+use std::io::{Read, Seek, SeekFrom};
+
+/// Machine type of x86_64 executables
+pub const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+/// Machine type of AArch64 executables
+pub const IMAGE_FILE_MACHINE_ARM64: u16 = 0xaa64;
+
+/// Read the machine field from the COFF header of a PE executable
+pub fn read_pe_machine<R: Read + Seek>(reader: &mut R) -> Result<u16> {
+    // The DOS header starts with "MZ" and stores the offset of the PE header at 0x3c.
+    let mut dos_magic = [0u8; 2];
+    reader.read_exact(&mut dos_magic)?;
+    if &dos_magic != b"MZ" {
+        return Err(Error::msg("Not a PE executable (missing MZ signature)"));
+    }
+    reader.seek(SeekFrom::Start(0x3c))?;
+    let mut pe_offset = [0u8; 4];
+    reader.read_exact(&mut pe_offset)?;
+    let pe_offset = u32::from_le_bytes(pe_offset);
+
+    // The PE signature is directly followed by the COFF header whose first field is the
+    // machine type.
+    reader.seek(SeekFrom::Start(pe_offset.into()))?;
+    let mut pe_header = [0u8; 6];
+    reader.read_exact(&mut pe_header)?;
+    if &pe_header[..4] != b"PE\0\0" {
+        return Err(Error::msg("Not a PE executable (missing PE signature)"));
+    }
+    Ok(u16::from_le_bytes([pe_header[4], pe_header[5]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn pe_image(machine: u16) -> Vec<u8> {
+        let mut image = vec![0u8; 0x90];
+        image[..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x80u32.to_le_bytes());
+        image[0x80..0x84].copy_from_slice(b"PE\0\0");
+        image[0x84..0x86].copy_from_slice(&machine.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn test_read_pe_machine() {
+        let mut image = Cursor::new(pe_image(IMAGE_FILE_MACHINE_ARM64));
+        assert_eq!(
+            read_pe_machine(&mut image).unwrap(),
+            IMAGE_FILE_MACHINE_ARM64
+        );
+        let mut image = Cursor::new(pe_image(IMAGE_FILE_MACHINE_AMD64));
+        assert_eq!(
+            read_pe_machine(&mut image).unwrap(),
+            IMAGE_FILE_MACHINE_AMD64
+        );
+    }
+
+    #[test]
+    fn test_read_pe_machine_invalid() {
+        let mut image = pe_image(IMAGE_FILE_MACHINE_AMD64);
+        image[0x80] = b'X';
+        assert!(read_pe_machine(&mut Cursor::new(image)).is_err());
+        assert!(read_pe_machine(&mut Cursor::new(b"ELF".to_vec())).is_err());
+    }
+}
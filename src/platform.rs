@@ -0,0 +1,603 @@
+use super::*;
+use std::process::{Command, ExitStatus};
+
+/// Restrict the process spawned by `command` to the given host CPUs
+#[cfg(target_os = "linux")]
+pub(crate) fn set_cpu_affinity(command: &mut Command, cpus: &[usize]) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let mut cpu_set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let max_cpus = 8 * std::mem::size_of::<libc::cpu_set_t>();
+    for &cpu in cpus {
+        if cpu >= max_cpus {
+            return Err(Error::msg(format!("CPU {} is out of range", cpu)));
+        }
+        unsafe { libc::CPU_SET(cpu, &mut cpu_set) };
+    }
+    // Safety: `sched_setaffinity` is async-signal-safe and the CPU set is prepared before forking.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::sched_setaffinity(0, std::mem::size_of_val(&cpu_set), &cpu_set) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Restrict the process spawned by `command` to the given host CPUs
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_cpu_affinity(_command: &mut Command, _cpus: &[usize]) -> Result<()> {
+    Err(Error::msg(
+        "Setting the CPU affinity is only supported on Linux",
+    ))
+}
+
+/// Set the scheduling priority of the process spawned by `command` to the nice value `nice`
+#[cfg(unix)]
+pub(crate) fn set_niceness(command: &mut Command, nice: i32) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    // Safety: `setpriority` is async-signal-safe.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Set the priority class of the process spawned by `command` from the nice value `nice`
+///
+/// Positive values map to below normal (idle from 15), negative values to above normal (high
+/// from -15).
+#[cfg(windows)]
+pub(crate) fn set_niceness(command: &mut Command, nice: i32) -> Result<()> {
+    use std::os::windows::process::CommandExt;
+
+    const IDLE_PRIORITY_CLASS: u32 = 0x0000_0040;
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+    const NORMAL_PRIORITY_CLASS: u32 = 0x0000_0020;
+    const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x0000_8000;
+    const HIGH_PRIORITY_CLASS: u32 = 0x0000_0080;
+    command.creation_flags(match nice {
+        15.. => IDLE_PRIORITY_CLASS,
+        1..=14 => BELOW_NORMAL_PRIORITY_CLASS,
+        0 => NORMAL_PRIORITY_CLASS,
+        -14..=-1 => ABOVE_NORMAL_PRIORITY_CLASS,
+        _ => HIGH_PRIORITY_CLASS,
+    });
+    Ok(())
+}
+
+/// Set the scheduling priority of the process spawned by `command`
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn set_niceness(_command: &mut Command, _nice: i32) -> Result<()> {
+    Err(Error::msg(
+        "Setting the priority of qemu is not supported on this platform",
+    ))
+}
+
+/// Command running `command_line` with the shell of the host
+#[cfg(windows)]
+pub(crate) fn shell_command(command_line: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(command_line);
+    command
+}
+
+/// Command running `command_line` with the shell of the host
+#[cfg(not(windows))]
+pub(crate) fn shell_command(command_line: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(command_line);
+    command
+}
+
+/// Whether KVM can be used by the current user
+#[cfg(target_os = "linux")]
+pub fn kvm_accessible() -> bool {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+        .is_ok()
+}
+
+/// Whether KVM can be used by the current user
+#[cfg(not(target_os = "linux"))]
+pub fn kvm_accessible() -> bool {
+    false
+}
+
+/// Check that a feature described by `feature` that requires Linux can be used
+#[cfg(target_os = "linux")]
+pub(crate) fn require_linux(_feature: &str) -> Result<()> {
+    Ok(())
+}
+
+/// Check that a feature described by `feature` that requires Linux can be used
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn require_linux(feature: &str) -> Result<()> {
+    Err(Error::msg(format!(
+        "{} is only supported on Linux",
+        feature
+    )))
+}
+
+/// Allow the process spawned by `command` to write core dumps of unlimited size
+#[cfg(unix)]
+pub(crate) fn enable_core_dumps(command: &mut Command) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    // Safety: `setrlimit` is async-signal-safe.
+    unsafe {
+        command.pre_exec(|| {
+            let limit = libc::rlimit {
+                rlim_cur: libc::RLIM_INFINITY,
+                rlim_max: libc::RLIM_INFINITY,
+            };
+            if libc::setrlimit(libc::RLIMIT_CORE, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Allow the process spawned by `command` to write core dumps of unlimited size
+#[cfg(not(unix))]
+pub(crate) fn enable_core_dumps(_command: &mut Command) -> Result<()> {
+    Err(Error::msg("Core dumps are only supported on Unix"))
+}
+
+/// Limit the CPU time of the process spawned by `command` to `seconds`
+///
+/// The process receives `SIGXCPU` when it exceeds the limit and `SIGKILL` a second later.
+#[cfg(unix)]
+pub(crate) fn set_cpu_time_limit(command: &mut Command, seconds: u64) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let limit = libc::rlimit {
+        rlim_cur: seconds as libc::rlim_t,
+        rlim_max: seconds.saturating_add(1) as libc::rlim_t,
+    };
+    // Safety: `setrlimit` is async-signal-safe.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setrlimit(libc::RLIMIT_CPU, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Limit the CPU time of the process spawned by `command` to `seconds`
+#[cfg(not(unix))]
+pub(crate) fn set_cpu_time_limit(_command: &mut Command, _seconds: u64) -> Result<()> {
+    Err(Error::msg("CPU time limits are only supported on Unix"))
+}
+
+/// cgroup limiting the memory of qemu. It is removed when dropped.
+#[derive(Debug)]
+pub(crate) struct MemoryCgroup {
+    path: std::path::PathBuf,
+}
+
+impl MemoryCgroup {
+    /// Number of processes killed because the cgroup ran out of memory
+    pub(crate) fn oom_kills(&self) -> u64 {
+        std::fs::read_to_string(self.path.join("memory.events"))
+            .unwrap_or_default()
+            .lines()
+            .find_map(|line| line.strip_prefix("oom_kill "))
+            .and_then(|count| count.trim().parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for MemoryCgroup {
+    fn drop(&mut self) {
+        // This fails if processes are left in the cgroup, e.g. because qemu could not be killed
+        let _ = std::fs::remove_dir(&self.path);
+        #[cfg(target_os = "linux")]
+        release_memory_parent();
+    }
+}
+
+/// Leaf cgroup uefi-run moves itself to below the cgroup it was started in
+///
+/// cgroup v2 only enables controllers for the children of cgroups without processes of their own.
+#[cfg(target_os = "linux")]
+const LEAF_CGROUP: &str = "uefi-run";
+
+/// Hint for running uefi-run in a cgroup it can create memory-limited children in
+#[cfg(target_os = "linux")]
+const DELEGATION_HINT: &str = "run uefi-run in its own delegated cgroup \
+     (e.g. with systemd-run --user --scope -p Delegate=yes)";
+
+/// cgroup the memory-limited cgroups are created in and the changes made to set it up
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct MemoryParent {
+    path: std::path::PathBuf,
+    /// Leaf cgroup uefi-run moved itself to and whether it was created for that
+    leaf: Option<(std::path::PathBuf, bool)>,
+    /// Whether the memory controller was enabled for the children of `path`
+    enabled_memory: bool,
+}
+
+/// Memory-limited cgroups in use and the parent they were created in
+///
+/// The changes to the cgroup of uefi-run are undone once the last of them is removed.
+#[cfg(target_os = "linux")]
+static MEMORY_PARENT: std::sync::Mutex<(usize, Option<MemoryParent>)> =
+    std::sync::Mutex::new((0, None));
+
+/// Whether the memory controller is enabled for the children of the cgroup at `dir`
+#[cfg(target_os = "linux")]
+fn memory_enabled(dir: &std::path::Path) -> bool {
+    std::fs::read_to_string(dir.join("cgroup.subtree_control"))
+        .unwrap_or_default()
+        .split_whitespace()
+        .any(|name| name == "memory")
+}
+
+/// Prepare the delegated cgroup at `dir` uefi-run (`pid`) runs in as parent of memory-limited
+/// cgroups
+///
+/// uefi-run moves itself to `LEAF_CGROUP` below `dir` and enables the memory controller for the
+/// children of `dir`. Nothing is changed if the memory controller is enabled for `dir` already
+/// or uefi-run runs in a leaf whose parent has it enabled.
+#[cfg(target_os = "linux")]
+fn prepare_memory_parent(dir: &std::path::Path, pid: u32) -> Result<MemoryParent> {
+    let unchanged = |path: &std::path::Path| MemoryParent {
+        path: path.to_path_buf(),
+        leaf: None,
+        enabled_memory: false,
+    };
+    if let Some(parent) = dir.parent() {
+        if dir.file_name() == Some(std::ffi::OsStr::new(LEAF_CGROUP)) && memory_enabled(parent) {
+            return Ok(unchanged(parent));
+        }
+    }
+    if memory_enabled(dir) {
+        // Only the root cgroup may have processes and controllers for its children
+        return Ok(unchanged(dir));
+    }
+    let available = std::fs::read_to_string(dir.join("cgroup.controllers"))
+        .map_err(|_| Error::msg("Limiting memory requires cgroup v2 mounted at /sys/fs/cgroup"))?;
+    if !available.split_whitespace().any(|name| name == "memory") {
+        return Err(Error::msg(format!(
+            "The memory controller is not available in {}, {}",
+            dir.display(),
+            DELEGATION_HINT
+        )));
+    }
+    let leaf = dir.join(LEAF_CGROUP);
+    let created = !leaf.is_dir();
+    if created {
+        std::fs::create_dir(&leaf).map_err(|err| {
+            Error::msg(format!(
+                "Unable to create cgroup {} ({}), {}",
+                leaf.display(),
+                err,
+                DELEGATION_HINT
+            ))
+        })?;
+    }
+    let mut parent = MemoryParent {
+        path: dir.to_path_buf(),
+        leaf: Some((leaf.clone(), created)),
+        enabled_memory: false,
+    };
+    if let Err(err) = std::fs::write(leaf.join("cgroup.procs"), pid.to_string()) {
+        if created {
+            let _ = std::fs::remove_dir(&leaf);
+        }
+        return Err(Error::msg(format!(
+            "Unable to move uefi-run to {} ({}), {}",
+            leaf.display(),
+            err,
+            DELEGATION_HINT
+        )));
+    }
+    if let Err(err) = std::fs::write(dir.join("cgroup.subtree_control"), "+memory") {
+        let _ = restore_memory_parent(&parent, pid);
+        return Err(Error::msg(format!(
+            "Unable to enable the memory controller for {} ({}), {}",
+            dir.display(),
+            err,
+            DELEGATION_HINT
+        )));
+    }
+    parent.enabled_memory = true;
+    Ok(parent)
+}
+
+/// Undo the changes `prepare_memory_parent` made for uefi-run (`pid`)
+#[cfg(target_os = "linux")]
+fn restore_memory_parent(parent: &MemoryParent, pid: u32) -> Result<()> {
+    if parent.enabled_memory {
+        std::fs::write(parent.path.join("cgroup.subtree_control"), "-memory")?;
+    }
+    if let Some((leaf, created)) = &parent.leaf {
+        std::fs::write(parent.path.join("cgroup.procs"), pid.to_string())?;
+        if *created {
+            std::fs::remove_dir(leaf)?;
+        }
+    }
+    Ok(())
+}
+
+/// Give up a memory-limited cgroup and restore the cgroup of uefi-run after the last one
+#[cfg(target_os = "linux")]
+fn release_memory_parent() {
+    let mut state = MEMORY_PARENT.lock().unwrap_or_else(|err| err.into_inner());
+    state.0 = state.0.saturating_sub(1);
+    if state.0 == 0 {
+        if let Some(parent) = state.1.take() {
+            // Errors can not be reported when dropping, the cgroup tree is left as it is
+            let _ = restore_memory_parent(&parent, std::process::id());
+        }
+    }
+}
+
+/// Place the process spawned by `command` in a new cgroup (v2) limiting its memory to `bytes`
+///
+/// The cgroup is created next to a leaf cgroup uefi-run moves itself to, both below the cgroup
+/// uefi-run was started in. That cgroup needs to be delegated to the user, have the memory
+/// controller available and must not contain other processes. uefi-run moves back and removes
+/// the leaf once the last memory-limited cgroup is dropped.
+#[cfg(target_os = "linux")]
+pub(crate) fn limit_memory(command: &mut Command, bytes: u64) -> Result<MemoryCgroup> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::process::CommandExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let parent = {
+        let mut state = MEMORY_PARENT.lock().unwrap_or_else(|err| err.into_inner());
+        if state.1.is_none() {
+            let own_cgroup = std::fs::read_to_string("/proc/self/cgroup")?
+                .lines()
+                .find_map(|line| line.strip_prefix("0::").map(str::to_string))
+                .ok_or_else(|| Error::msg("Limiting memory requires cgroup v2"))?;
+            let own_dir =
+                std::path::Path::new("/sys/fs/cgroup").join(own_cgroup.trim_start_matches('/'));
+            state.1 = Some(prepare_memory_parent(&own_dir, std::process::id())?);
+        }
+        // Released when the `MemoryCgroup` is dropped
+        state.0 += 1;
+        state
+            .1
+            .as_ref()
+            .map(|parent| parent.path.clone())
+            .unwrap_or_default()
+    };
+    let path = parent.join(format!(
+        "uefi-run-{}-{}",
+        std::process::id(),
+        NEXT_ID.fetch_add(1, Ordering::SeqCst)
+    ));
+    if let Err(err) = std::fs::create_dir(&path) {
+        release_memory_parent();
+        return Err(Error::msg(format!(
+            "Unable to create cgroup {}: {}",
+            path.display(),
+            err
+        )));
+    }
+    let cgroup = MemoryCgroup { path };
+    std::fs::write(cgroup.path.join("memory.max"), bytes.to_string())?;
+    // Swapping would let qemu exceed the limit. Not all systems have swap accounting.
+    let _ = std::fs::write(cgroup.path.join("memory.swap.max"), "0");
+    let procs = std::ffi::CString::new(cgroup.path.join("cgroup.procs").as_os_str().as_bytes())?;
+    // Safety: `open`, `write` and `close` are async-signal-safe. Writing 0 moves the writing
+    // process, so qemu runs in the cgroup from the start.
+    unsafe {
+        command.pre_exec(move || {
+            let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+            let error = std::io::Error::last_os_error();
+            libc::close(fd);
+            if written != 1 {
+                return Err(error);
+            }
+            Ok(())
+        });
+    }
+    Ok(cgroup)
+}
+
+/// Place the process spawned by `command` in a cgroup limiting its memory to `bytes`
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn limit_memory(_command: &mut Command, _bytes: u64) -> Result<MemoryCgroup> {
+    Err(Error::msg("Limiting memory is only supported on Linux"))
+}
+
+/// Whether a process was terminated for exceeding its CPU time limit
+///
+/// The hard limit kills the process, so `SIGKILL` is included as well.
+#[cfg(unix)]
+pub(crate) fn cpu_time_limit_exceeded(status: &ExitStatus) -> bool {
+    matches!(
+        exit_signal(status),
+        Some(libc::SIGXCPU) | Some(libc::SIGKILL)
+    )
+}
+
+/// Whether a process was terminated for exceeding its CPU time limit
+#[cfg(not(unix))]
+pub(crate) fn cpu_time_limit_exceeded(_status: &ExitStatus) -> bool {
+    false
+}
+
+/// Signal that terminated a process
+#[cfg(unix)]
+pub(crate) fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+/// Signal that terminated a process
+#[cfg(not(unix))]
+pub(crate) fn exit_signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+/// Whether a process that was terminated by a signal dumped core
+#[cfg(unix)]
+pub(crate) fn core_dumped(status: &ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    status.core_dumped()
+}
+
+/// Whether a process that was terminated by a signal dumped core
+#[cfg(not(unix))]
+pub(crate) fn core_dumped(_status: &ExitStatus) -> bool {
+    false
+}
+
+/// Describe where the kernel writes core dumps
+#[cfg(target_os = "linux")]
+pub fn core_dump_location() -> String {
+    match std::fs::read_to_string("/proc/sys/kernel/core_pattern") {
+        Ok(pattern) => {
+            let pattern = pattern.trim();
+            if let Some(handler) = pattern.strip_prefix('|') {
+                format!("piped to {}", handler)
+            } else if pattern.starts_with('/') {
+                pattern.to_string()
+            } else {
+                format!("{} in the working directory", pattern)
+            }
+        }
+        Err(_) => "unknown".to_string(),
+    }
+}
+
+/// Describe where the kernel writes core dumps
+#[cfg(not(target_os = "linux"))]
+pub fn core_dump_location() -> String {
+    "core file in the working directory (system dependent)".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_prepare_memory_parent() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().join("scope");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("cgroup.controllers"), "cpu memory pids").unwrap();
+        std::fs::write(dir.join("cgroup.subtree_control"), "").unwrap();
+
+        let parent = prepare_memory_parent(&dir, 42).unwrap();
+        let leaf = dir.join(LEAF_CGROUP);
+        assert_eq!(parent.path, dir);
+        assert_eq!(
+            std::fs::read_to_string(leaf.join("cgroup.procs")).unwrap(),
+            "42"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("cgroup.subtree_control")).unwrap(),
+            "+memory"
+        );
+        // The real files list enabled controllers without "+"
+        std::fs::write(dir.join("cgroup.subtree_control"), "memory").unwrap();
+        let unchanged = prepare_memory_parent(&leaf, 42).unwrap();
+        assert_eq!(unchanged.path, dir);
+        assert!(unchanged.leaf.is_none() && !unchanged.enabled_memory);
+        assert_eq!(prepare_memory_parent(&dir, 42).unwrap().path, dir);
+
+        // Unlike in the real cgroup file system, files keep a directory from being removed
+        std::fs::remove_file(leaf.join("cgroup.procs")).unwrap();
+        restore_memory_parent(&parent, 42).unwrap();
+        assert!(!leaf.exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("cgroup.procs")).unwrap(),
+            "42"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("cgroup.subtree_control")).unwrap(),
+            "-memory"
+        );
+
+        let no_memory = temp_dir.path().join("no-memory");
+        std::fs::create_dir(&no_memory).unwrap();
+        std::fs::write(no_memory.join("cgroup.controllers"), "cpu pids").unwrap();
+        std::fs::write(no_memory.join("cgroup.subtree_control"), "").unwrap();
+        assert!(prepare_memory_parent(&no_memory, 42).is_err());
+        assert!(!no_memory.join(LEAF_CGROUP).exists());
+    }
+
+    /// Only runs in a delegated cgroup v2 with the memory controller, e.g. with
+    /// `systemd-run --user --scope -p Delegate=yes cargo test`
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_limit_memory() {
+        let own_dir = match std::fs::read_to_string("/proc/self/cgroup")
+            .unwrap_or_default()
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+        {
+            Some(own_cgroup) => {
+                std::path::Path::new("/sys/fs/cgroup").join(own_cgroup.trim_start_matches('/'))
+            }
+            None => return,
+        };
+        let delegated = std::fs::metadata(own_dir.join("cgroup.subtree_control"))
+            .map(|metadata| !metadata.permissions().readonly())
+            .unwrap_or(false)
+            && std::fs::read_to_string(own_dir.join("cgroup.controllers"))
+                .unwrap_or_default()
+                .split_whitespace()
+                .any(|name| name == "memory");
+        if !delegated {
+            return;
+        }
+        let own_cgroup = std::fs::read_to_string("/proc/self/cgroup").unwrap();
+        let subtree_control =
+            std::fs::read_to_string(own_dir.join("cgroup.subtree_control")).unwrap();
+
+        let mut command = Command::new("cat");
+        command.arg("/proc/self/cgroup");
+        let cgroup = limit_memory(&mut command, 64 << 20).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(cgroup.path.join("memory.max"))
+                .unwrap()
+                .trim(),
+            (64u64 << 20).to_string()
+        );
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        let name = cgroup.path.file_name().unwrap().to_str().unwrap();
+        assert!(String::from_utf8_lossy(&output.stdout).contains(name));
+        let path = cgroup.path.clone();
+        drop(cgroup);
+        assert!(!path.exists());
+        // The cgroup of the test process is restored
+        assert_eq!(
+            std::fs::read_to_string("/proc/self/cgroup").unwrap(),
+            own_cgroup
+        );
+        assert_eq!(
+            std::fs::read_to_string(own_dir.join("cgroup.subtree_control")).unwrap(),
+            subtree_control
+        );
+    }
+}
@@ -1,6 +1,13 @@
 use super::*;
-use std::process::{Child, Command};
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStderr, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use wait_timeout::ChildExt;
 
 /// Qemu run configuration
@@ -8,8 +15,179 @@ use wait_timeout::ChildExt;
 pub struct QemuConfig {
     pub qemu_path: String,
     pub bios_path: String,
+    /// UEFI variable store attached as second flash device
+    ///
+    /// If set, `bios_path` has to be the code part of a split firmware and is attached as first
+    /// flash device instead of being passed with `-bios`.
+    pub vars_path: Option<String>,
+    /// Attach the code part of a split firmware writable instead of read-only
+    pub bios_writable: bool,
     pub drives: Vec<QemuDriveConfig>,
     pub additional_args: Vec<String>,
+    /// Leave out the firmware and the `-net none` default, so only explicitly configured
+    /// arguments are passed
+    ///
+    /// The firmware and the ESP then have to be attached with additional arguments.
+    pub no_default_args: bool,
+    /// Exit instead of rebooting when the guest resets
+    pub no_reboot: bool,
+    /// Pause instead of exiting when the guest shuts down
+    pub no_shutdown: bool,
+    /// Attach the human monitor to stdio and keep qemu running after the guest shut down until
+    /// it is quit from the monitor
+    pub interactive_monitor: bool,
+    /// Number of guest resets after which qemu is stopped at the next reset
+    ///
+    /// Resets are detected over QMP, so this enables it.
+    pub reboot_count: Option<usize>,
+    /// Action to take when the guest watchdog fires
+    pub watchdog_action: Option<String>,
+    /// Host CPUs the qemu process is pinned to
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// Run qemu with a minimal environment holding only `PATH` and `env`
+    pub clear_env: bool,
+    /// Environment variables set for qemu
+    pub env: Vec<(String, String)>,
+    /// Allow qemu to write a core dump if it crashes (Unix only)
+    pub core_dump: bool,
+    /// CPU time in seconds qemu may use before it is terminated (Unix only)
+    pub cpu_time_limit: Option<u64>,
+    /// Memory in bytes qemu may use, enforced with a cgroup (Linux only)
+    ///
+    /// While qemu runs, the calling process is moved to a leaf cgroup below its own and the
+    /// memory controller is enabled for the children of its cgroup. Both are undone when the
+    /// last memory-limited qemu exits.
+    pub memory_limit: Option<u64>,
+    /// Nice value (-20 to 19) qemu runs with. On Windows it selects a priority class.
+    pub nice: Option<i32>,
+    /// Do not provide ACPI tables to the firmware
+    pub no_acpi: bool,
+    /// Accelerator running the guest (e.g. `kvm` or `tcg`). By default qemu picks one.
+    pub accel: Option<String>,
+    /// Seed of qemu's guest random number generator for reproducible runs
+    ///
+    /// Only passed to qemu 4.0 and later.
+    pub seed: Option<u64>,
+    /// CPU model with optional features (e.g. `host,+x2apic`)
+    ///
+    /// This replaces the CPU model selected for the architecture.
+    pub cpu: Option<String>,
+    /// NUMA node specifications (e.g. `nodeid=0,cpus=0-1,mem=512M`)
+    ///
+    /// `mem=` is translated into a RAM memory backend since newer machine types do not accept
+    /// it anymore. The nodes must match the `-smp` and `-m` configuration.
+    pub numa_nodes: Vec<String>,
+    /// Guest memory, including the slots for hotplugged memory
+    pub memory: Option<QemuMemory>,
+    /// Sizes in bytes of the DIMMs plugged into the memory slots
+    ///
+    /// This exercises the ACPI memory hotplug paths of the firmware.
+    pub memory_hotplug: Vec<u64>,
+    /// Kernel passed to the firmware with `-kernel`
+    pub kernel: Option<String>,
+    /// Let qemu load the kernel directly instead of booting the firmware
+    ///
+    /// No firmware arguments are passed to qemu, so UEFI is bypassed entirely.
+    pub direct_kernel: bool,
+    /// Initial ramdisk loaded together with the kernel
+    pub initrd: Option<String>,
+    /// Kernel command line. It is passed to qemu as a single argument, so it may contain spaces.
+    pub append: Option<String>,
+    /// Device tree blob passed to the guest instead of the one generated by qemu (AArch64 only)
+    pub dtb: Option<String>,
+    /// Network configuration of the guest
+    pub network: QemuNetwork,
+    /// MAC address of the network card (e.g. `52:54:00:12:34:56`). By default qemu assigns one.
+    pub mac: Option<String>,
+    /// Only boot from disks and disable the boot ROM of the network card
+    ///
+    /// The ESP is the first drive, so the firmware boots it unless it is not bootable. Drives
+    /// added with `--drive` remain possible boot targets.
+    pub esp_only: bool,
+    /// 24-bit BMP image shown by the firmware as boot splash
+    ///
+    /// qemu passes it over fw_cfg as `bootsplash.bmp` together with the boot menu timeout. SeaBIOS
+    /// shows it, while OVMF ignores it and shows the logo built into the firmware. The firmware
+    /// picks the display resolution.
+    pub splash: Option<String>,
+    /// Character device backend of the first serial port (e.g. `file,path=serial.log`)
+    ///
+    /// With a ring buffer (`ringbuf,size=<bytes>`) the output is kept in memory and can be read
+    /// over QMP. `none` disables the serial port. By default qemu picks the backend.
+    pub serial: Option<String>,
+    /// Character device backend of a virtio console (e.g. `stdio` or `file,path=console.log`)
+    pub virtio_console: Option<String>,
+    /// Character device backend of the virtio-serial port the guest writes structured results to
+    ///
+    /// The port is named `RESULTS_PORT_NAME`.
+    pub results_chardev: Option<String>,
+    /// virtio-serial controller the virtio console and the results port are attached to
+    pub virtio_serial_device: String,
+    /// Character device backend receiving the firmware debug log written to I/O port 0x402
+    ///
+    /// This needs an ISA bus, so it is only available on x86.
+    pub debugcon: Option<String>,
+    /// Host directory exported read-only to the guest over virtio-9p with the mount tag
+    /// `SHARE_MOUNT_TAG`
+    pub shared_dir: Option<String>,
+    /// Host PCI devices (e.g. `0000:01:00.0`) passed through to the guest with vfio (Linux only)
+    pub vfio_devices: Vec<String>,
+    /// USB devices (e.g. `usb-kbd` or `usb-storage,drive=data`) attached to a USB controller
+    pub usb_devices: Vec<String>,
+    /// Device of the USB controller that is added if there are USB devices
+    pub usb_controller: String,
+    /// Emulated VGA card (e.g. `std`, `cirrus` or `none`)
+    pub vga: Option<String>,
+    /// virtio-gpu device used as display instead of a VGA card
+    pub virtio_gpu_device: Option<String>,
+    /// pvpanic device the guest reports panics to
+    ///
+    /// Panics are detected over QMP, so this enables it. qemu is stopped once the guest panicked.
+    pub pvpanic_device: Option<String>,
+    /// isa-debug-exit device the guest writes its exit code to (x86_64 only)
+    pub debug_exit: Option<DebugExitConfig>,
+    /// Snapshot to resume from with `-loadvm`. It is stored in a qcow2 drive.
+    pub loadvm: Option<String>,
+    /// Real time clock configuration (e.g. `base=utc,clock=host`)
+    pub rtc: Option<String>,
+    /// Clock the real time clock of the guest is driven by
+    pub rtc_clock: Option<RtcClock>,
+    /// Slew the real time clock to catch up on ticks lost while the guest was not scheduled
+    pub rtc_driftfix: bool,
+    /// Prefix every line qemu writes to stdout (e.g. the serial console) with the host time
+    pub serial_timestamps: bool,
+    /// Items of qemu's debug log to enable with `-d` (e.g. `int,cpu_reset`)
+    ///
+    /// The log is written to stderr.
+    pub debug_log: Option<String>,
+    /// Write markers for lifecycle points of qemu (spawn, first serial output, exit) to the debug
+    /// log stream
+    ///
+    /// This captures stdout to detect the first serial output.
+    pub phase_markers: bool,
+    /// Write everything qemu prints to stdout and stderr to this file as well
+    ///
+    /// This captures stdout.
+    pub output_log: Option<PathBuf>,
+    /// Forward stdout through uefi-run, so it is known whether qemu wrote any output
+    pub capture_stdout: bool,
+    /// Reduce the messages printed by qemu
+    ///
+    /// Warnings qemu writes to stderr are not forwarded. They are still considered when analyzing
+    /// errors.
+    pub quiet: bool,
+    /// Save a screenshot (PPM) to this path before qemu exits
+    ///
+    /// This enables QMP and keeps qemu alive after the guest shut down until the screenshot is
+    /// taken.
+    pub screenshot: Option<PathBuf>,
+    /// Monitor qemu over QMP
+    pub qmp: bool,
+    /// Version of the qemu executable, used to select the argument syntax
+    ///
+    /// If the version is unknown, the legacy syntax is used since it is understood by all qemu
+    /// versions.
+    pub qemu_version: Option<QemuVersion>,
 }
 
 impl Default for QemuConfig {
@@ -17,36 +195,1046 @@ impl Default for QemuConfig {
         Self {
             qemu_path: "qemu-system-x86_64".to_string(),
             bios_path: "OVMF.fd".to_string(),
+            vars_path: None,
+            bios_writable: false,
             drives: Vec::new(),
-            additional_args: vec!["-net".to_string(), "none".to_string()],
+            additional_args: Vec::new(),
+            no_default_args: false,
+            no_reboot: false,
+            no_shutdown: false,
+            interactive_monitor: false,
+            reboot_count: None,
+            watchdog_action: None,
+            cpu_affinity: None,
+            clear_env: false,
+            env: Vec::new(),
+            core_dump: false,
+            cpu_time_limit: None,
+            memory_limit: None,
+            nice: None,
+            no_acpi: false,
+            accel: None,
+            cpu: None,
+            numa_nodes: Vec::new(),
+            seed: None,
+            memory: None,
+            memory_hotplug: Vec::new(),
+            kernel: None,
+            direct_kernel: false,
+            initrd: None,
+            append: None,
+            dtb: None,
+            network: QemuNetwork::default(),
+            mac: None,
+            esp_only: false,
+            splash: None,
+            serial: None,
+            virtio_console: None,
+            debugcon: None,
+            results_chardev: None,
+            virtio_serial_device: Arch::default().virtio_serial_device().to_string(),
+            shared_dir: None,
+            vfio_devices: Vec::new(),
+            usb_devices: Vec::new(),
+            usb_controller: UsbController::default()
+                .device(Arch::default())
+                .unwrap_or_default()
+                .to_string(),
+            vga: None,
+            virtio_gpu_device: None,
+            pvpanic_device: None,
+            debug_exit: None,
+            loadvm: None,
+            rtc: None,
+            rtc_clock: None,
+            rtc_driftfix: false,
+            serial_timestamps: false,
+            debug_log: None,
+            phase_markers: false,
+            output_log: None,
+            capture_stdout: false,
+            quiet: false,
+            screenshot: None,
+            qmp: false,
+            qemu_version: None,
         }
     }
 }
 
 impl QemuConfig {
-    /// Run an instance of qemu with the given config
-    pub fn run(&self) -> Result<QemuProcess> {
-        let mut args = vec!["-bios".to_string(), self.bios_path.clone()];
-        for (index, drive) in self.drives.iter().enumerate() {
+    /// Command line arguments passed to qemu
+    pub fn args(&self) -> Vec<String> {
+        let caps = QemuCapabilities::for_version(self.qemu_version);
+        let mut args = match &self.vars_path {
+            _ if self.direct_kernel || self.no_default_args => Vec::new(),
+            Some(vars_path) => vec![
+                "-drive".to_string(),
+                format!(
+                    "if=pflash,format=raw,unit=0,{}file={}",
+                    if self.bios_writable {
+                        ""
+                    } else {
+                        "readonly=on,"
+                    },
+                    self.bios_path
+                ),
+                "-drive".to_string(),
+                format!("if=pflash,format=raw,unit=1,file={}", vars_path),
+            ],
+            None => vec!["-bios".to_string(), self.bios_path.clone()],
+        };
+        for drive in &self.drives {
             args.push("-drive".to_string());
+            args.push(drive.to_arg());
+        }
+        if caps.action {
+            let mut actions = Vec::new();
+            if self.no_reboot {
+                actions.push("reboot=shutdown".to_string());
+            }
+            if self.pause_on_shutdown() {
+                actions.push("shutdown=pause".to_string());
+            }
+            if let Some(watchdog_action) = &self.watchdog_action {
+                actions.push(format!("watchdog={}", watchdog_action));
+            }
+            if !actions.is_empty() {
+                args.push("-action".to_string());
+                args.push(actions.join(","));
+            }
+        } else {
+            if self.no_reboot {
+                args.push("-no-reboot".to_string());
+            }
+            if self.pause_on_shutdown() {
+                args.push("-no-shutdown".to_string());
+            }
+            if let Some(watchdog_action) = &self.watchdog_action {
+                args.push("-watchdog-action".to_string());
+                args.push(watchdog_action.clone());
+            }
+        }
+        if self.no_acpi {
+            if caps.machine_acpi {
+                args.push("-machine".to_string());
+                args.push("acpi=off".to_string());
+            } else {
+                args.push("-no-acpi".to_string());
+            }
+        }
+        if let Some(accel) = &self.accel {
+            args.push("-accel".to_string());
+            args.push(accel.clone());
+        }
+        if let Some(cpu) = &self.cpu {
+            args.push("-cpu".to_string());
+            args.push(cpu.clone());
+        }
+        if let (Some(seed), true) = (self.seed, caps.seed) {
+            args.push("-seed".to_string());
+            args.push(seed.to_string());
+        }
+        if let Some(memory) = &self.memory {
+            args.push("-m".to_string());
+            args.push(memory.to_arg());
+        }
+        for (index, size) in self.memory_hotplug.iter().enumerate() {
+            args.push("-object".to_string());
+            args.push(format!(
+                "memory-backend-ram,id=hotplug-mem{},size={}",
+                index,
+                format_size(*size)
+            ));
+            args.push("-device".to_string());
+            args.push(format!("pc-dimm,id=dimm{0},memdev=hotplug-mem{0}", index));
+        }
+        for (index, node) in self.numa_nodes.iter().enumerate() {
+            let mut properties = Vec::new();
+            for property in node.split(',') {
+                match property.strip_prefix("mem=") {
+                    Some(size) => {
+                        let memdev = format!("numa-mem{}", index);
+                        args.push("-object".to_string());
+                        args.push(format!("memory-backend-ram,id={},size={}", memdev, size));
+                        properties.push(format!("memdev={}", memdev));
+                    }
+                    None => properties.push(property.to_string()),
+                }
+            }
+            args.push("-numa".to_string());
+            args.push(format!("node,{}", properties.join(",")));
+        }
+        for (flag, value) in [
+            ("-kernel", &self.kernel),
+            ("-initrd", &self.initrd),
+            ("-append", &self.append),
+            ("-dtb", &self.dtb),
+        ] {
+            if let Some(value) = value {
+                args.push(flag.to_string());
+                args.push(value.clone());
+            }
+        }
+        if let Some(splash) = &self.splash {
+            args.push("-boot".to_string());
+            args.push(format!("menu=on,splash={}", splash));
+        } else if self.esp_only {
+            args.push("-boot".to_string());
+            args.push("order=c,strict=on,menu=off".to_string());
+        }
+        if !(self.no_default_args && self.network == QemuNetwork::None) {
+            args.extend(self.network.args(!self.esp_only, self.mac.as_deref()));
+        }
+        if self.serial.as_deref() == Some("none") {
+            args.push("-serial".to_string());
+            args.push("none".to_string());
+        } else if let Some(backend) = &self.serial {
+            args.push("-chardev".to_string());
+            args.push(format!("{},id=serial0", backend));
+            args.push("-serial".to_string());
+            args.push("chardev:serial0".to_string());
+        }
+        if let Some(backend) = &self.virtio_console {
+            args.push("-chardev".to_string());
+            args.push(format!("{},id=virtcon0", backend));
+            args.push("-device".to_string());
+            args.push(self.virtio_serial_device.clone());
+            args.push("-device".to_string());
+            args.push("virtconsole,chardev=virtcon0".to_string());
+        }
+        if let Some(backend) = &self.results_chardev {
+            args.push("-chardev".to_string());
+            args.push(format!("{},id=results0", backend));
+            args.push("-device".to_string());
+            args.push(self.virtio_serial_device.clone());
+            args.push("-device".to_string());
             args.push(format!(
-                "file={},index={},media={},format={}",
-                drive.file, index, drive.media, drive.format
+                "virtserialport,chardev=results0,name={}",
+                RESULTS_PORT_NAME
             ));
         }
+        if let Some(backend) = &self.debugcon {
+            args.push("-chardev".to_string());
+            args.push(format!("{},id=debugcon0", backend));
+            args.push("-device".to_string());
+            args.push("isa-debugcon,iobase=0x402,chardev=debugcon0".to_string());
+        }
+        if let Some(shared_dir) = &self.shared_dir {
+            args.push("-virtfs".to_string());
+            args.push(format!(
+                "local,path={},mount_tag={},security_model=none,readonly=on",
+                shared_dir, SHARE_MOUNT_TAG
+            ));
+        }
+        for device in &self.vfio_devices {
+            args.push("-device".to_string());
+            args.push(format!("vfio-pci,host={}", device));
+        }
+        if !self.usb_devices.is_empty() {
+            args.push("-device".to_string());
+            args.push(format!("{},id=usb0", self.usb_controller));
+            for device in &self.usb_devices {
+                args.push("-device".to_string());
+                args.push(format!("{},bus=usb0.0", device));
+            }
+        }
+        if let Some(vga) = &self.vga {
+            args.push("-vga".to_string());
+            args.push(vga.clone());
+        }
+        if let Some(device) = &self.virtio_gpu_device {
+            // Make sure the firmware does not pick the default VGA card instead
+            if self.vga.is_none() {
+                args.push("-vga".to_string());
+                args.push("none".to_string());
+            }
+            args.push("-device".to_string());
+            args.push(device.clone());
+        }
+        if let Some(device) = &self.pvpanic_device {
+            args.push("-device".to_string());
+            args.push(device.clone());
+        }
+        if let Some(debug_exit) = &self.debug_exit {
+            args.push("-device".to_string());
+            args.push(debug_exit.to_arg());
+        }
+        if let Some(loadvm) = &self.loadvm {
+            args.push("-loadvm".to_string());
+            args.push(loadvm.clone());
+        }
+        let mut rtc = self.rtc.iter().cloned().collect::<Vec<_>>();
+        if let Some(clock) = self.rtc_clock {
+            rtc.push(format!("clock={}", clock.as_str()));
+        }
+        if self.rtc_driftfix {
+            rtc.push("driftfix=slew".to_string());
+        }
+        if !rtc.is_empty() {
+            args.push("-rtc".to_string());
+            args.push(rtc.join(","));
+        }
+        if let Some(items) = &self.debug_log {
+            args.push("-d".to_string());
+            args.push(items.clone());
+        }
+        if self.interactive_monitor {
+            args.push("-monitor".to_string());
+            args.push("stdio".to_string());
+        }
+        if self.quiet {
+            args.push("-msg".to_string());
+            args.push("timestamp=off".to_string());
+        }
         args.extend(self.additional_args.iter().cloned());
+        args
+    }
+
+    /// Size of the ring buffer the serial port is captured in, if any
+    pub fn serial_ringbuf_size(&self) -> Option<u64> {
+        self.serial
+            .as_deref()?
+            .strip_prefix("ringbuf,size=")?
+            .parse()
+            .ok()
+    }
+
+    /// Whether qemu has to stay alive after the guest shut down until it was inspected over QMP
+    fn inspect_after_shutdown(&self) -> bool {
+        self.screenshot.is_some() || self.serial_ringbuf_size().is_some()
+    }
+
+    /// Whether qemu keeps running with the guest paused once the guest shut down
+    fn pause_on_shutdown(&self) -> bool {
+        self.no_shutdown || self.interactive_monitor || self.inspect_after_shutdown()
+    }
+
+    /// Whether the generated arguments depend on the qemu version
+    pub fn needs_qemu_version(&self) -> bool {
+        self.no_reboot
+            || self.pause_on_shutdown()
+            || self.watchdog_action.is_some()
+            || self.no_acpi
+            || self.seed.is_some()
+    }
+
+    /// Check the configuration for combinations qemu does not accept
+    pub fn validate(&self) -> Result<()> {
+        if self.virtio_gpu_device.is_some() && self.vga.as_deref().is_some_and(|vga| vga != "none")
+        {
+            return Err(Error::msg("virtio-gpu can not be combined with a VGA card"));
+        }
+        if self.bios_writable && self.vars_path.is_none() {
+            return Err(Error::msg(
+                "A writable firmware requires a split firmware with a variable store",
+            ));
+        }
+        if self.direct_kernel {
+            if self.kernel.is_none() {
+                return Err(Error::msg("Direct kernel boot requires a kernel"));
+            }
+            if self.vars_path.is_some() {
+                return Err(Error::msg(
+                    "Direct kernel boot does not use a UEFI variable store",
+                ));
+            }
+        }
+        let rtc_options = self
+            .rtc
+            .iter()
+            .flat_map(|rtc| rtc.split(','))
+            .filter_map(|option| option.split_once('=').map(|(key, _)| key))
+            .collect::<Vec<_>>();
+        if self.rtc_clock.is_some() && rtc_options.contains(&"clock") {
+            return Err(Error::msg("The RTC clock is configured twice"));
+        }
+        if self.rtc_driftfix && rtc_options.contains(&"driftfix") {
+            return Err(Error::msg("The RTC drift fix is configured twice"));
+        }
+        for (position, drive) in self.drives.iter().enumerate() {
+            for other in &self.drives[..position] {
+                if other.id() == drive.id() {
+                    return Err(Error::msg(format!(
+                        "Multiple drives have the id {}",
+                        drive.id()
+                    )));
+                }
+                if other.index == drive.index
+                    && other.interface == drive.interface
+                    && drive.interface.as_deref() != Some("none")
+                {
+                    return Err(Error::msg(format!(
+                        "Multiple drives have the index {}",
+                        drive.index
+                    )));
+                }
+            }
+        }
+        let machine_acpi = self
+            .additional_args
+            .windows(2)
+            .any(|pair| (pair[0] == "-machine" || pair[0] == "-M") && pair[1].contains("acpi="));
+        if self.no_acpi && machine_acpi {
+            return Err(Error::msg(
+                "ACPI is already configured in the machine arguments",
+            ));
+        }
+        if self.splash.is_some() && self.esp_only {
+            return Err(Error::msg(
+                "A boot splash requires the boot menu, which is disabled when only booting the ESP",
+            ));
+        }
+        if let Some(memory) = &self.memory {
+            if self.additional_args.iter().any(|arg| arg == "-m") {
+                return Err(Error::msg(
+                    "Memory is already configured in the additional arguments",
+                ));
+            }
+            match (memory.slots, memory.maxmem) {
+                (Some(_), None) | (None, Some(_)) => {
+                    return Err(Error::msg(
+                        "Memory slots and maxmem have to be given together",
+                    ))
+                }
+                (Some(0), _) => return Err(Error::msg("At least one memory slot is required")),
+                _ => {}
+            }
+            if memory.maxmem.is_some_and(|maxmem| maxmem < memory.size) {
+                return Err(Error::msg("maxmem is smaller than the initial memory"));
+            }
+        }
+        if !self.memory_hotplug.is_empty() {
+            let (slots, maxmem) = match self.memory {
+                Some(QemuMemory {
+                    size,
+                    slots: Some(slots),
+                    maxmem: Some(maxmem),
+                }) => (slots as usize, maxmem - size),
+                _ => {
+                    return Err(Error::msg(
+                        "Hotplugged memory requires memory slots and maxmem",
+                    ))
+                }
+            };
+            if self.memory_hotplug.len() > slots {
+                return Err(Error::msg(format!(
+                    "{} DIMMs do not fit into {} memory slots",
+                    self.memory_hotplug.len(),
+                    slots
+                )));
+            }
+            if self.memory_hotplug.iter().sum::<u64>() > maxmem {
+                return Err(Error::msg(
+                    "Hotplugged memory exceeds maxmem minus the initial memory",
+                ));
+            }
+        }
+        if self.phase_markers && self.debug_log.is_none() {
+            return Err(Error::msg("Phase markers require qemu's debug log"));
+        }
+        if self.mac.is_some() && self.network == QemuNetwork::None {
+            return Err(Error::msg("A MAC address requires a network"));
+        }
+        if self.additional_args.iter().any(|arg| arg == "-nographic") {
+            for (name, backend) in [
+                ("serial port", &self.serial),
+                ("virtio console", &self.virtio_console),
+            ] {
+                if backend.as_deref() == Some("stdio") {
+                    return Err(Error::msg(format!(
+                        "-nographic already connects stdio, use -display none to connect the {} \
+                         to stdio",
+                        name
+                    )));
+                }
+            }
+        }
+        if self.interactive_monitor {
+            for (name, backend) in [
+                ("serial port", &self.serial),
+                ("virtio console", &self.virtio_console),
+                ("debug console", &self.debugcon),
+            ] {
+                if backend.as_deref() == Some("stdio") {
+                    return Err(Error::msg(format!(
+                        "The interactive monitor and the {} can not both use stdio",
+                        name
+                    )));
+                }
+            }
+        }
+        if self.no_reboot && self.reboot_count.is_some() {
+            return Err(Error::msg(
+                "A reboot count can not be combined with exiting on the first reboot",
+            ));
+        }
+        if self.loadvm.is_some() && !self.drives.iter().any(|drive| drive.format == "qcow2") {
+            return Err(Error::msg(
+                "Loading a snapshot requires a qcow2 drive holding the snapshot",
+            ));
+        }
+        Ok(())
+    }
 
-        let child = Command::new(&self.qemu_path).args(args).spawn()?;
-        Ok(QemuProcess { child })
+    /// Run an instance of qemu with the given config
+    pub fn run(&self) -> Result<QemuProcess> {
+        self.validate()?;
+        if !self.vfio_devices.is_empty() {
+            require_linux("Passing through PCI devices")?;
+        }
+        if let QemuNetwork::Tap(_) = self.network {
+            require_linux("Tap networking")?;
+        }
+        let mut command = Command::new(&self.qemu_path);
+        command.args(self.args());
+        if self.clear_env {
+            command.env_clear();
+            // Keep qemu's helpers (e.g. the bridge helper) reachable
+            if let Some(path) = std::env::var_os("PATH") {
+                command.env("PATH", path);
+            }
+        }
+        command.envs(self.env.iter().map(|(key, value)| (key, value)));
+        let qmp_listener = if self.qmp
+            || self.inspect_after_shutdown()
+            || self.reboot_count.is_some()
+            || self.pvpanic_device.is_some()
+        {
+            let (listener, address) = QmpClient::listen()?;
+            command.arg("-qmp").arg(address);
+            Some(listener)
+        } else {
+            None
+        };
+        if let Some(cpus) = &self.cpu_affinity {
+            set_cpu_affinity(&mut command, cpus)?;
+        }
+        if self.core_dump {
+            enable_core_dumps(&mut command)?;
+        }
+        if let Some(nice) = self.nice {
+            set_niceness(&mut command, nice)?;
+        }
+        if let Some(seconds) = self.cpu_time_limit {
+            set_cpu_time_limit(&mut command, seconds)?;
+        }
+        let memory_cgroup = match self.memory_limit {
+            Some(bytes) => Some(limit_memory(&mut command, bytes)?),
+            None => None,
+        };
+        command.stderr(Stdio::piped());
+        if self.serial_timestamps
+            || self.phase_markers
+            || self.output_log.is_some()
+            || self.capture_stdout
+        {
+            command.stdout(Stdio::piped());
+        }
+        let output_log = match &self.output_log {
+            Some(path) => Some(Arc::new(Mutex::new(std::fs::File::create(path).map_err(
+                |err| Error::msg(format!("Unable to create {}: {}", path.display(), err)),
+            )?))),
+            None => None,
+        };
+        let mut child = command.spawn()?;
+        if self.phase_markers {
+            phase_marker("spawn");
+        }
+        let (timestamps, phase_markers) = (self.serial_timestamps, self.phase_markers);
+        let stdout_written = child
+            .stdout
+            .as_ref()
+            .map(|_| Arc::new(AtomicBool::new(false)));
+        let stdout_thread = child.stdout.take().map(|stdout| {
+            let written = stdout_written.clone().unwrap_or_default();
+            let writer = TeeWriter {
+                writer: std::io::stdout(),
+                log: output_log.clone(),
+            };
+            forward_stdout(stdout, writer, timestamps, phase_markers, written)
+        });
+        let stderr_tail = Arc::new(Mutex::new(VecDeque::new()));
+        let stderr_thread = child
+            .stderr
+            .take()
+            .map(|stderr| forward_stderr(stderr, stderr_tail.clone(), self.quiet, output_log));
+
+        let qmp = match qmp_listener {
+            Some(listener) => {
+                let alive = || matches!(child.try_wait(), Ok(None));
+                match QmpClient::accept(&listener, Duration::from_secs(10), alive) {
+                    Ok(qmp) => Some(qmp),
+                    Err(err) => {
+                        if let Err(err) = warn(format!("QMP is not available ({})", err)) {
+                            let _ = child.kill();
+                            let _ = child.wait();
+                            return Err(err);
+                        }
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        let screenshot = match &self.screenshot {
+            Some(screenshot) => Some(std::path::absolute(screenshot)?),
+            None => None,
+        };
+        Ok(QemuProcess {
+            screenshot,
+            // qemu only stays alive after shutdown to be inspected unless requested
+            quit_on_shutdown: self.inspect_after_shutdown()
+                && !self.no_shutdown
+                && !self.interactive_monitor,
+            serial_ringbuf_size: self.serial_ringbuf_size(),
+            serial_ringbuf: None,
+            reboot_count: self.reboot_count,
+            quit_on_panic: self.pvpanic_device.is_some(),
+            phase_markers: self.phase_markers,
+            stdout_written,
+            memory_cgroup,
+            cpu_time_limited: self.cpu_time_limit.is_some(),
+            child,
+            exit_status: None,
+            timed_out: false,
+            qmp,
+            run_state: None,
+            stderr_tail,
+            stderr_thread,
+            stdout_thread,
+        })
     }
+
+    /// Run `qemu --version` and return its output
+    pub fn version_string(&self) -> Result<String> {
+        let output = Command::new(&self.qemu_path).arg("--version").output()?;
+        if !output.status.success() {
+            return Err(Error::msg(format!(
+                "{} --version failed: {}",
+                self.qemu_path,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Query the version of the configured qemu executable
+    pub fn version(&self) -> Result<QemuVersion> {
+        QemuVersion::parse(&self.version_string()?)
+    }
+
+    /// Check whether the configured qemu executable provides `device`
+    pub fn supports_device(&self, device: &str) -> Result<bool> {
+        let output = Command::new(&self.qemu_path)
+            .args(["-device", "help"])
+            .output()?;
+        if !output.status.success() {
+            return Err(Error::msg(format!(
+                "{} -device help failed: {}",
+                self.qemu_path,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(lists_device(
+            &String::from_utf8_lossy(&output.stdout),
+            device,
+        ))
+    }
+}
+
+/// Whether the output of `qemu -device help` lists `device`
+///
+/// Devices are listed as `name "nvme", bus PCI, desc "Non-Volatile Memory Express"`.
+fn lists_device(help: &str, device: &str) -> bool {
+    let name = format!("name \"{}\"", device);
+    help.lines()
+        .any(|line| line.trim_start().split(',').next() == Some(name.as_str()))
+}
+
+/// Interface the ESP is attached to instead of the default interface of the machine
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EspInterface {
+    /// NVMe controller with the ESP as its namespace
+    Nvme,
+}
+
+/// Version of a qemu executable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct QemuVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub micro: u32,
+}
+
+impl QemuVersion {
+    pub fn new(major: u32, minor: u32, micro: u32) -> Self {
+        Self {
+            major,
+            minor,
+            micro,
+        }
+    }
+
+    /// Parse the output of `qemu --version`
+    ///
+    /// The first line is expected to look like `QEMU emulator version 7.2.0 (Debian 1:7.2+dfsg-5)`.
+    pub fn parse(version_output: &str) -> Result<Self> {
+        let invalid = || Error::msg(format!("Unable to parse qemu version: {}", version_output));
+        let version = version_output
+            .lines()
+            .next()
+            .and_then(|line| line.split("version ").nth(1))
+            .and_then(|rest| rest.split_whitespace().next())
+            .ok_or_else(invalid)?;
+        let mut components = version.split('.').map(|x| x.parse::<u32>());
+        let major = components
+            .next()
+            .ok_or_else(invalid)?
+            .map_err(|_| invalid())?;
+        let minor = components
+            .next()
+            .ok_or_else(invalid)?
+            .map_err(|_| invalid())?;
+        let micro = components.next().unwrap_or(Ok(0)).map_err(|_| invalid())?;
+        Ok(Self::new(major, minor, micro))
+    }
+}
+
+/// Features of a qemu version that influence the generated arguments
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QemuCapabilities {
+    /// `-action` replaces `-no-reboot`, `-no-shutdown` and `-watchdog-action` (qemu 6.0)
+    pub action: bool,
+    /// `-machine acpi=off` replaces `-no-acpi` (qemu 5.1)
+    pub machine_acpi: bool,
+    /// `-seed` makes the guest random number generator deterministic (qemu 4.0)
+    pub seed: bool,
+}
+
+impl QemuCapabilities {
+    /// Capabilities of the given qemu version
+    ///
+    /// An unknown version has no capabilities so that only the legacy syntax is used.
+    pub fn for_version(version: Option<QemuVersion>) -> Self {
+        match version {
+            Some(version) => Self {
+                action: version >= QemuVersion::new(6, 0, 0),
+                machine_acpi: version >= QemuVersion::new(5, 1, 0),
+                seed: version >= QemuVersion::new(4, 0, 0),
+            },
+            None => Self::default(),
+        }
+    }
+}
+
+impl fmt::Display for QemuVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.micro)
+    }
+}
+
+/// Clock driving the real time clock of the guest
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcClock {
+    /// Host system time. The guest clock keeps running while the VM is paused.
+    Host,
+    /// Virtual clock that stops while the VM is paused
+    Vm,
+    /// Host monotonic clock, unaffected by changes of the host time
+    Rt,
+}
+
+impl RtcClock {
+    /// Value of the `clock` option of `-rtc`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RtcClock::Host => "host",
+            RtcClock::Vm => "vm",
+            RtcClock::Rt => "rt",
+        }
+    }
+}
+
+/// Model of the USB controller USB devices are attached to
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UsbController {
+    /// USB 3 controller (qemu-xhci)
+    #[default]
+    Xhci,
+    /// USB 2 controller
+    Ehci,
+    /// USB 1.1 controller of the PIIX3 chipset (x86_64 only)
+    Uhci,
+    /// NEC USB 3 controller
+    NecXhci,
+}
+
+impl UsbController {
+    /// qemu device of the controller on the given architecture
+    pub fn device(&self, arch: Arch) -> Result<&'static str> {
+        match (self, arch) {
+            (UsbController::Xhci, _) => Ok("qemu-xhci"),
+            (UsbController::Ehci, _) => Ok("usb-ehci"),
+            (UsbController::Uhci, Arch::X86_64) => Ok("piix3-usb-uhci"),
+            (UsbController::Uhci, _) => Err(Error::msg(
+                "The UHCI controller is only available on x86_64",
+            )),
+            (UsbController::NecXhci, _) => Ok("nec-usb-xhci"),
+        }
+    }
+}
+
+/// Network configuration of the guest
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum QemuNetwork {
+    /// No network device
+    #[default]
+    None,
+    /// User-mode networking without port forwarding
+    ///
+    /// The guest can use the DHCP and DNS services of slirp but is not reachable from the host.
+    User,
+    /// Bridged networking over an existing tap interface with a virtio network card
+    ///
+    /// The interface has to be created beforehand, which usually requires `CAP_NET_ADMIN`.
+    Tap(String),
+}
+
+impl QemuNetwork {
+    /// Command line arguments configuring the network
+    ///
+    /// If `boot_rom` is not set, the network card can not be used for network boot (PXE). `mac`
+    /// is the MAC address of the network card.
+    pub fn args(&self, boot_rom: bool, mac: Option<&str>) -> Vec<String> {
+        let netdev = match self {
+            QemuNetwork::None => return vec!["-net".to_string(), "none".to_string()],
+            QemuNetwork::User => "user,id=net0".to_string(),
+            QemuNetwork::Tap(ifname) => {
+                format!("tap,id=net0,ifname={},script=no,downscript=no", ifname)
+            }
+        };
+        let device = match self {
+            QemuNetwork::Tap(_) => "virtio-net-pci,netdev=net0",
+            _ => "e1000,netdev=net0",
+        };
+        let mut device = device.to_string();
+        if let Some(mac) = mac {
+            device.push_str(&format!(",mac={}", mac));
+        }
+        if !boot_rom {
+            device.push_str(",romfile=");
+        }
+        vec!["-netdev".to_string(), netdev, "-device".to_string(), device]
+    }
+}
+
+/// Check that `mac` is a MAC address of the form `52:54:00:12:34:56`
+pub fn check_mac(mac: &str) -> Result<()> {
+    let octets = mac.split(':').collect::<Vec<_>>();
+    let valid = octets.len() == 6
+        && octets
+            .iter()
+            .all(|octet| octet.len() == 2 && u8::from_str_radix(octet, 16).is_ok());
+    if !valid {
+        return Err(Error::msg(format!(
+            "Invalid MAC address {}: expected six hex octets like 52:54:00:12:34:56",
+            mac
+        )));
+    }
+    if u8::from_str_radix(octets[0], 16)? & 1 != 0 {
+        return Err(Error::msg(format!(
+            "{} is a multicast address, which can not be assigned to a network card",
+            mac
+        )));
+    }
+    Ok(())
+}
+
+/// Random locally administered unicast MAC address
+///
+/// With a `seed` the same address is returned every time.
+pub fn random_mac(seed: Option<u64>) -> String {
+    use std::hash::{BuildHasher, Hasher};
+    let bits = match seed {
+        Some(seed) => {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            hasher.write_u64(seed);
+            hasher.finish()
+        }
+        None => {
+            // The hasher is seeded randomly, which is good enough to avoid collisions between
+            // guests
+            let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+            hasher.write_u128(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos(),
+            );
+            hasher.finish()
+        }
+    };
+    let mut octets = bits.to_le_bytes();
+    octets[0] = (octets[0] & 0xfc) | 0x02;
+    octets[..6]
+        .iter()
+        .map(|octet| format!("{:02x}", octet))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Check that `file` is a BMP image qemu accepts as boot splash
+///
+/// qemu only passes uncompressed 24-bit BMP images to the firmware.
+pub fn check_splash(file: &str) -> Result<()> {
+    let mut header = [0u8; 30];
+    std::fs::File::open(file)
+        .and_then(|mut image| std::io::Read::read_exact(&mut image, &mut header))
+        .map_err(|err| Error::msg(format!("Unable to read splash {}: {}", file, err)))?;
+    if &header[..2] != b"BM" {
+        return Err(Error::msg(format!("{} is not a BMP image", file)));
+    }
+    let bits_per_pixel = u16::from_le_bytes([header[28], header[29]]);
+    if bits_per_pixel != 24 {
+        return Err(Error::msg(format!(
+            "{} has {} bits per pixel, but only 24-bit BMP images are supported",
+            file, bits_per_pixel
+        )));
+    }
+    Ok(())
+}
+
+/// Guest memory configuration passed with `-m`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QemuMemory {
+    /// Initial memory in bytes
+    pub size: u64,
+    /// Number of slots for hotplugged memory
+    pub slots: Option<u32>,
+    /// Maximum memory in bytes including hotplugged memory
+    pub maxmem: Option<u64>,
+}
+
+impl QemuMemory {
+    /// Value of the `-m` argument
+    pub fn to_arg(&self) -> String {
+        let mut arg = format!("size={}", format_size(self.size));
+        if let Some(slots) = self.slots {
+            arg.push_str(&format!(",slots={}", slots));
+        }
+        if let Some(maxmem) = self.maxmem {
+            arg.push_str(&format!(",maxmem={}", format_size(maxmem)));
+        }
+        arg
+    }
+}
+
+/// Format `bytes` with the largest binary suffix qemu accepts that keeps the size exact
+pub(crate) fn format_size(bytes: u64) -> String {
+    for (suffix, unit) in [("G", 0x4000_0000), ("M", 0x10_0000), ("K", 0x400)] {
+        if bytes != 0 && bytes.is_multiple_of(unit) {
+            return format!("{}{}", bytes / unit, suffix);
+        }
+    }
+    format!("{}B", bytes)
+}
+
+/// I/O port range of the isa-debug-exit device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugExitConfig {
+    /// First I/O port of the device
+    pub iobase: u16,
+    /// Number of I/O ports (1, 2 or 4), which is also the width of the value written
+    pub iosize: u16,
 }
 
+impl Default for DebugExitConfig {
+    /// The port used by most test harnesses, e.g. the one of uefi-rs
+    fn default() -> Self {
+        Self {
+            iobase: 0xf4,
+            iosize: 0x04,
+        }
+    }
+}
+
+impl DebugExitConfig {
+    /// Value of the `-device` argument
+    pub fn to_arg(&self) -> String {
+        format!(
+            "isa-debug-exit,iobase={:#x},iosize={:#04x}",
+            self.iobase, self.iosize
+        )
+    }
+}
+
+/// How qemu exited when an isa-debug-exit device is attached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugExitStatus {
+    /// The guest wrote this value to the device
+    Value(i32),
+    /// qemu exited on its own with this code
+    ///
+    /// A normal shutdown of the guest is reported as `Exited(0)`. The device can not produce
+    /// even codes, since qemu exits with `(value << 1) | 1` for a written value.
+    Exited(i32),
+}
+
+impl DebugExitStatus {
+    /// Decode the exit code of qemu
+    ///
+    /// Odd exit codes are taken as written values, except for 1. qemu itself fails with exit
+    /// code 1, which can not be told apart from the guest writing 0, so it is kept as
+    /// `Exited(1)` instead of being taken as success.
+    pub fn from_exit_code(exit_code: i32) -> Self {
+        if exit_code & 1 == 1 && exit_code != 1 {
+            DebugExitStatus::Value(exit_code >> 1)
+        } else {
+            DebugExitStatus::Exited(exit_code)
+        }
+    }
+}
+
+/// Sizes of standard floppy formats in KiB
+const FLOPPY_SIZES: [u64; 8] = [160, 180, 320, 360, 720, 1200, 1440, 2880];
+
 /// Qemu drive configuration
 #[derive(Debug, Clone)]
 pub struct QemuDriveConfig {
     pub file: String,
     pub media: String,
     pub format: String,
+    pub readonly: bool,
+    /// Index of the drive on its interface
+    pub index: usize,
+    /// Id `-device` arguments refer to the drive with. See `id()` for the default.
+    pub id: Option<String>,
+    /// Interface the drive is attached to (e.g. `none` to attach it with `-device`)
+    pub interface: Option<String>,
+    /// How discard (TRIM) requests of the guest are handled. By default qemu ignores them.
+    pub discard: Option<DiscardMode>,
+}
+
+/// Handling of discard (TRIM) requests of the guest
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscardMode {
+    /// Pass discard requests to the image file (same as `unmap`)
+    On,
+    /// Ignore discard requests
+    Off,
+    /// Free the discarded ranges of the image file
+    Unmap,
+}
+
+impl DiscardMode {
+    /// Value of the `discard` option of `-drive`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiscardMode::On => "on",
+            DiscardMode::Off => "off",
+            DiscardMode::Unmap => "unmap",
+        }
+    }
 }
 
 impl QemuDriveConfig {
@@ -55,12 +1243,339 @@ impl QemuDriveConfig {
             file: file.to_string(),
             media: media.to_string(),
             format: format.to_string(),
+            readonly: false,
+            index: 0,
+            id: None,
+            interface: None,
+            discard: None,
+        }
+    }
+
+    /// Drive for a `--drive` argument of the form `<file>[,id=<name>][,if=<interface>]`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut options = spec.split(',');
+        let mut drive = Self::for_file(options.next().unwrap_or_default())?;
+        for option in options {
+            match option.split_once('=') {
+                Some(("id", id)) if !id.is_empty() => drive.id = Some(id.to_string()),
+                Some(("if", interface)) if !interface.is_empty() => {
+                    drive.interface = Some(interface.to_string())
+                }
+                _ => {
+                    return Err(Error::msg(format!(
+                        "Invalid drive option {} (expected id=<name> or if=<interface>)",
+                        option
+                    )))
+                }
+            }
+        }
+        Ok(drive)
+    }
+
+    /// Id of the drive
+    ///
+    /// Defaults to `driveN` on the default interface and the interface name followed by the
+    /// index (e.g. `floppy0`) on others.
+    pub fn id(&self) -> String {
+        self.id.clone().unwrap_or_else(|| {
+            format!(
+                "{}{}",
+                self.interface.as_deref().unwrap_or("drive"),
+                self.index
+            )
+        })
+    }
+
+    /// Floppy drive for a raw image
+    ///
+    /// A warning is emitted if the size of the image does not match a standard floppy format.
+    pub fn floppy(file: &str) -> Result<Self> {
+        let size = std::fs::metadata(file)
+            .map_err(|err| Error::msg(format!("Unable to read floppy {}: {}", file, err)))?
+            .len();
+        if !FLOPPY_SIZES.contains(&(size / 1024)) || size % 1024 != 0 {
+            warn(format!(
+                "The size of {} ({} bytes) does not match a floppy format",
+                file, size
+            ))?;
+        }
+        let mut drive = Self::new(file, "disk", "raw");
+        drive.interface = Some("floppy".to_string());
+        Ok(drive)
+    }
+
+    /// Disk drive for an image file, detecting whether it is a qcow2 or raw image
+    pub fn for_file(file: &str) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        let is_qcow2 = std::fs::File::open(file)
+            .and_then(|mut image| std::io::Read::read_exact(&mut image, &mut magic))
+            .map(|_| magic == *b"QFI\xfb");
+        match is_qcow2 {
+            Ok(true) => Ok(Self::new(file, "disk", "qcow2")),
+            Ok(false) => Ok(Self::new(file, "disk", "raw")),
+            Err(err) => Err(Error::msg(format!(
+                "Unable to read drive {}: {}",
+                file, err
+            ))),
+        }
+    }
+
+    /// Value of the `-drive` argument for this drive
+    pub fn to_arg(&self) -> String {
+        let mut arg = format!("file={},id={}", self.file, self.id());
+        match &self.interface {
+            // Drives attached with `-device` do not occupy a slot of the default interface
+            Some(interface) if interface == "none" => arg.push_str(",if=none"),
+            Some(interface) => arg.push_str(&format!(",if={},index={}", interface, self.index)),
+            None => arg.push_str(&format!(",index={}", self.index)),
+        }
+        arg.push_str(&format!(",media={},format={}", self.media, self.format));
+        if self.readonly {
+            arg.push_str(",readonly=on");
+        }
+        if let Some(discard) = self.discard {
+            arg.push_str(&format!(",discard={}", discard.as_str()));
         }
+        arg
+    }
+}
+
+/// Assign consecutive indices to drives whose first entry is the ESP
+///
+/// The ESP gets `esp_index` and the other drives keep their order on the remaining indices of
+/// their interface.
+pub fn number_drives(drives: &mut [QemuDriveConfig], esp_index: usize) {
+    let mut next_indices: Vec<(Option<String>, usize)> = Vec::new();
+    for (position, drive) in drives.iter_mut().enumerate() {
+        if position == 0 {
+            drive.index = esp_index;
+            continue;
+        }
+        let next = match next_indices
+            .iter_mut()
+            .find(|(interface, _)| *interface == drive.interface)
+        {
+            Some((_, next)) => next,
+            None => {
+                next_indices.push((drive.interface.clone(), 0));
+                &mut next_indices.last_mut().unwrap().1
+            }
+        };
+        // The ESP uses the default interface
+        if drive.interface.is_none() && *next == esp_index {
+            *next += 1;
+        }
+        drive.index = *next;
+        *next += 1;
+    }
+}
+
+/// Number of resets triggered by the guest among QMP events
+fn guest_resets(events: &[QmpEvent]) -> usize {
+    events
+        .iter()
+        .filter(|event| event.name == "RESET" && event.data["guest"] == true)
+        .count()
+}
+
+/// Number of lines of qemu's stderr kept for error analysis
+const STDERR_TAIL_LINES: usize = 32;
+
+/// Whether a line qemu wrote to stderr is a warning that can be suppressed
+fn is_qemu_warning(line: &str) -> bool {
+    line.contains("warning: ") || line.starts_with("VNC server running on")
+}
+
+/// Forward the stderr of qemu and keep its last lines
+///
+/// If `quiet` is set, warnings are not forwarded. All lines are copied to `log` if given.
+fn forward_stderr(
+    stderr: ChildStderr,
+    tail: Arc<Mutex<VecDeque<String>>>,
+    quiet: bool,
+    log: Option<Arc<Mutex<std::fs::File>>>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(stderr);
+        let mut line = Vec::new();
+        while let Ok(len) = reader.read_until(b'\n', &mut line) {
+            if len == 0 {
+                break;
+            }
+            let text = String::from_utf8_lossy(&line).trim_end().to_string();
+            if !quiet || !is_qemu_warning(&text) {
+                let _ = std::io::stderr().write_all(&line);
+            }
+            if let Some(log) = &log {
+                let _ = log.lock().unwrap().write_all(&line);
+            }
+            let mut tail = tail.lock().unwrap();
+            if tail.len() == STDERR_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(text);
+            line.clear();
+        }
+    })
+}
+
+/// Write a marker for the lifecycle point `phase` of qemu to stderr, where qemu's debug log goes
+fn phase_marker(phase: &str) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    eprintln!(
+        "=== uefi-run phase: {} [{:.3}] ===",
+        phase,
+        timestamp.as_secs_f64()
+    );
+}
+
+/// Reader calling `on_first_byte` once the first data was read
+struct FirstByteReader<R, F> {
+    reader: R,
+    on_first_byte: Option<F>,
+}
+
+impl<R: Read, F: FnOnce()> Read for FirstByteReader<R, F> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.reader.read(buffer)?;
+        if len > 0 {
+            if let Some(on_first_byte) = self.on_first_byte.take() {
+                on_first_byte();
+            }
+        }
+        Ok(len)
+    }
+}
+
+/// Writer copying everything written to it to a shared log file
+struct TeeWriter<W> {
+    writer: W,
+    log: Option<Arc<Mutex<std::fs::File>>>,
+}
+
+impl<W: Write> Write for TeeWriter<W> {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        let len = self.writer.write(buffer)?;
+        if let Some(log) = &self.log {
+            log.lock().unwrap().write_all(&buffer[..len])?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Forward the stdout of qemu to `writer`, optionally prefixing each line with the host time
+///
+/// `written` is set once qemu produced its first output. If `phase_markers` is set, a marker is
+/// written at that point.
+fn forward_stdout<W: Write + Send + 'static>(
+    stdout: ChildStdout,
+    writer: W,
+    timestamps: bool,
+    phase_markers: bool,
+    written: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let reader = FirstByteReader {
+            reader: stdout,
+            on_first_byte: Some(move || {
+                written.store(true, Ordering::SeqCst);
+                if phase_markers {
+                    phase_marker("first-serial-byte");
+                }
+            }),
+        };
+        let _ = if timestamps {
+            write_with_timestamps(reader, writer, SystemTime::now)
+        } else {
+            copy_unbuffered(reader, writer)
+        };
+    })
+}
+
+/// Copy `reader` to `writer`, flushing after every read so partial lines show up immediately
+fn copy_unbuffered<R: Read, W: Write>(mut reader: R, mut writer: W) -> std::io::Result<()> {
+    let mut buffer = [0u8; 4096];
+    loop {
+        let len = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        writer.write_all(&buffer[..len])?;
+        writer.flush()?;
+    }
+}
+
+/// Copy `reader` to `writer` and prefix each line with the time returned by `now`
+///
+/// Output is written as soon as it is read, so a line that is not terminated yet (e.g. a shell
+/// prompt) shows up immediately. Its timestamp is the time its first byte was read.
+fn write_with_timestamps<R: Read, W: Write, F: FnMut() -> SystemTime>(
+    mut reader: R,
+    mut writer: W,
+    mut now: F,
+) -> std::io::Result<()> {
+    let mut buffer = [0u8; 4096];
+    let mut line_start = true;
+    loop {
+        let len = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        for chunk in buffer[..len].split_inclusive(|&byte| byte == b'\n') {
+            if line_start {
+                let timestamp = now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                write!(writer, "[{:.3}] ", timestamp.as_secs_f64())?;
+            }
+            writer.write_all(chunk)?;
+            line_start = chunk.ends_with(b"\n");
+        }
+        writer.flush()?;
     }
 }
 
 pub struct QemuProcess {
     child: Child,
+    exit_status: Option<ExitStatus>,
+    qmp: Option<QmpClient>,
+    /// Last run state of the guest sampled over QMP
+    run_state: Option<String>,
+    /// Last lines qemu wrote to stderr
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+    stderr_thread: Option<JoinHandle<()>>,
+    /// Thread forwarding stdout if it is not inherited from uefi-run
+    stdout_thread: Option<JoinHandle<()>>,
+    /// Screenshot to be taken before qemu exits
+    screenshot: Option<PathBuf>,
+    /// Tell qemu to quit once the guest shut down
+    quit_on_shutdown: bool,
+    /// Tell qemu to quit once the guest reset more often than this
+    reboot_count: Option<usize>,
+    /// Tell qemu to quit once the guest panicked instead of leaving it paused
+    quit_on_panic: bool,
+    /// Write a phase marker once qemu exited
+    phase_markers: bool,
+    /// Set once qemu wrote to stdout, if stdout is captured
+    stdout_written: Option<Arc<AtomicBool>>,
+    /// Size of the ring buffer the serial port is captured in, if any
+    serial_ringbuf_size: Option<u64>,
+    /// Contents of the serial ring buffer read before qemu quit
+    serial_ringbuf: Option<String>,
+    /// cgroup limiting the memory of qemu, removed once the process is dropped
+    memory_cgroup: Option<MemoryCgroup>,
+    /// Whether qemu runs with a CPU time limit
+    cpu_time_limited: bool,
+    /// Set if qemu did not exit within the timeout given to `wait_for_exit`
+    timed_out: bool,
 }
 
 impl QemuProcess {
@@ -68,10 +1583,268 @@ impl QemuProcess {
     ///
     /// Returns `true` if the process exited and false if the timeout expired.
     pub fn wait(&mut self, duration: Duration) -> Option<i32> {
-        self.child
+        let exit_status = self
+            .child
             .wait_timeout(duration)
-            .expect("Failed to wait on child process")
-            .map(|exit_status| exit_status.code().unwrap_or(0))
+            .expect("Failed to wait on child process");
+        if exit_status.is_some() {
+            self.exit_status = exit_status;
+        }
+        exit_status.map(|exit_status| exit_status.code().unwrap_or(0))
+    }
+
+    /// Wait for the process to exit for `duration` and decode the exit code of an isa-debug-exit
+    /// device
+    ///
+    /// Returns `None` if the timeout expired. See `DebugExitStatus` for the ambiguous cases.
+    pub fn wait_debug_exit(&mut self, duration: Duration) -> Option<DebugExitStatus> {
+        self.wait(duration).map(DebugExitStatus::from_exit_code)
+    }
+
+    /// Wait for the process to exit, polling every `poll_interval`.
+    ///
+    /// If `terminating` is set while waiting or qemu did not exit within `timeout`, qemu is given
+    /// one second to exit on its own before it is killed. Returns the exit code of qemu.
+    pub fn wait_for_exit(
+        &mut self,
+        poll_interval: Duration,
+        timeout: Option<Duration>,
+        terminating: &AtomicBool,
+    ) -> Result<i32> {
+        // Wait for qemu to exit or signal. A failed screenshot is only reported once qemu exited.
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut exit_code;
+        let mut screenshot = Ok(());
+        loop {
+            self.sample_run_state();
+            if self.quit_on_shutdown && self.run_state().as_deref() == Some("shutdown") {
+                screenshot = self.quit();
+                self.quit_on_shutdown = false;
+            }
+            if let (Some(reboot_count), Some(qmp)) = (self.reboot_count, &self.qmp) {
+                if guest_resets(&qmp.events()) > reboot_count {
+                    screenshot = self.quit();
+                    self.reboot_count = None;
+                }
+            }
+            if self.quit_on_panic && self.run_state().as_deref() == Some("guest-panicked") {
+                screenshot = self.quit();
+                self.quit_on_panic = false;
+            }
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            exit_code = self
+                .wait(remaining.map_or(poll_interval, |remaining| remaining.min(poll_interval)));
+            if exit_code.is_some() || terminating.load(Ordering::SeqCst) {
+                break;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                self.timed_out = true;
+                break;
+            }
+        }
+
+        // The above loop may have been broken by a signal or the timeout
+        if exit_code.is_none() {
+            screenshot = screenshot.and(self.take_screenshot());
+            self.capture_serial_ringbuf();
+            // In this case we wait for qemu to exit for one second
+            exit_code = self.wait(Duration::from_secs(1));
+        }
+
+        // Qemu may still be running
+        if exit_code.is_none() {
+            // In this case we need to kill it
+            self.kill().or_else(|e| match e.kind() {
+                // Not running anymore
+                std::io::ErrorKind::InvalidInput => Ok(()),
+                _ => Err(e),
+            })?;
+            exit_code = self.wait(Duration::from_secs(1));
+        }
+
+        let exit_code =
+            exit_code.ok_or_else(|| Error::msg("qemu should have exited by now but did not"))?;
+        if self.phase_markers {
+            // Place the marker after the remaining debug log
+            if let Some(thread) = self.stderr_thread.take() {
+                let _ = thread.join();
+            }
+            phase_marker("exit");
+        }
+        // Make sure all output of qemu is written before returning
+        if let Some(thread) = self.stdout_thread.take() {
+            let _ = thread.join();
+        }
+        screenshot.map(|_| exit_code)
+    }
+
+    /// Whether qemu was stopped because it did not exit within the timeout
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// Take the requested screenshot, read the serial ring buffer and tell qemu to quit
+    fn quit(&mut self) -> Result<()> {
+        let screenshot = self.take_screenshot();
+        self.capture_serial_ringbuf();
+        if let Some(qmp) = &mut self.qmp {
+            let _ = qmp.execute("quit", None);
+        }
+        screenshot
+    }
+
+    /// Remember the contents of the serial ring buffer, so it can be read after qemu exited
+    fn capture_serial_ringbuf(&mut self) {
+        if self.serial_ringbuf_size.is_some() && self.exit_status.is_none() {
+            if let Ok(contents) = self.read_serial_ringbuf() {
+                self.serial_ringbuf = Some(contents);
+            }
+        }
+    }
+
+    /// Read the serial output captured in the ring buffer
+    ///
+    /// While qemu runs, this reads over QMP and the returned output is removed from the buffer.
+    /// Once qemu exited, the output read right before it quit is returned.
+    pub fn read_serial_ringbuf(&mut self) -> Result<String> {
+        let size = self
+            .serial_ringbuf_size
+            .ok_or_else(|| Error::msg("The serial port is not captured in a ring buffer"))?;
+        if self.exit_status.is_some() {
+            return self.serial_ringbuf.clone().ok_or_else(|| {
+                Error::msg("The serial ring buffer was not read before qemu exited")
+            });
+        }
+        let qmp = self
+            .qmp
+            .as_mut()
+            .ok_or_else(|| Error::msg("QMP is not available"))?;
+        let contents = qmp.execute(
+            "ringbuf-read",
+            Some(serde_json::json!({ "device": "serial0", "size": size, "format": "utf8" })),
+        )?;
+        contents
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| Error::msg("Invalid ringbuf-read response"))
+    }
+
+    /// Whether qemu wrote any output (e.g. of the guest's serial console)
+    ///
+    /// This is only known if stdout is captured or the serial port is connected to a ring buffer.
+    pub fn wrote_output(&self) -> Option<bool> {
+        let ringbuf = self
+            .serial_ringbuf_size
+            .map(|_| self.serial_ringbuf.as_ref().is_some_and(|s| !s.is_empty()));
+        let stdout = self
+            .stdout_written
+            .as_ref()
+            .map(|written| written.load(Ordering::SeqCst));
+        match (ringbuf, stdout) {
+            (None, None) => None,
+            (ringbuf, stdout) => Some(ringbuf.unwrap_or(false) || stdout.unwrap_or(false)),
+        }
+    }
+
+    /// QMP connection to qemu, if QMP is enabled
+    pub fn qmp(&mut self) -> Option<&mut QmpClient> {
+        self.qmp.as_mut()
+    }
+
+    /// Take the requested screenshot over QMP, if it was not taken yet
+    fn take_screenshot(&mut self) -> Result<()> {
+        let screenshot = match self.screenshot.take() {
+            Some(screenshot) => screenshot,
+            None => return Ok(()),
+        };
+        let result = match &mut self.qmp {
+            Some(qmp) => qmp
+                .execute(
+                    "screendump",
+                    Some(serde_json::json!({ "filename": screenshot })),
+                )
+                .map(|_| ()),
+            None => Err(Error::msg("QMP is not available")),
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => warn(format!("Unable to take screenshot ({})", err)),
+        }
+    }
+
+    /// Query the current run state of the guest over QMP and remember it
+    fn sample_run_state(&mut self) {
+        if let Some(qmp) = &mut self.qmp {
+            if let Ok(status) = qmp.query_status() {
+                self.run_state = Some(status);
+            }
+        }
+    }
+
+    /// Run state of the guest when qemu exited as reported by QMP
+    ///
+    /// Shutdown and panic events sent right before qemu exited take precedence over the last
+    /// sampled state, since qemu may exit before the state can be queried again.
+    pub fn run_state(&self) -> Option<String> {
+        let qmp = self.qmp.as_ref()?;
+        for event in qmp.events().iter().rev() {
+            match event.name.as_str() {
+                "GUEST_PANICKED" => return Some("guest-panicked".to_string()),
+                "SHUTDOWN" => return Some("shutdown".to_string()),
+                _ => {}
+            }
+        }
+        self.run_state.clone()
+    }
+
+    /// Last lines qemu wrote to stderr
+    ///
+    /// Once qemu exited, this waits until all of its output has been read.
+    pub fn stderr_tail(&mut self) -> Vec<String> {
+        if self.exit_status.is_some() {
+            if let Some(thread) = self.stderr_thread.take() {
+                let _ = thread.join();
+            }
+        }
+        self.stderr_tail.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Whether qemu reported that it was unable to load the firmware
+    pub fn firmware_load_failed(&mut self) -> bool {
+        self.stderr_tail().iter().any(|line| {
+            FIRMWARE_LOAD_ERRORS
+                .iter()
+                .any(|message| line.contains(message))
+        })
+    }
+
+    /// Signal that terminated qemu, if it was killed by one
+    pub fn exit_signal(&self) -> Option<i32> {
+        self.exit_status.as_ref().and_then(exit_signal)
+    }
+
+    /// Whether qemu dumped core when it was terminated by a signal
+    pub fn core_dumped(&self) -> bool {
+        self.exit_status.as_ref().is_some_and(core_dumped)
+    }
+
+    /// Whether qemu was killed for exceeding its memory limit
+    pub fn memory_limit_exceeded(&self) -> bool {
+        self.memory_cgroup
+            .as_ref()
+            .is_some_and(|cgroup| cgroup.oom_kills() > 0)
+    }
+
+    /// Whether qemu was terminated by the signal sent when it exceeds its CPU time limit
+    ///
+    /// Always false without a limit, as the signals are sent for other reasons as well.
+    pub fn cpu_time_limit_exceeded(&self) -> bool {
+        self.cpu_time_limited
+            && self
+                .exit_status
+                .as_ref()
+                .is_some_and(cpu_time_limit_exceeded)
     }
 
     /// Kill the process.
@@ -79,3 +1852,792 @@ impl QemuProcess {
         self.child.kill()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_qemu_version() {
+        #[rustfmt::skip]
+        let cases = vec![
+            ("QEMU emulator version 7.2.0 (Debian 1:7.2+dfsg-5)\nCopyright (c) 2003-2022 Fabrice Bellard and the QEMU Project developers\n", QemuVersion::new(7, 2, 0)),
+            ("QEMU emulator version 4.2.1 (Debian 1:4.2-3ubuntu6.24)\n", QemuVersion::new(4, 2, 1)),
+            ("QEMU emulator version 8.1\n", QemuVersion::new(8, 1, 0)),
+        ];
+        for (output, expected) in cases {
+            assert_eq!(QemuVersion::parse(output).unwrap(), expected);
+        }
+        assert!(QemuVersion::parse("qemu-system-x86_64: command not found").is_err());
+        assert!(QemuVersion::parse("QEMU emulator version x.y").is_err());
+    }
+
+    #[test]
+    fn test_interactive_monitor_args() {
+        let mut config = QemuConfig {
+            interactive_monitor: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            &config.args()[2..],
+            &["-no-shutdown", "-net", "none", "-monitor", "stdio"]
+        );
+        assert!(config.validate().is_ok());
+        config.serial = Some("stdio".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_action_args() {
+        let mut config = QemuConfig {
+            no_reboot: true,
+            no_shutdown: true,
+            watchdog_action: Some("poweroff".to_string()),
+            ..Default::default()
+        };
+        let legacy = config.args();
+        assert_eq!(
+            &legacy[2..],
+            &[
+                "-no-reboot",
+                "-no-shutdown",
+                "-watchdog-action",
+                "poweroff",
+                "-net",
+                "none"
+            ]
+        );
+        config.qemu_version = Some(QemuVersion::new(7, 2, 0));
+        let modern = config.args();
+        assert_eq!(
+            &modern[2..],
+            &[
+                "-action",
+                "reboot=shutdown,shutdown=pause,watchdog=poweroff",
+                "-net",
+                "none"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_no_acpi_args() {
+        let mut config = QemuConfig {
+            no_acpi: true,
+            ..Default::default()
+        };
+        assert_eq!(config.args()[2], "-no-acpi");
+        config.qemu_version = Some(QemuVersion::new(8, 2, 0));
+        assert_eq!(&config.args()[2..4], &["-machine", "acpi=off"]);
+        assert!(config.validate().is_ok());
+        config.additional_args = vec!["-machine".to_string(), "q35,acpi=on".to_string()];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_screenshot_keeps_qemu_alive() {
+        let mut config = QemuConfig {
+            screenshot: Some(PathBuf::from("screen.ppm")),
+            ..Default::default()
+        };
+        assert!(config.needs_qemu_version());
+        assert_eq!(&config.args()[2..3], &["-no-shutdown"]);
+        config.qemu_version = Some(QemuVersion::new(7, 2, 0));
+        assert_eq!(&config.args()[2..4], &["-action", "shutdown=pause"]);
+    }
+
+    #[test]
+    fn test_seed_args() {
+        let mut config = QemuConfig {
+            seed: Some(42),
+            ..Default::default()
+        };
+        assert!(config.needs_qemu_version());
+        assert!(!config.args().contains(&"-seed".to_string()));
+        config.qemu_version = Some(QemuVersion::new(4, 0, 0));
+        assert_eq!(&config.args()[2..4], &["-seed", "42"]);
+    }
+
+    #[test]
+    fn test_readonly_drive_args() {
+        let mut esp = QemuDriveConfig::new("image.fat", "disk", "raw");
+        esp.readonly = true;
+        let mut data = QemuDriveConfig::new("data.img", "disk", "raw");
+        data.index = 1;
+        let config = QemuConfig {
+            drives: vec![esp, data],
+            ..Default::default()
+        };
+        let args = config.args();
+        assert_eq!(
+            args[3],
+            "file=image.fat,id=drive0,index=0,media=disk,format=raw,readonly=on"
+        );
+        assert_eq!(
+            args[5],
+            "file=data.img,id=drive1,index=1,media=disk,format=raw"
+        );
+    }
+
+    #[test]
+    fn test_drive_indices() {
+        let mut config = QemuConfig {
+            drives: vec![
+                QemuDriveConfig::new("image.fat", "disk", "raw"),
+                QemuDriveConfig::new("a.img", "disk", "raw"),
+                QemuDriveConfig::new("b.img", "disk", "raw"),
+            ],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        number_drives(&mut config.drives, 1);
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            &config.args()[2..8],
+            &[
+                "-drive",
+                "file=image.fat,id=drive1,index=1,media=disk,format=raw",
+                "-drive",
+                "file=a.img,id=drive0,index=0,media=disk,format=raw",
+                "-drive",
+                "file=b.img,id=drive2,index=2,media=disk,format=raw"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_floppy_args() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let image = temp_dir.path().join("floppy.img");
+        std::fs::write(&image, vec![0u8; 1440 * 1024]).unwrap();
+        let image = image.to_str().unwrap();
+        let mut config = QemuConfig {
+            drives: vec![
+                QemuDriveConfig::new("image.fat", "disk", "raw"),
+                QemuDriveConfig::floppy(image).unwrap(),
+                QemuDriveConfig::new("data.img", "disk", "raw"),
+            ],
+            ..Default::default()
+        };
+        number_drives(&mut config.drives, 0);
+        assert_eq!(
+            config.args()[5],
+            format!(
+                "file={},id=floppy0,if=floppy,index=0,media=disk,format=raw",
+                image
+            )
+        );
+        assert_eq!(config.drives[2].index, 1);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_drive_id_args() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let image = temp_dir.path().join("data.img");
+        std::fs::write(&image, [0u8; 512]).unwrap();
+        let spec = format!("{},id=data,if=none", image.display());
+        let mut config = QemuConfig {
+            drives: vec![
+                QemuDriveConfig::new("image.fat", "disk", "raw"),
+                QemuDriveConfig::parse(&spec).unwrap(),
+            ],
+            additional_args: vec![
+                "-device".to_string(),
+                "nvme,drive=data,serial=1".to_string(),
+            ],
+            ..Default::default()
+        };
+        let args = config.args();
+        assert_eq!(
+            args[5],
+            format!(
+                "file={},id=data,if=none,media=disk,format=raw",
+                image.display()
+            )
+        );
+        let device = &args[args.iter().position(|arg| arg == "-device").unwrap() + 1];
+        assert!(device.contains(&format!("drive={}", config.drives[1].id())));
+        config.drives[0].discard = Some(DiscardMode::Unmap);
+        assert_eq!(
+            config.drives[0].to_arg(),
+            "file=image.fat,id=drive0,index=0,media=disk,format=raw,discard=unmap"
+        );
+        assert!(config.validate().is_ok());
+
+        config.drives[1].id = Some("drive0".to_string());
+        assert!(config.validate().is_err());
+        config.drives[1].id = None;
+        config.drives[1].index = 1;
+        assert!(config.validate().is_ok());
+        assert!(QemuDriveConfig::parse(&format!("{},cache=none", image.display())).is_err());
+    }
+
+    #[test]
+    fn test_numa_args() {
+        let config = QemuConfig {
+            numa_nodes: vec![
+                "nodeid=0,cpus=0-1,mem=512M".to_string(),
+                "nodeid=1,cpus=2-3,memdev=ram1".to_string(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            &config.args()[2..],
+            &[
+                "-object",
+                "memory-backend-ram,id=numa-mem0,size=512M",
+                "-numa",
+                "node,nodeid=0,cpus=0-1,memdev=numa-mem0",
+                "-numa",
+                "node,nodeid=1,cpus=2-3,memdev=ram1",
+                "-net",
+                "none",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_memory_args() {
+        let mut config = QemuConfig {
+            memory: Some(QemuMemory {
+                size: 2 * 0x4000_0000,
+                slots: Some(4),
+                maxmem: Some(8 * 0x4000_0000),
+            }),
+            memory_hotplug: vec![0x4000_0000, 512 * 0x10_0000],
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            &config.args()[2..12],
+            &[
+                "-m",
+                "size=2G,slots=4,maxmem=8G",
+                "-object",
+                "memory-backend-ram,id=hotplug-mem0,size=1G",
+                "-device",
+                "pc-dimm,id=dimm0,memdev=hotplug-mem0",
+                "-object",
+                "memory-backend-ram,id=hotplug-mem1,size=512M",
+                "-device",
+                "pc-dimm,id=dimm1,memdev=hotplug-mem1",
+            ]
+        );
+        config.memory_hotplug = vec![4 * 0x4000_0000, 3 * 0x4000_0000];
+        assert!(config.validate().is_err());
+        config.memory_hotplug = vec![0x4000_0000; 5];
+        assert!(config.validate().is_err());
+        config.memory = Some(QemuMemory {
+            size: 0x4000_0000,
+            slots: None,
+            maxmem: None,
+        });
+        assert!(config.validate().is_err());
+        config.memory_hotplug.clear();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.args()[2..4], ["-m", "size=1G"]);
+        config.additional_args = vec!["-m".to_string(), "1G".to_string()];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_network_args() {
+        let mut config = QemuConfig {
+            additional_args: vec!["-m".to_string(), "1G".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(&config.args()[2..], &["-net", "none", "-m", "1G"]);
+        config.network = QemuNetwork::Tap("tap0".to_string());
+        assert_eq!(
+            &config.args()[2..6],
+            &[
+                "-netdev",
+                "tap,id=net0,ifname=tap0,script=no,downscript=no",
+                "-device",
+                "virtio-net-pci,netdev=net0"
+            ]
+        );
+        config.network = QemuNetwork::User;
+        assert_eq!(
+            &config.args()[2..],
+            &[
+                "-netdev",
+                "user,id=net0",
+                "-device",
+                "e1000,netdev=net0",
+                "-m",
+                "1G"
+            ]
+        );
+        config.mac = Some("52:54:00:12:34:56".to_string());
+        assert_eq!(config.args()[5], "e1000,netdev=net0,mac=52:54:00:12:34:56");
+        config.mac = None;
+        config.esp_only = true;
+        assert_eq!(
+            &config.args()[2..8],
+            &[
+                "-boot",
+                "order=c,strict=on,menu=off",
+                "-netdev",
+                "user,id=net0",
+                "-device",
+                "e1000,netdev=net0,romfile="
+            ]
+        );
+        config.splash = Some("logo.bmp".to_string());
+        assert!(config.validate().is_err());
+        config.esp_only = false;
+        assert_eq!(&config.args()[2..4], &["-boot", "menu=on,splash=logo.bmp"]);
+    }
+
+    #[test]
+    fn test_debug_exit() {
+        let config = QemuConfig {
+            debug_exit: Some(DebugExitConfig::default()),
+            ..Default::default()
+        };
+        assert_eq!(
+            &config.args()[4..],
+            &["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"]
+        );
+        assert_eq!(
+            DebugExitStatus::from_exit_code(0),
+            DebugExitStatus::Exited(0)
+        );
+        assert_eq!(
+            DebugExitStatus::from_exit_code(1),
+            DebugExitStatus::Exited(1)
+        );
+        assert_eq!(
+            DebugExitStatus::from_exit_code(7),
+            DebugExitStatus::Value(3)
+        );
+        assert_eq!(
+            DebugExitStatus::from_exit_code(2),
+            DebugExitStatus::Exited(2)
+        );
+    }
+
+    #[test]
+    fn test_check_mac() {
+        assert!(check_mac("52:54:00:12:34:56").is_ok());
+        assert!(check_mac("52:54:00:12:34").is_err());
+        assert!(check_mac("52-54-00-12-34-56").is_err());
+        assert!(check_mac("52:54:00:12:34:5g").is_err());
+        assert!(check_mac("01:00:5e:00:00:01").is_err());
+        let mac = random_mac(None);
+        assert!(check_mac(&mac).is_ok());
+        assert_eq!(u8::from_str_radix(&mac[..2], 16).unwrap() & 0x03, 0x02);
+        assert_eq!(random_mac(Some(7)), random_mac(Some(7)));
+        assert_ne!(random_mac(Some(7)), random_mac(Some(8)));
+    }
+
+    #[test]
+    fn test_check_splash() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let splash = temp_dir.path().join("logo.bmp");
+        let mut header = [0u8; 54];
+        header[..2].copy_from_slice(b"BM");
+        header[28] = 24;
+        std::fs::write(&splash, header).unwrap();
+        assert!(check_splash(splash.to_str().unwrap()).is_ok());
+        header[28] = 8;
+        std::fs::write(&splash, header).unwrap();
+        assert!(check_splash(splash.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_pflash_args() {
+        let mut config = QemuConfig {
+            bios_path: "OVMF_CODE.fd".to_string(),
+            vars_path: Some("vars.fd".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            &config.args()[..4],
+            &[
+                "-drive",
+                "if=pflash,format=raw,unit=0,readonly=on,file=OVMF_CODE.fd",
+                "-drive",
+                "if=pflash,format=raw,unit=1,file=vars.fd"
+            ]
+        );
+        config.bios_writable = true;
+        assert_eq!(
+            config.args()[1],
+            "if=pflash,format=raw,unit=0,file=OVMF_CODE.fd"
+        );
+        assert!(config.validate().is_ok());
+        config.vars_path = None;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_loadvm_requires_qcow2() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let qcow2 = temp_dir.path().join("state.qcow2");
+        std::fs::write(&qcow2, b"QFI\xfb\x00\x00\x00\x03").unwrap();
+        let raw = temp_dir.path().join("data.img");
+        std::fs::write(&raw, [0u8; 512]).unwrap();
+
+        let mut config = QemuConfig {
+            drives: vec![QemuDriveConfig::for_file(raw.to_str().unwrap()).unwrap()],
+            loadvm: Some("booted".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(config.drives[0].format, "raw");
+        assert!(config.validate().is_err());
+        config
+            .drives
+            .push(QemuDriveConfig::for_file(qcow2.to_str().unwrap()).unwrap());
+        assert_eq!(config.drives[1].format, "qcow2");
+        config.drives[1].index = 1;
+        assert!(config.validate().is_ok());
+        assert!(QemuDriveConfig::for_file("/nonexistent/disk.img").is_err());
+    }
+
+    #[test]
+    fn test_virtio_gpu_args() {
+        let mut config = QemuConfig {
+            virtio_gpu_device: Some(Arch::X86_64.virtio_gpu_device().to_string()),
+            ..Default::default()
+        };
+        let args = config.args();
+        assert_eq!(
+            &args[args.len() - 4..],
+            &["-vga", "none", "-device", "virtio-gpu-pci"]
+        );
+        assert!(config.validate().is_ok());
+        config.vga = Some("std".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_pvpanic_args() {
+        let config = QemuConfig {
+            pvpanic_device: Some(Arch::Aarch64.pvpanic_device().to_string()),
+            ..Default::default()
+        };
+        let args = config.args();
+        assert_eq!(&args[args.len() - 2..], &["-device", "pvpanic-pci"]);
+    }
+
+    #[test]
+    fn test_kernel_args() {
+        let config = QemuConfig {
+            kernel: Some("vmlinuz".to_string()),
+            append: Some("console=ttyS0 root=\"/dev/vda 1\" quiet".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            &config.args()[2..6],
+            &[
+                "-kernel",
+                "vmlinuz",
+                "-append",
+                "console=ttyS0 root=\"/dev/vda 1\" quiet"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_direct_kernel_args() {
+        let mut config = QemuConfig {
+            direct_kernel: true,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        config.kernel = Some("vmlinuz".to_string());
+        assert!(config.validate().is_ok());
+        assert_eq!(&config.args()[..2], &["-kernel", "vmlinuz"]);
+        assert!(!config.args().contains(&"-bios".to_string()));
+        config.dtb = Some("virt.dtb".to_string());
+        assert_eq!(&config.args()[2..4], &["-dtb", "virt.dtb"]);
+    }
+
+    #[test]
+    fn test_lists_device() {
+        let help = "Storage devices:\nname \"nvme\", bus PCI, desc \"Non-Volatile Memory Express\"\nname \"nvme-ns\", bus nvme-bus\n";
+        assert!(lists_device(help, "nvme"));
+        assert!(lists_device(help, "nvme-ns"));
+        assert!(!lists_device(help, "virtio-blk-pci"));
+    }
+
+    #[test]
+    fn test_no_default_args() {
+        let mut config = QemuConfig {
+            no_default_args: true,
+            additional_args: vec!["-bios".to_string(), "custom.fd".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(config.args(), &["-bios", "custom.fd"]);
+        config.network = QemuNetwork::User;
+        assert_eq!(&config.args()[..2], &["-netdev", "user,id=net0"]);
+    }
+
+    #[test]
+    fn test_virtio_console_args() {
+        let config = QemuConfig {
+            virtio_console: Some("file,path=console.log".to_string()),
+            virtio_serial_device: Arch::Aarch64.virtio_serial_device().to_string(),
+            network: QemuNetwork::User,
+            ..Default::default()
+        };
+        assert_eq!(
+            &config.args()[6..],
+            &[
+                "-chardev",
+                "file,path=console.log,id=virtcon0",
+                "-device",
+                "virtio-serial-device",
+                "-device",
+                "virtconsole,chardev=virtcon0"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_serial_ringbuf_args() {
+        let config = QemuConfig {
+            serial: Some("ringbuf,size=4096".to_string()),
+            network: QemuNetwork::User,
+            ..Default::default()
+        };
+        assert_eq!(config.serial_ringbuf_size(), Some(4096));
+        assert_eq!(config.args()[2], "-no-shutdown");
+        assert_eq!(
+            &config.args()[7..],
+            &[
+                "-chardev",
+                "ringbuf,size=4096,id=serial0",
+                "-serial",
+                "chardev:serial0"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_serial_args() {
+        let mut config = QemuConfig {
+            serial: Some("none".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(&config.args()[4..], &["-serial", "none"]);
+        config.serial = Some("stdio".to_string());
+        assert!(config.validate().is_ok());
+        config.additional_args = vec!["-nographic".to_string()];
+        assert!(config.validate().is_err());
+        config.additional_args = vec!["-display".to_string(), "none".to_string()];
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_debugcon_args() {
+        let config = QemuConfig {
+            debugcon: Some("file,path=ovmf.log".to_string()),
+            network: QemuNetwork::User,
+            ..Default::default()
+        };
+        assert_eq!(
+            &config.args()[6..],
+            &[
+                "-chardev",
+                "file,path=ovmf.log,id=debugcon0",
+                "-device",
+                "isa-debugcon,iobase=0x402,chardev=debugcon0"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shared_dir_args() {
+        let config = QemuConfig {
+            shared_dir: Some("/tmp/share".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            &config.args()[config.args().len() - 2..],
+            &[
+                "-virtfs",
+                "local,path=/tmp/share,mount_tag=uefi-run,security_model=none,readonly=on"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vfio_args() {
+        let config = QemuConfig {
+            vfio_devices: vec!["0000:01:00.0".to_string(), "0000:02:00.1".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            &config.args()[4..],
+            &[
+                "-device",
+                "vfio-pci,host=0000:01:00.0",
+                "-device",
+                "vfio-pci,host=0000:02:00.1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_usb_args() {
+        let mut config = QemuConfig::default();
+        assert!(!config.args().iter().any(|arg| arg.contains("xhci")));
+        config.usb_devices = vec!["usb-kbd".to_string(), "usb-tablet".to_string()];
+        config.usb_controller = UsbController::NecXhci
+            .device(Arch::Aarch64)
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            &config.args()[4..],
+            &[
+                "-device",
+                "nec-usb-xhci,id=usb0",
+                "-device",
+                "usb-kbd,bus=usb0.0",
+                "-device",
+                "usb-tablet,bus=usb0.0"
+            ]
+        );
+        assert!(UsbController::Uhci.device(Arch::Aarch64).is_err());
+    }
+
+    #[test]
+    fn test_rtc_args() {
+        let mut config = QemuConfig {
+            rtc: Some("base=localtime".to_string()),
+            rtc_clock: Some(RtcClock::Vm),
+            rtc_driftfix: true,
+            ..Default::default()
+        };
+        let args = config.args();
+        assert_eq!(
+            &args[args.len() - 2..],
+            &["-rtc", "base=localtime,clock=vm,driftfix=slew"]
+        );
+        assert!(config.validate().is_ok());
+        config.rtc = Some("base=utc,clock=host".to_string());
+        assert!(config.validate().is_err());
+        config.rtc_clock = None;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_guest_resets() {
+        let event = |name: &str, guest: bool| QmpEvent {
+            name: name.to_string(),
+            data: serde_json::json!({ "guest": guest, "reason": "guest-reset" }),
+        };
+        let events = [
+            event("RESET", true),
+            event("RESET", false),
+            event("STOP", true),
+            event("RESET", true),
+        ];
+        assert_eq!(guest_resets(&events), 2);
+    }
+
+    #[test]
+    fn test_write_with_timestamps() {
+        // Reads return the partial line "boo", then the rest
+        struct Chunks(Vec<&'static [u8]>);
+        impl Read for Chunks {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.0.is_empty() {
+                    return Ok(0);
+                }
+                let chunk = self.0.remove(0);
+                buf[..chunk.len()].copy_from_slice(chunk);
+                Ok(chunk.len())
+            }
+        }
+
+        let mut seconds = 0;
+        let now = || {
+            seconds += 1;
+            UNIX_EPOCH + Duration::from_millis(1_700_000_000_000 + seconds * 250)
+        };
+        let mut output = Vec::new();
+        write_with_timestamps(
+            Chunks(vec![b"boo", b"t\nShell> \n", b"x"]),
+            &mut output,
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[1700000000.250] boot\n[1700000000.500] Shell> \n[1700000000.750] x"
+        );
+    }
+
+    #[test]
+    fn test_tee_writer() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("qemu.log");
+        let log = Arc::new(Mutex::new(std::fs::File::create(&path).unwrap()));
+        let mut writer = TeeWriter {
+            writer: Vec::new(),
+            log: Some(log.clone()),
+        };
+        writer.write_all(b"BdsDxe: loading").unwrap();
+        log.lock().unwrap().write_all(b"\nwarning").unwrap();
+        assert_eq!(writer.writer, b"BdsDxe: loading");
+        assert_eq!(std::fs::read(&path).unwrap(), b"BdsDxe: loading\nwarning");
+    }
+
+    #[test]
+    fn test_first_byte_reader() {
+        let calls = std::cell::Cell::new(0);
+        let mut reader = FirstByteReader {
+            reader: &b"boot"[..],
+            on_first_byte: Some(|| calls.set(calls.get() + 1)),
+        };
+        let mut output = Vec::new();
+        copy_unbuffered(&mut reader, &mut output).unwrap();
+        assert_eq!(output, b"boot");
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_is_qemu_warning() {
+        assert!(is_qemu_warning(
+            "qemu-system-x86_64: warning: TCG doesn't support requested feature: CPUID.01H:ECX.vmx [bit 5]"
+        ));
+        assert!(is_qemu_warning("VNC server running on 127.0.0.1:5900"));
+        assert!(!is_qemu_warning(
+            "qemu-system-x86_64: could not load PC BIOS 'OVMF.fd'"
+        ));
+    }
+
+    #[test]
+    fn test_qemu_version_ordering() {
+        assert!(QemuVersion::new(6, 0, 0) > QemuVersion::new(5, 2, 9));
+        assert!(QemuVersion::new(7, 2, 1) > QemuVersion::new(7, 2, 0));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_wait_for_exit_timeout() {
+        let config = QemuConfig {
+            qemu_path: "sleep".to_string(),
+            no_default_args: true,
+            additional_args: vec!["10".to_string()],
+            ..Default::default()
+        };
+        let mut process = config.run().unwrap();
+        let start = Instant::now();
+        let terminating = AtomicBool::new(false);
+        process
+            .wait_for_exit(
+                Duration::from_millis(50),
+                Some(Duration::from_millis(200)),
+                &terminating,
+            )
+            .unwrap();
+        assert!(process.timed_out());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,177 @@
+use super::*;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Time to wait for a reply to a QMP command
+const QMP_COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Asynchronous event sent by qemu over QMP
+#[derive(Debug, Clone, PartialEq)]
+pub struct QmpEvent {
+    /// Name of the event (e.g. `SHUTDOWN`)
+    pub name: String,
+    /// Event specific data
+    pub data: Value,
+}
+
+/// Client for the QEMU Machine Protocol
+///
+/// Qemu connects to a listener opened by uefi-run, so no port or socket path has to be agreed on
+/// beforehand. Messages are read by a background thread which collects events and forwards
+/// command replies.
+pub struct QmpClient {
+    stream: TcpStream,
+    replies: Receiver<Result<Value>>,
+    events: Arc<Mutex<Vec<QmpEvent>>>,
+}
+
+impl QmpClient {
+    /// Open a listener for qemu to connect to
+    ///
+    /// Returns the listener and the value for qemu's `-qmp` argument.
+    pub fn listen() -> Result<(TcpListener, String)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        Ok((listener, format!("tcp:{}", address)))
+    }
+
+    /// Accept the connection of qemu and negotiate capabilities
+    ///
+    /// `alive` is polled while waiting and aborts the wait if it returns `false`.
+    pub fn accept<F: FnMut() -> bool>(
+        listener: &TcpListener,
+        timeout: Duration,
+        mut alive: F,
+    ) -> Result<Self> {
+        listener.set_nonblocking(true)?;
+        let start = Instant::now();
+        let stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    if !alive() || start.elapsed() > timeout {
+                        return Err(Error::msg("qemu did not connect to the QMP socket"));
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
+        stream.set_nonblocking(false)?;
+
+        let (reply_sender, replies) = mpsc::channel();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        {
+            let reader = BufReader::new(stream.try_clone()?);
+            let events = events.clone();
+            std::thread::spawn(move || {
+                for line in reader.lines() {
+                    let message: Value = match line.map(|line| serde_json::from_str(&line)) {
+                        Ok(Ok(message)) => message,
+                        _ => break,
+                    };
+                    if let Some(name) = message["event"].as_str() {
+                        events.lock().unwrap().push(QmpEvent {
+                            name: name.to_string(),
+                            data: message["data"].clone(),
+                        });
+                    } else if let Some(reply) = message.get("return") {
+                        let _ = reply_sender.send(Ok(reply.clone()));
+                    } else if let Some(error) = message.get("error") {
+                        let _ = reply_sender.send(Err(Error::msg(format!(
+                            "QMP error: {}",
+                            error["desc"].as_str().unwrap_or("unknown")
+                        ))));
+                    }
+                    // The greeting is ignored
+                }
+            });
+        }
+
+        let mut client = Self {
+            stream,
+            replies,
+            events,
+        };
+        client.execute("qmp_capabilities", None)?;
+        Ok(client)
+    }
+
+    /// Execute a QMP command and return its reply
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        let mut message = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            message["arguments"] = arguments;
+        }
+        writeln!(self.stream, "{}", message)?;
+        self.replies
+            .recv_timeout(QMP_COMMAND_TIMEOUT)
+            .map_err(|_| Error::msg(format!("No reply to QMP command {}", command)))?
+    }
+
+    /// Query the run state of the guest (e.g. `running`, `paused`, `guest-panicked`)
+    pub fn query_status(&mut self) -> Result<String> {
+        let status = self.execute("query-status", None)?;
+        status["status"]
+            .as_str()
+            .map(|status| status.to_string())
+            .ok_or_else(|| Error::msg("Invalid reply to query-status"))
+    }
+
+    /// Events received so far
+    pub fn events(&self) -> Vec<QmpEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pretend to be qemu: connect to the listener and answer commands
+    fn fake_qemu(address: String) {
+        let stream = TcpStream::connect(address.trim_start_matches("tcp:")).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        writeln!(
+            writer,
+            r#"{{"QMP": {{"version": {{}}, "capabilities": []}}}}"#
+        )
+        .unwrap();
+        for line in BufReader::new(stream).lines() {
+            let command: Value = serde_json::from_str(&line.unwrap()).unwrap();
+            match command["execute"].as_str().unwrap() {
+                "qmp_capabilities" => writeln!(writer, r#"{{"return": {{}}}}"#).unwrap(),
+                "query-status" => {
+                    writeln!(writer, r#"{{"event": "RESET", "data": {{"guest": true}}}}"#).unwrap();
+                    writeln!(
+                        writer,
+                        r#"{{"return": {{"status": "running", "running": true}}}}"#
+                    )
+                    .unwrap();
+                }
+                _ => writeln!(writer, r#"{{"error": {{"desc": "unknown command"}}}}"#).unwrap(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_qmp_client() {
+        let (listener, address) = QmpClient::listen().unwrap();
+        assert!(address.starts_with("tcp:127.0.0.1:"));
+        std::thread::spawn(move || fake_qemu(address));
+        let mut client = QmpClient::accept(&listener, Duration::from_secs(5), || true).unwrap();
+        assert_eq!(client.query_status().unwrap(), "running");
+        assert!(client.execute("foo", None).is_err());
+        assert_eq!(
+            client.events(),
+            vec![QmpEvent {
+                name: "RESET".to_string(),
+                data: json!({ "guest": true }),
+            }]
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Structured results written by the guest to a virtio-serial port
+use super::*;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Name of the virtio-serial port the guest writes its results to
+pub const RESULTS_PORT_NAME: &str = "org.uefi-run.results";
+
+/// Unix socket qemu connects the results port to, read in the background
+pub struct ResultsChannel {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<std::io::Result<Vec<u8>>>>,
+}
+
+impl ResultsChannel {
+    /// Listen on a Unix socket at `path` and read everything written to it by the first client
+    #[cfg(unix)]
+    pub fn listen(path: &Path) -> Result<Self> {
+        let listener = std::os::unix::net::UnixListener::bind(path).map_err(|err| {
+            Error::msg(format!(
+                "Unable to create results socket {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        listener.set_nonblocking(true)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                // qemu connects right after it started, but it may fail before
+                let mut stream = loop {
+                    match listener.accept() {
+                        Ok((stream, _)) => break stream,
+                        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                            if stop.load(Ordering::SeqCst) {
+                                return Ok(Vec::new());
+                            }
+                            std::thread::sleep(Duration::from_millis(10));
+                        }
+                        Err(err) => return Err(err),
+                    }
+                };
+                stream.set_nonblocking(false)?;
+                let mut contents = Vec::new();
+                stream.read_to_end(&mut contents)?;
+                Ok(contents)
+            })
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Listening on Unix sockets is not supported on this platform
+    #[cfg(not(unix))]
+    pub fn listen(_path: &Path) -> Result<Self> {
+        Err(Error::msg("A results channel requires Unix domain sockets"))
+    }
+
+    /// Value of the `-chardev` backend connecting to the socket
+    pub fn chardev_backend(&self) -> String {
+        format!("socket,path={}", self.path.display())
+    }
+
+    /// Wait until qemu closed the connection and parse the results
+    ///
+    /// Has to be called after qemu exited.
+    pub fn finish(mut self) -> Result<Vec<serde_json::Value>> {
+        self.stop.store(true, Ordering::SeqCst);
+        let contents = match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| Error::msg("Results reader panicked"))?
+                .map_err(|err| Error::msg(format!("Unable to read results: {}", err)))?,
+            None => Vec::new(),
+        };
+        parse_results(&contents)
+    }
+}
+
+impl Drop for ResultsChannel {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Parse the JSON values the guest wrote, one after another
+///
+/// Values may be separated by whitespace, e.g. one per line.
+pub fn parse_results(contents: &[u8]) -> Result<Vec<serde_json::Value>> {
+    serde_json::Deserializer::from_slice(contents)
+        .into_iter()
+        .collect::<std::result::Result<_, _>>()
+        .map_err(|err| Error::msg(format!("Invalid results written by the guest: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_results() {
+        let results = parse_results(b"{\"test\": \"boot\", \"passed\": true}\r\n[1, 2]\n").unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["passed"], true);
+        assert!(parse_results(b"").unwrap().is_empty());
+        assert!(parse_results(b"{\"test\": ").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_results_channel() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("results.sock");
+        let channel = ResultsChannel::listen(&path).unwrap();
+        assert_eq!(
+            channel.chardev_backend(),
+            format!("socket,path={}", path.display())
+        );
+        let mut stream = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        std::io::Write::write_all(&mut stream, b"{\"passed\": 3}").unwrap();
+        drop(stream);
+        assert_eq!(
+            channel.finish().unwrap(),
+            [serde_json::json!({"passed": 3})]
+        );
+        assert!(!path.exists());
+    }
+}
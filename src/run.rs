@@ -0,0 +1,1277 @@
+use super::*;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How the EFI application is started by the firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BootMode {
+    /// Start the application from the EFI shell using `startup.nsh`
+    #[default]
+    Shell,
+    /// Install the application at the removable media boot path
+    Bootloader,
+    /// Install the application at the removable media boot path and as `run.efi` with a
+    /// `startup.nsh` so that it is found by either path
+    Both,
+}
+
+/// Exit code reported when qemu exceeded its CPU time limit (`128 + SIGXCPU` on Linux)
+pub const CPU_TIME_LIMIT_EXIT_CODE: i32 = 152;
+
+/// Exit code reported when qemu was killed for exceeding its memory limit (`128 + SIGKILL`)
+pub const MEMORY_LIMIT_EXIT_CODE: i32 = 137;
+
+/// Exit code reported when qemu did not exit within the timeout, like coreutils' `timeout`
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// Default exit code reported when the guest signaled a panic over pvpanic
+pub const GUEST_PANIC_EXIT_CODE: i32 = 126;
+
+/// Placeholder in additional qemu arguments that is replaced with the path of the image
+pub const ESP_PLACEHOLDER: &str = "{esp}";
+
+/// qemu exiting with an error before this time most likely failed itself instead of the guest
+const QEMU_FAILURE_TIME: Duration = Duration::from_secs(1);
+
+/// Options for building an image around an EFI application
+#[derive(Debug, Clone)]
+pub struct ImageOptions {
+    /// EFI executable
+    pub efi_exe: PathBuf,
+    /// Architecture of the EFI executable
+    pub arch: Arch,
+    /// How the application is started
+    pub boot_mode: BootMode,
+    /// Options for creating the image
+    pub image: EfiImageOptions,
+    /// Additional `(outer, inner, attributes)` files to be added to the image
+    pub add_files: Vec<(PathBuf, PathBuf, FileAttributes)>,
+    /// Tar or zip archives `(archive, destination)` extracted into the image
+    pub archives: Vec<(PathBuf, PathBuf)>,
+    /// Host directories `(outer, inner)` copied into the image recursively
+    pub dirs: Vec<(PathBuf, PathBuf)>,
+    /// Drivers loaded from the EFI shell before the application is started, in order
+    pub drivers: Vec<PathBuf>,
+    /// Directory in the image the drivers are copied to
+    pub driver_dir: PathBuf,
+    /// Write a JSON manifest of all files in the image to this path
+    pub manifest: Option<PathBuf>,
+    /// Re-open the image after building it and check that all files read back correctly
+    pub verify: bool,
+    /// Print diagnostics like the final `startup.nsh` to stderr
+    pub verbose: bool,
+    /// Write a build stamp (`BUILD_INFO_PATH`) to the root of the image
+    pub stamp: bool,
+    /// Do not replace a `startup.nsh` that already exists in the image
+    pub keep_existing_startup: bool,
+    /// Contents of `startup.nsh` instead of the default script running `run.efi`
+    pub startup_nsh: Option<Vec<u8>>,
+    /// Do not write a `startup.nsh`
+    pub no_startup_nsh: bool,
+    /// Path of the image. If it exists, the files are added to the existing image.
+    ///
+    /// If not provided, the image is created in the temporary directory and it is up to the
+    /// caller to remove it.
+    pub path: Option<PathBuf>,
+    /// Directory temporary files are created in instead of the default temporary directory
+    pub temp_dir: Option<PathBuf>,
+    /// Also build a UEFI-bootable ISO embedding the image at this path
+    pub iso: Option<PathBuf>,
+}
+
+impl ImageOptions {
+    /// Default options for building an image around the given EFI executable
+    pub fn new<P: Into<PathBuf>>(efi_exe: P) -> Self {
+        Self {
+            efi_exe: efi_exe.into(),
+            arch: Arch::default(),
+            boot_mode: BootMode::default(),
+            image: EfiImageOptions {
+                size: 10 * 0x10_0000,
+                ..Default::default()
+            },
+            add_files: Vec::new(),
+            archives: Vec::new(),
+            dirs: Vec::new(),
+            drivers: Vec::new(),
+            driver_dir: PathBuf::from(DEFAULT_DRIVER_DIR),
+            manifest: None,
+            verify: false,
+            verbose: false,
+            stamp: false,
+            keep_existing_startup: false,
+            startup_nsh: None,
+            no_startup_nsh: false,
+            path: None,
+            temp_dir: None,
+            iso: None,
+        }
+    }
+}
+
+/// Blank disk created for a run and attached after the other drives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataDisk {
+    /// Size in bytes
+    pub size: u64,
+    /// Volume label. If given, the disk is formatted with FAT.
+    pub label: Option<String>,
+}
+
+impl DataDisk {
+    /// Create the disk at `path`
+    pub fn create(&self, path: &Path) -> Result<()> {
+        match &self.label {
+            Some(label) => {
+                EfiImage::with_options(
+                    path,
+                    &EfiImageOptions {
+                        size: self.size,
+                        volume_label: Some(label.clone()),
+                        ..Default::default()
+                    },
+                )?;
+            }
+            None => std::fs::File::create(path)?.set_len(self.size)?,
+        }
+        Ok(())
+    }
+}
+
+/// Create `disks` as `data<N>.img` in `dir` and attach them to `qemu_config` after its drives
+///
+/// The drives are renumbered with the ESP at `esp_index`. Discard requests are handled as given
+/// by `discard`.
+pub fn attach_data_disks(
+    qemu_config: &mut QemuConfig,
+    disks: &[DataDisk],
+    dir: &Path,
+    esp_index: usize,
+    discard: Option<DiscardMode>,
+) -> Result<()> {
+    for (index, disk) in disks.iter().enumerate() {
+        let path = dir.join(format!("data{}.img", index));
+        disk.create(&path)?;
+        let path = path
+            .to_str()
+            .ok_or_else(|| Error::msg("Invalid temporary directory path"))?;
+        let mut drive = QemuDriveConfig::new(path, "disk", "raw");
+        drive.discard = discard;
+        qemu_config.drives.push(drive);
+    }
+    number_drives(&mut qemu_config.drives, esp_index);
+    Ok(())
+}
+
+/// Create a temporary directory in `parent`, or the default temporary directory if not given
+pub fn create_temp_dir(parent: Option<&Path>) -> Result<tempfile::TempDir> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("uefi-run-");
+    Ok(match parent {
+        Some(parent) => builder.tempdir_in(parent).map_err(|err| {
+            Error::msg(format!(
+                "Unable to create a temporary directory in {}: {}",
+                parent.display(),
+                err
+            ))
+        })?,
+        None => builder.tempdir()?,
+    })
+}
+
+/// Split `temp_dir` into a guard removing it and its path
+///
+/// If `keep` is set, there is no guard and the path is printed instead.
+pub fn keep_temp_dir(
+    temp_dir: tempfile::TempDir,
+    keep: bool,
+) -> (Option<tempfile::TempDir>, PathBuf) {
+    if keep {
+        let path = temp_dir.into_path();
+        eprintln!("Keeping temporary files in {}", path.display());
+        (None, path)
+    } else {
+        let path = temp_dir.path().to_path_buf();
+        (Some(temp_dir), path)
+    }
+}
+
+/// Check that the EFI executable at `path` is a readable regular file
+pub fn check_efi_exe(path: &Path) -> Result<()> {
+    let metadata = std::fs::metadata(path).map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => {
+            Error::msg(format!("EFI executable not found: {}", path.display()))
+        }
+        _ => Error::msg(format!(
+            "Unable to access EFI executable {}: {}",
+            path.display(),
+            err
+        )),
+    })?;
+    if !metadata.is_file() {
+        return Err(Error::msg(format!(
+            "EFI executable is not a regular file: {}",
+            path.display()
+        )));
+    }
+    std::fs::File::open(path).map_err(|err| {
+        Error::msg(format!(
+            "Unable to read EFI executable {}: {}",
+            path.display(),
+            err
+        ))
+    })?;
+    Ok(())
+}
+
+/// Build an image around an EFI application without running it
+///
+/// Returns the path of the image.
+pub fn build_image(options: &ImageOptions) -> Result<PathBuf> {
+    check_efi_exe(&options.efi_exe)?;
+    // A temporary image is removed again unless it is built successfully
+    let mut temp_image = None;
+    let (path, mut image) = match &options.path {
+        Some(path) if path.exists() => (
+            path.clone(),
+            EfiImage::open_with_options(path, &options.image)?,
+        ),
+        Some(path) => (path.clone(), EfiImage::with_options(path, &options.image)?),
+        None => {
+            let mut builder = tempfile::Builder::new();
+            builder.prefix("uefi-run-").suffix(".fat");
+            let (file, temp_path) = match &options.temp_dir {
+                Some(temp_dir) => builder.tempfile_in(temp_dir)?,
+                None => builder.tempfile()?,
+            }
+            .into_parts();
+            let image = EfiImage::from_file(file, &options.image)?;
+            let path = temp_path.to_path_buf();
+            temp_image = Some(temp_path);
+            (path, image)
+        }
+    };
+
+    // Create EFI executable
+    if options.boot_mode != BootMode::Shell {
+        // Copy the application to where the firmware expects a bootloader.
+        image.copy_host_file(&options.efi_exe, options.arch.boot_path())?;
+    }
+    if options.boot_mode != BootMode::Bootloader {
+        if (options.startup_nsh.is_some() || options.no_startup_nsh) && !options.drivers.is_empty()
+        {
+            return Err(Error::msg(
+                "Loading drivers requires the default startup.nsh",
+            ));
+        }
+
+        // Copy the drivers startup.nsh loads before the application
+        let mut drivers = Vec::new();
+        for driver in &options.drivers {
+            let inner = options.driver_dir.join(
+                driver
+                    .file_name()
+                    .ok_or_else(|| Error::msg("Invalid driver path"))?,
+            );
+            if drivers.contains(&inner) {
+                return Err(Error::msg(format!(
+                    "Multiple drivers are named {}",
+                    inner.display()
+                )));
+            }
+            image.copy_host_file(driver, &inner)?;
+            drivers.push(inner);
+        }
+
+        // Use startup.nsh to start the application from the EFI shell.
+        image.copy_host_file(&options.efi_exe, "run.efi")?;
+        let keep_startup = options.keep_existing_startup && image.file_exists("startup.nsh");
+        if !(options.no_startup_nsh || keep_startup) {
+            let contents = match &options.startup_nsh {
+                Some(contents) => contents.clone(),
+                None => startup_nsh(&drivers)?.into_bytes(),
+            };
+            image.set_file_contents("startup.nsh", contents)?;
+        }
+    } else if !options.drivers.is_empty() {
+        return Err(Error::msg("Loading drivers requires the EFI shell"));
+    }
+
+    // Extract archives before the additional files, so single files can replace their contents
+    for (archive, dest) in &options.archives {
+        extract_archive(&mut image, archive, dest)?;
+    }
+
+    // Copy directories before the additional files as well
+    for (outer, inner) in &options.dirs {
+        image.copy_host_dir(outer, inner)?;
+    }
+
+    // Create user provided additional files
+    for (outer, inner, attributes) in &options.add_files {
+        image.copy_host_file(outer, inner)?;
+        if !attributes.is_empty() {
+            image.set_file_attributes(inner, *attributes)?;
+        }
+    }
+
+    if options.stamp {
+        let timestamp = match options.image.timestamp {
+            Some(timestamp) => timestamp,
+            None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+        let efi_exe_name = options
+            .efi_exe
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let efi_exe = std::fs::read(&options.efi_exe)?;
+        image.set_file_contents(
+            BUILD_INFO_PATH,
+            build_info(&efi_exe_name, &efi_exe, timestamp),
+        )?;
+    }
+
+    // Show the script the shell runs, which additional files may have replaced
+    if options.verbose {
+        match image.read_file("startup.nsh") {
+            Ok(contents) => {
+                eprintln!("startup.nsh in {}:", path.display());
+                for line in String::from_utf8_lossy(&contents).lines() {
+                    eprintln!("  {}", line);
+                }
+            }
+            Err(_) => eprintln!("{} does not contain a startup.nsh", path.display()),
+        }
+    }
+
+    if let Some(manifest) = &options.manifest {
+        let entries = image
+            .manifest()
+            .iter()
+            .map(ManifestEntry::to_json)
+            .collect::<Vec<_>>();
+        std::fs::write(
+            manifest,
+            serde_json::to_string_pretty(&serde_json::json!({ "files": entries }))?,
+        )?;
+    }
+
+    // The image has to be closed so that everything is written before reading it back
+    let manifest = image.manifest().to_vec();
+    drop(image);
+    if options.verify {
+        verify_image(&path, &manifest)?;
+    }
+    if let Some(iso) = &options.iso {
+        build_iso(&path, iso)?;
+    }
+    if let Some(temp_path) = temp_image {
+        temp_path.keep()?;
+    }
+    Ok(path)
+}
+
+/// Options for building an image around an EFI application and running it in qemu
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// Name identifying the run in the results of `run_many`
+    pub name: String,
+    /// Options for building the image
+    ///
+    /// Without a path, `prepare_image` builds the image in the temporary directory of the run.
+    pub image: ImageOptions,
+    /// Run the image at the path of `image` as is instead of adding files to it
+    pub launch_only: bool,
+    /// Attach the image read-only
+    pub esp_readonly: bool,
+    /// Drive index of the image. The other drives are numbered around it.
+    pub esp_index: usize,
+    /// Interface the image is attached to instead of the default interface
+    pub esp_interface: Option<EspInterface>,
+    /// How discard requests of the guest are handled on all disks, including the image and the
+    /// data disks
+    pub discard: Option<DiscardMode>,
+    /// UEFI variable store template. A copy of it is attached together with the firmware code.
+    pub vars_template: Option<PathBuf>,
+    /// X.509 certificate enrolled as PK, KEK and db in the copy of the variable store
+    pub enroll_key: Option<PathBuf>,
+    /// Directory packed into a cpio archive that is passed to the kernel as initial ramdisk
+    pub initrd_dir: Option<PathBuf>,
+    /// Directory shared with the guest over virtio-9p
+    ///
+    /// A copy of it is exported and kept in sync while qemu runs.
+    pub watch_dir: Option<PathBuf>,
+    /// Keep the temporary directories instead of removing them when the run is done
+    pub keep_temp_dir: bool,
+    /// Blank disks created in the temporary directory and attached after the other drives
+    pub data_disks: Vec<DataDisk>,
+    /// Unix socket the guest's results port (`RESULTS_PORT_NAME`) is connected to
+    ///
+    /// The JSON values written to it are collected in the report.
+    pub results_channel: Option<PathBuf>,
+    /// Attach the ISO as CD-ROM instead of attaching the image as disk
+    pub boot_iso: bool,
+    /// Qemu configuration. The image is attached as the first drive.
+    pub qemu: QemuConfig,
+    /// Exit code reported when the guest panicked. Panics are only detected with a pvpanic
+    /// device.
+    pub panic_exit_code: i32,
+    /// Interval in which qemu is polled for exit and termination requests
+    pub poll_interval: Duration,
+    /// Time qemu may run before it is stopped and `TIMEOUT_EXIT_CODE` is reported
+    pub timeout: Option<Duration>,
+    /// How often qemu is started again if it was killed by a signal right after it was started
+    /// and before the guest wrote any output
+    ///
+    /// The image is reused. Exits of the guest are never retried.
+    pub relaunch_on_crash: usize,
+    /// Time to wait before qemu is started, e.g. for services qemu connects to
+    pub startup_delay: Duration,
+    /// Shell command run before qemu is started. The run is aborted if it fails.
+    ///
+    /// `UEFI_RUN_IMAGE` is set to the path of the image.
+    pub pre_launch: Option<String>,
+    /// Shell command run after qemu exited. A failure is reported as warning.
+    ///
+    /// `UEFI_RUN_IMAGE` is set to the path of the image and `UEFI_RUN_EXIT_CODE` to the exit code.
+    pub post_exit: Option<String>,
+    /// Metadata (e.g. the commit) copied to the report as is
+    pub labels: BTreeMap<String, String>,
+}
+
+impl RunOptions {
+    /// Qemu configuration with the image at `image_file_path` (or the ISO if it is booted)
+    /// attached and the machine arguments of the architecture added
+    ///
+    /// Files derived from the options, like the variable store copy, are not included.
+    /// `ESP_PLACEHOLDER` in the additional arguments is replaced with `image_file_path`. If the
+    /// qemu configuration has `no_default_args` set, nothing else is changed.
+    pub fn qemu_config(&self, image_file_path: &str) -> QemuConfig {
+        let mut qemu_config = self.qemu.clone();
+        for arg in &mut qemu_config.additional_args {
+            *arg = arg.replace(ESP_PLACEHOLDER, image_file_path);
+        }
+        if qemu_config.no_default_args {
+            return qemu_config;
+        }
+        let mut esp_drive = match &self.image.iso {
+            Some(iso) if self.boot_iso => {
+                QemuDriveConfig::new(&iso.display().to_string(), "cdrom", "raw")
+            }
+            _ => QemuDriveConfig::new(image_file_path, "disk", "raw"),
+        };
+        // qemu refuses to load a snapshot if a writable drive does not support snapshots
+        esp_drive.readonly = self.esp_readonly || qemu_config.loadvm.is_some();
+        if self.esp_interface == Some(EspInterface::Nvme) {
+            esp_drive.id = Some("esp".to_string());
+            esp_drive.interface = Some("none".to_string());
+            qemu_config.additional_args.push("-device".to_string());
+            qemu_config
+                .additional_args
+                .push("nvme,serial=deadbeef,drive=esp".to_string());
+        }
+        qemu_config.drives.insert(0, esp_drive);
+        number_drives(&mut qemu_config.drives, self.esp_index);
+        if self.discard.is_some() {
+            // CD-ROMs can not be written to, so there is nothing to discard
+            for drive in &mut qemu_config.drives {
+                if drive.media == "disk" {
+                    drive.discard = self.discard;
+                }
+            }
+        }
+        qemu_config.virtio_serial_device = self.image.arch.virtio_serial_device().to_string();
+        let mut arch_args = self.image.arch.qemu_args();
+        if qemu_config.cpu.is_some() {
+            // The configured CPU replaces the default model of the architecture
+            if let Some(index) = arch_args.iter().position(|arg| arg == "-cpu") {
+                arch_args.drain(index..index + 2);
+            }
+        }
+        qemu_config.additional_args.splice(0..0, arch_args);
+        qemu_config
+    }
+
+    /// Machine-readable representation of the effective configuration
+    ///
+    /// The image is referred to by `image_file_path`, as its final location is only known when
+    /// it is built.
+    pub fn to_json(&self, image_file_path: &str) -> serde_json::Value {
+        let qemu_config = self.qemu_config(image_file_path);
+        let image = &self.image;
+        serde_json::json!({
+            "name": self.name,
+            "efi_exe": image.efi_exe,
+            "arch": image.arch.name(),
+            "boot_mode": match image.boot_mode {
+                BootMode::Shell => "shell",
+                BootMode::Bootloader => "bootloader",
+                BootMode::Both => "both",
+            },
+            "image": {
+                "path": image.path,
+                "size": image.image.size,
+                "timestamp": image.image.timestamp,
+            },
+            "add_files": image
+                .add_files
+                .iter()
+                .map(|(outer, inner, _)| serde_json::json!({"outer": outer, "inner": inner}))
+                .collect::<Vec<_>>(),
+            "archives": image
+                .archives
+                .iter()
+                .map(|(archive, dest)| serde_json::json!({"archive": archive, "dest": dest}))
+                .collect::<Vec<_>>(),
+            "dirs": image
+                .dirs
+                .iter()
+                .map(|(outer, inner)| serde_json::json!({"outer": outer, "inner": inner}))
+                .collect::<Vec<_>>(),
+            "drivers": image.drivers,
+            "data_disks": self
+                .data_disks
+                .iter()
+                .map(|disk| serde_json::json!({"size": disk.size, "label": disk.label}))
+                .collect::<Vec<_>>(),
+            "vars_template": self.vars_template,
+            "initrd_dir": self.initrd_dir,
+            "watch_dir": self.watch_dir,
+            "labels": self.labels,
+            "qemu": {
+                "path": qemu_config.qemu_path,
+                "args": qemu_config.args(),
+            },
+        })
+    }
+
+    /// Default options for running the given EFI executable
+    pub fn new<P: Into<PathBuf>>(efi_exe: P) -> Self {
+        let image = ImageOptions::new(efi_exe);
+        Self {
+            name: image.efi_exe.display().to_string(),
+            image,
+            launch_only: false,
+            esp_readonly: false,
+            esp_index: 0,
+            esp_interface: None,
+            discard: None,
+            vars_template: None,
+            enroll_key: None,
+            initrd_dir: None,
+            watch_dir: None,
+            keep_temp_dir: false,
+            data_disks: Vec::new(),
+            results_channel: None,
+            boot_iso: false,
+            qemu: QemuConfig::default(),
+            panic_exit_code: GUEST_PANIC_EXIT_CODE,
+            poll_interval: Duration::from_millis(500),
+            timeout: None,
+            relaunch_on_crash: 0,
+            startup_delay: Duration::ZERO,
+            pre_launch: None,
+            post_exit: None,
+            labels: BTreeMap::new(),
+        }
+    }
+}
+
+/// Outcome of running an EFI application
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunReport {
+    /// Name of the run as given in its `RunOptions`
+    pub name: String,
+    /// Exit code of qemu
+    pub exit_code: i32,
+    /// Run state of the guest when qemu exited (e.g. `shutdown`, `guest-panicked`), if QMP is
+    /// enabled
+    pub run_state: Option<String>,
+    /// Host time at which qemu was started
+    ///
+    /// Together with `duration` this allows correlating timestamps printed by the guest with the
+    /// host clock.
+    pub start_time: SystemTime,
+    /// Wall-clock time qemu was running
+    pub duration: Duration,
+    /// Serial output captured in a ring buffer, if the serial port is connected to one
+    pub serial: Option<String>,
+    /// JSON values the guest wrote to the results channel, if there is one
+    pub results: Option<Vec<serde_json::Value>>,
+    /// Metadata given in the `RunOptions`
+    pub labels: BTreeMap<String, String>,
+}
+
+impl RunReport {
+    /// Machine-readable representation of the report
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "exit_code": self.exit_code,
+            "run_state": self.run_state,
+            "start_time": self
+                .start_time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            "duration": self.duration.as_secs_f64(),
+            "serial": self.serial,
+            "results": self.results,
+            "labels": self.labels,
+        })
+    }
+}
+
+/// Result of one run started by `run_many`
+#[derive(Debug)]
+pub struct RunResult {
+    /// Name of the run as given in its `RunOptions`
+    pub name: String,
+    /// Report of the run
+    pub result: Result<RunReport>,
+}
+
+/// Build an image around the EFI application and run it in qemu
+///
+/// Unless an image path is given, the image is created in a temporary directory that is removed
+/// when qemu exits. If `terminating` is set, qemu is stopped. This function does not install a
+/// signal handler, so it can be called multiple times and in parallel.
+pub fn run_uefi_app(options: &RunOptions, terminating: &AtomicBool) -> Result<RunReport> {
+    // Create temporary dir for the image file.
+    let temp_dir = create_temp_dir(options.image.temp_dir.as_deref())?;
+    let (_temp_dir, temp_path) = keep_temp_dir(temp_dir, options.keep_temp_dir);
+    let image_file_path = prepare_image(options, &temp_path)?;
+    run_image(options, &image_file_path, terminating)
+}
+
+/// Build the image for `options` in `temp_dir` unless an image path is given
+///
+/// With `launch_only` the existing image is used as is. Returns the path of the image.
+pub fn prepare_image(options: &RunOptions, temp_dir: &Path) -> Result<PathBuf> {
+    match &options.image.path {
+        Some(image_path) if options.launch_only => {
+            if !image_path.is_file() {
+                return Err(Error::msg(format!(
+                    "The image {} does not exist",
+                    image_path.display()
+                )));
+            }
+            Ok(image_path.clone())
+        }
+        Some(_) => build_image(&options.image),
+        None => {
+            let mut image = options.image.clone();
+            image.path = Some(temp_dir.join("image.fat"));
+            build_image(&image)
+        }
+    }
+}
+
+/// Run an image built with `build_image` in qemu
+///
+/// The image options of `options` are ignored. This allows running the same image with several
+/// qemu configurations.
+pub fn run_image(
+    options: &RunOptions,
+    image_file_path: &Path,
+    terminating: &AtomicBool,
+) -> Result<RunReport> {
+    // Create temporary dir for files derived from the options
+    let temp_dir = create_temp_dir(options.image.temp_dir.as_deref())?;
+    let (_temp_dir, temp_path) = keep_temp_dir(temp_dir, options.keep_temp_dir);
+    let mut qemu_config = options.qemu_config(
+        image_file_path
+            .to_str()
+            .ok_or_else(|| Error::msg("Invalid temporary directory path"))?,
+    );
+    // Crashes are only retried if qemu is known to not have written any output
+    qemu_config.capture_stdout |= options.relaunch_on_crash > 0;
+    attach_data_disks(
+        &mut qemu_config,
+        &options.data_disks,
+        &temp_path,
+        options.esp_index,
+        options.discard,
+    )?;
+    if let Some(vars_template) = &options.vars_template {
+        // Variables are written by the firmware, so each run gets its own copy
+        let mut vars = VarStore::read(vars_template)?;
+        if let Some(enroll_key) = &options.enroll_key {
+            vars.enroll_certificate(&load_certificate(enroll_key)?)?;
+        }
+        let vars_path = temp_path.join("vars.fd");
+        vars.write(&vars_path)?;
+        qemu_config.vars_path = Some(
+            vars_path
+                .to_str()
+                .ok_or_else(|| Error::msg("Invalid temporary directory path"))?
+                .to_string(),
+        );
+    } else if options.enroll_key.is_some() {
+        return Err(Error::msg("Enrolling a key requires a variable store"));
+    }
+    if let Some(initrd_dir) = &options.initrd_dir {
+        let initrd_path = temp_path.join("initrd.cpio");
+        let mut initrd = std::io::BufWriter::new(std::fs::File::create(&initrd_path)?);
+        write_cpio(initrd_dir, &mut initrd)?;
+        std::io::Write::flush(&mut initrd)?;
+        qemu_config.initrd = Some(
+            initrd_path
+                .to_str()
+                .ok_or_else(|| Error::msg("Invalid temporary directory path"))?
+                .to_string(),
+        );
+    }
+    let watcher = match &options.watch_dir {
+        Some(watch_dir) => {
+            let share_dir = temp_path.join("share");
+            qemu_config.shared_dir = Some(
+                share_dir
+                    .to_str()
+                    .ok_or_else(|| Error::msg("Invalid temporary directory path"))?
+                    .to_string(),
+            );
+            Some(DirWatcher::start(
+                watch_dir.clone(),
+                share_dir,
+                options.poll_interval,
+            )?)
+        }
+        None => None,
+    };
+
+    let mut results_channel = match &options.results_channel {
+        Some(path) => {
+            let channel = ResultsChannel::listen(path)?;
+            qemu_config.results_chardev = Some(channel.chardev_backend());
+            Some(channel)
+        }
+        None => None,
+    };
+
+    if let Some(pre_launch) = &options.pre_launch {
+        run_hook(
+            pre_launch,
+            &[("UEFI_RUN_IMAGE", image_file_path.as_os_str())],
+        )?;
+    }
+
+    // Wait before starting qemu. Termination requests are still honored.
+    let delay_end = Instant::now() + options.startup_delay;
+    while let Some(remaining) = delay_end.checked_duration_since(Instant::now()) {
+        if terminating.load(Ordering::SeqCst) {
+            return Err(Error::msg("Terminated before qemu was started"));
+        }
+        if remaining.is_zero() {
+            break;
+        }
+        std::thread::sleep(remaining.min(options.poll_interval));
+    }
+
+    // Run qemu, relaunching it if it crashed at startup
+    let mut relaunches = 0;
+    let (start_time, duration, mut qemu_process, mut exit_code) = loop {
+        let start_time = SystemTime::now();
+        let start = Instant::now();
+        let mut qemu_process = qemu_config.run()?;
+        let exit_code =
+            qemu_process.wait_for_exit(options.poll_interval, options.timeout, terminating)?;
+        let duration = start.elapsed();
+        match startup_crash_signal(&qemu_process, duration, terminating) {
+            Some(signal) if relaunches < options.relaunch_on_crash => {
+                relaunches += 1;
+                eprintln!(
+                    "qemu crashed with signal {} at startup, relaunching it ({} of {})",
+                    signal, relaunches, options.relaunch_on_crash
+                );
+                if let Some(path) = &options.results_channel {
+                    // The crashed qemu may already have closed its connection
+                    drop(results_channel.take());
+                    results_channel = Some(ResultsChannel::listen(path)?);
+                }
+            }
+            _ => break (start_time, duration, qemu_process, exit_code),
+        }
+    };
+    // `wait_for_exit` reports 0 for qemu killed by a signal, which must not look like success
+    if let Some(signal) = qemu_process.exit_signal() {
+        exit_code = 128 + signal;
+    }
+    if let Some(watcher) = watcher {
+        watcher.stop()?;
+    }
+    let results = match results_channel.map(ResultsChannel::finish) {
+        Some(Ok(results)) => Some(results),
+        Some(Err(err)) => {
+            warn(err.to_string())?;
+            None
+        }
+        None => None,
+    };
+    if qemu_process.firmware_load_failed() {
+        return Err(FirmwareNotFound {
+            bios_path: qemu_config.bios_path,
+            arch: options.image.arch,
+        }
+        .into());
+    }
+
+    // Point out errors of qemu, which could otherwise be taken for a failure of the guest
+    let qemu_failed = exit_code != 0
+        && duration < QEMU_FAILURE_TIME
+        && qemu_process.wrote_output() != Some(true)
+        && !terminating.load(Ordering::SeqCst)
+        && !qemu_process.timed_out();
+    if qemu_failed {
+        eprintln!(
+            "qemu exited with code {} after {:.2}s without any guest output, so qemu itself likely \
+             failed. Its last messages were:",
+            exit_code,
+            duration.as_secs_f64()
+        );
+        for line in qemu_process.stderr_tail() {
+            eprintln!("  {}", line);
+        }
+    }
+
+    // Report the value the guest wrote to the debug exit device instead of qemu's exit code
+    if qemu_config.debug_exit.is_some()
+        && qemu_process.exit_signal().is_none()
+        && !qemu_failed
+        && !terminating.load(Ordering::SeqCst)
+        && !qemu_process.timed_out()
+    {
+        match DebugExitStatus::from_exit_code(exit_code) {
+            DebugExitStatus::Value(value) => exit_code = value,
+            DebugExitStatus::Exited(1) => warn(
+                "qemu exited with code 1, which is either an error of qemu or the guest writing 0 \
+                 to the debug exit device, so it is reported as failure",
+            )?,
+            DebugExitStatus::Exited(_) => {}
+        }
+    }
+
+    if let Some(bytes) = qemu_config.memory_limit {
+        if qemu_process.memory_limit_exceeded() {
+            eprintln!("qemu exceeded its memory limit of {}", format_size(bytes));
+            exit_code = MEMORY_LIMIT_EXIT_CODE;
+        }
+    }
+    if let Some(seconds) = qemu_config.cpu_time_limit {
+        // uefi-run itself kills qemu with SIGKILL when it is asked to terminate and so does the
+        // kernel when qemu runs out of memory
+        if qemu_process.cpu_time_limit_exceeded()
+            && !terminating.load(Ordering::SeqCst)
+            && !qemu_process.timed_out()
+            && exit_code != MEMORY_LIMIT_EXIT_CODE
+        {
+            eprintln!("qemu exceeded its CPU time limit of {} seconds", seconds);
+            exit_code = CPU_TIME_LIMIT_EXIT_CODE;
+        }
+    }
+
+    if qemu_process.timed_out() {
+        eprintln!(
+            "qemu did not exit within {} seconds",
+            options.timeout.unwrap_or_default().as_secs_f64()
+        );
+        exit_code = TIMEOUT_EXIT_CODE;
+    }
+
+    let run_state = qemu_process.run_state();
+    if qemu_config.pvpanic_device.is_some() && run_state.as_deref() == Some("guest-panicked") {
+        eprintln!("The guest panicked");
+        exit_code = options.panic_exit_code;
+    }
+
+    // Point out whether a crash left a core dump
+    if qemu_config.core_dump
+        && exit_code != TIMEOUT_EXIT_CODE
+        && exit_code != CPU_TIME_LIMIT_EXIT_CODE
+        && exit_code != MEMORY_LIMIT_EXIT_CODE
+    {
+        if let Some(signal) = qemu_process.exit_signal() {
+            if qemu_process.core_dumped() {
+                eprintln!(
+                    "qemu crashed with signal {} and dumped core ({})",
+                    signal,
+                    core_dump_location()
+                );
+            } else {
+                eprintln!("qemu crashed with signal {} (no core dump written)", signal);
+            }
+        }
+    }
+    if let Some(post_exit) = &options.post_exit {
+        let exit_code = exit_code.to_string();
+        let env = [
+            ("UEFI_RUN_IMAGE", image_file_path.as_os_str()),
+            ("UEFI_RUN_EXIT_CODE", exit_code.as_ref()),
+        ];
+        if let Err(err) = run_hook(post_exit, &env) {
+            warn(err.to_string())?;
+        }
+    }
+    Ok(RunReport {
+        name: options.name.clone(),
+        exit_code,
+        run_state,
+        start_time,
+        duration,
+        serial: qemu_process.read_serial_ringbuf().ok(),
+        results,
+        labels: options.labels.clone(),
+    })
+}
+
+/// Signal qemu was terminated by if it crashed before the guest wrote any output
+///
+/// qemu stopped by uefi-run or for exceeding its limits is not taken as a crash. Without captured
+/// output the guest may have crashed qemu, so that is not taken as a startup crash either.
+fn startup_crash_signal(
+    qemu_process: &QemuProcess,
+    duration: Duration,
+    terminating: &AtomicBool,
+) -> Option<i32> {
+    if duration >= QEMU_FAILURE_TIME
+        || qemu_process.wrote_output() != Some(false)
+        || terminating.load(Ordering::SeqCst)
+        || qemu_process.timed_out()
+        || qemu_process.cpu_time_limit_exceeded()
+        || qemu_process.memory_limit_exceeded()
+    {
+        return None;
+    }
+    qemu_process.exit_signal()
+}
+
+/// Run a shell command with additional environment variables and fail if it does not succeed
+fn run_hook(command_line: &str, env: &[(&str, &std::ffi::OsStr)]) -> Result<()> {
+    let status = shell_command(command_line)
+        .envs(env.iter().copied())
+        .status()
+        .map_err(|err| Error::msg(format!("Unable to run {}: {}", command_line, err)))?;
+    if !status.success() {
+        return Err(Error::msg(format!("{} failed ({})", command_line, status)));
+    }
+    Ok(())
+}
+
+/// Run several configurations in parallel
+///
+/// At most `max_parallel` instances of qemu run at the same time. The results are returned in the
+/// order of `configs`.
+pub fn run_many(
+    configs: Vec<RunOptions>,
+    max_parallel: usize,
+    terminating: &AtomicBool,
+) -> Vec<RunResult> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(
+        configs
+            .iter()
+            .map(|_| None)
+            .collect::<Vec<Option<Result<RunReport>>>>(),
+    );
+    std::thread::scope(|scope| {
+        for _ in 0..max_parallel.max(1).min(configs.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let options = match configs.get(index) {
+                    Some(options) => options,
+                    None => break,
+                };
+                let result = run_uefi_app(options, terminating);
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+    configs
+        .into_iter()
+        .zip(results.into_inner().unwrap())
+        .map(|(options, result)| RunResult {
+            name: options.name,
+            result: result.expect("Every configuration is run exactly once"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_image() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let efi_exe = temp_dir.path().join("app.efi");
+        std::fs::write(&efi_exe, b"efi").unwrap();
+        let mut options = ImageOptions {
+            arch: Arch::Aarch64,
+            boot_mode: BootMode::Both,
+            drivers: vec![efi_exe.clone()],
+            manifest: Some(temp_dir.path().join("manifest.json")),
+            verify: true,
+            stamp: true,
+            path: Some(temp_dir.path().join("image.fat")),
+            ..ImageOptions::new(&efi_exe)
+        };
+        let path = build_image(&options).unwrap();
+        assert_eq!(path, temp_dir.path().join("image.fat"));
+        let fs =
+            fatfs::FileSystem::new(std::fs::File::open(&path).unwrap(), fatfs::FsOptions::new())
+                .unwrap();
+        let root_dir = fs.root_dir();
+        for file in &[
+            "EFI/Boot/BootAA64.efi",
+            "EFI/Drivers/app.efi",
+            "run.efi",
+            "startup.nsh",
+            BUILD_INFO_PATH,
+        ] {
+            assert!(root_dir.open_file(file).is_ok(), "{} is missing", file);
+        }
+
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(temp_dir.path().join("manifest.json")).unwrap())
+                .unwrap();
+        assert_eq!(manifest["files"].as_array().unwrap().len(), 5);
+        assert_eq!(manifest["files"][0]["path"], "EFI/Boot/BootAA64.efi");
+        assert_eq!(manifest["files"][0]["size"], 3);
+
+        options.manifest = None;
+        options.path = None;
+        let path = build_image(&options).unwrap();
+        assert!(path.is_file());
+
+        // A startup.nsh in an existing image is kept if requested
+        let mut image = EfiImage::open_with_options(&path, &options.image).unwrap();
+        image.set_file_contents("startup.nsh", b"custom").unwrap();
+        drop(image);
+        options.path = Some(path.clone());
+        options.keep_existing_startup = true;
+        build_image(&options).unwrap();
+        let image = EfiImage::open_with_options(&path, &options.image).unwrap();
+        assert_eq!(image.read_file("startup.nsh").unwrap(), b"custom");
+        drop(image);
+        std::fs::remove_file(&path).unwrap();
+
+        // A custom startup.nsh replaces the default one and can be left out entirely
+        options.keep_existing_startup = false;
+        options.startup_nsh = Some(b"run.efi".to_vec());
+        assert!(build_image(&options).is_err());
+        options.drivers.clear();
+        build_image(&options).unwrap();
+        let image = EfiImage::open_with_options(&path, &options.image).unwrap();
+        assert_eq!(image.read_file("startup.nsh").unwrap(), b"run.efi");
+        drop(image);
+        std::fs::remove_file(&path).unwrap();
+        options.no_startup_nsh = true;
+        build_image(&options).unwrap();
+        let image = EfiImage::open_with_options(&path, &options.image).unwrap();
+        assert!(!image.file_exists("startup.nsh"));
+        drop(image);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_build_image_temp_path() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let efi_exe = temp_dir.path().join("app.efi");
+        std::fs::write(&efi_exe, b"efi").unwrap();
+        let images_dir = temp_dir.path().join("images");
+        std::fs::create_dir(&images_dir).unwrap();
+        let mut options = ImageOptions {
+            temp_dir: Some(images_dir.clone()),
+            ..ImageOptions::new(&efi_exe)
+        };
+        let path = build_image(&options).unwrap();
+        assert_eq!(path.parent(), Some(images_dir.as_path()));
+        std::fs::remove_file(path).unwrap();
+
+        // Temporary images are removed if building them fails, whether formatting or filling them
+        options.image.size = 1024;
+        assert!(build_image(&options).is_err());
+        options.image.size = 10 * 0x10_0000;
+        options.drivers = vec![temp_dir.path().join("missing.efi")];
+        assert!(build_image(&options).is_err());
+        assert_eq!(std::fs::read_dir(&images_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_check_efi_exe() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let efi_exe = temp_dir.path().join("app.efi");
+        let err = check_efi_exe(&efi_exe).unwrap_err();
+        assert!(err.to_string().starts_with("EFI executable not found"));
+        std::fs::write(&efi_exe, b"efi").unwrap();
+        assert!(check_efi_exe(&efi_exe).is_ok());
+        assert!(check_efi_exe(temp_dir.path()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_startup_crash_signal() {
+        let terminating = AtomicBool::new(false);
+        #[rustfmt::skip]
+        let cases = [
+            ("kill -SEGV $$", None, true, Some(11)),
+            ("exit 3", None, true, None),
+            // Without a CPU time limit SIGKILL is a crash like any other signal
+            ("kill -KILL $$", None, true, Some(9)),
+            ("kill -KILL $$", Some(60), true, None),
+            // The guest may have crashed qemu once it wrote output or if that is not known
+            ("echo boot; kill -SEGV $$", None, true, None),
+            ("kill -SEGV $$", None, false, None),
+        ];
+        for (script, cpu_time_limit, capture_stdout, signal) in cases {
+            let config = QemuConfig {
+                qemu_path: "sh".to_string(),
+                no_default_args: true,
+                additional_args: vec!["-c".to_string(), script.to_string()],
+                cpu_time_limit,
+                capture_stdout,
+                ..Default::default()
+            };
+            let mut process = config.run().unwrap();
+            process
+                .wait_for_exit(Duration::from_millis(10), None, &terminating)
+                .unwrap();
+            assert_eq!(
+                startup_crash_signal(&process, Duration::ZERO, &terminating),
+                signal
+            );
+            assert_eq!(
+                startup_crash_signal(&process, QEMU_FAILURE_TIME, &terminating),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_many_keeps_order() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let efi_exe = temp_dir.path().join("app.efi");
+        std::fs::write(&efi_exe, b"efi").unwrap();
+        let configs = (0..5)
+            .map(|i| {
+                let mut options = RunOptions::new(&efi_exe);
+                options.name = format!("config-{}", i);
+                options.qemu.qemu_path = "/nonexistent/qemu".to_string();
+                options
+            })
+            .collect::<Vec<_>>();
+        let results = run_many(configs, 2, &AtomicBool::new(false));
+        assert_eq!(results.len(), 5);
+        for (i, result) in results.iter().enumerate() {
+            assert_eq!(result.name, format!("config-{}", i));
+            assert!(result.result.is_err());
+        }
+    }
+
+    #[test]
+    fn test_prepare_image_launch_only() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut options = RunOptions::new("missing.efi");
+        options.image.path = Some(temp_dir.path().join("image.fat"));
+        options.launch_only = true;
+        assert!(prepare_image(&options, temp_dir.path()).is_err());
+        std::fs::write(temp_dir.path().join("image.fat"), b"").unwrap();
+        assert_eq!(
+            prepare_image(&options, temp_dir.path()).unwrap(),
+            temp_dir.path().join("image.fat")
+        );
+    }
+
+    #[test]
+    fn test_attach_data_disks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut qemu_config = RunOptions::new("app.efi").qemu_config("image.fat");
+        let disks = [
+            DataDisk {
+                size: 0x10_0000,
+                label: None,
+            },
+            DataDisk {
+                size: 2 * 0x10_0000,
+                label: Some("scratch".to_string()),
+            },
+        ];
+        attach_data_disks(&mut qemu_config, &disks, temp_dir.path(), 0, None).unwrap();
+        let data0 = temp_dir.path().join("data0.img");
+        let data1 = temp_dir.path().join("data1.img");
+        assert_eq!(std::fs::metadata(&data0).unwrap().len(), 0x10_0000);
+        let fs = fatfs::FileSystem::new(
+            std::fs::File::open(&data1).unwrap(),
+            fatfs::FsOptions::new(),
+        )
+        .unwrap();
+        assert_eq!(fs.volume_label(), "SCRATCH");
+        let drives = qemu_config
+            .drives
+            .iter()
+            .map(|drive| (drive.file.clone(), drive.index))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            drives,
+            [
+                ("image.fat".to_string(), 0),
+                (data0.display().to_string(), 1),
+                (data1.display().to_string(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_report_json() {
+        let report = RunReport {
+            name: "app.efi".to_string(),
+            exit_code: 0,
+            run_state: None,
+            start_time: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            duration: Duration::from_millis(1500),
+            serial: None,
+            results: None,
+            labels: BTreeMap::from([("commit".to_string(), "1a2b3c".to_string())]),
+        };
+        let json = report.to_json();
+        assert_eq!(json["start_time"], 1_700_000_000.0);
+        assert_eq!(json["duration"], 1.5);
+        assert_eq!(json["labels"], serde_json::json!({"commit": "1a2b3c"}));
+    }
+
+    #[test]
+    fn test_qemu_config() {
+        let mut options = RunOptions::new("app.efi");
+        options.image.arch = Arch::Aarch64;
+        options.qemu.cpu = Some("max".to_string());
+        let qemu_config = options.qemu_config("image.fat");
+        assert_eq!(qemu_config.drives[0].file, "image.fat");
+        assert_eq!(qemu_config.additional_args, vec!["-machine", "virt"]);
+        options.qemu.additional_args = vec!["file={esp},if=none,id=esp".to_string()];
+        let qemu_config = options.qemu_config("image.fat");
+        assert_eq!(
+            qemu_config.additional_args.last().unwrap(),
+            "file=image.fat,if=none,id=esp"
+        );
+        options.qemu.additional_args.clear();
+        options.esp_interface = Some(EspInterface::Nvme);
+        let args = options.qemu_config("image.fat").args();
+        let drive = args
+            .iter()
+            .position(|arg| arg.starts_with("file=image.fat"));
+        assert_eq!(
+            args[drive.unwrap()],
+            "file=image.fat,id=esp,if=none,media=disk,format=raw"
+        );
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["-device", "nvme,serial=deadbeef,drive=esp"]));
+        let json = options.to_json("image.fat");
+        assert_eq!(json["arch"], "aarch64");
+        assert_eq!(json["qemu"]["path"], "qemu-system-x86_64");
+    }
+}
@@ -0,0 +1,143 @@
+//! Host directories shared with the guest over virtio-9p
+use super::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Mount tag of the directory shared with `--watch-dir`
+pub const SHARE_MOUNT_TAG: &str = "uefi-run";
+
+/// Mirror `src` into `dst`
+///
+/// Files are copied if they are missing in `dst` or changed since they were last copied. Entries
+/// of `dst` that do not exist in `src` anymore are removed. Returns whether anything changed.
+pub fn sync_dir(src: &Path, dst: &Path) -> Result<bool> {
+    let mut changed = false;
+    if !dst.is_dir() {
+        fs::create_dir_all(dst)?;
+        changed = true;
+    }
+    let mut names = Vec::new();
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let (src_path, dst_path) = (entry.path(), dst.join(entry.file_name()));
+        let metadata = fs::metadata(&src_path)?;
+        names.push(entry.file_name());
+        if metadata.is_dir() {
+            if dst_path.is_file() {
+                fs::remove_file(&dst_path)?;
+            }
+            changed |= sync_dir(&src_path, &dst_path)?;
+        } else if metadata.is_file() {
+            let outdated = match fs::metadata(&dst_path) {
+                Ok(existing) if existing.is_dir() => {
+                    fs::remove_dir_all(&dst_path)?;
+                    true
+                }
+                // Copies get the time they were made as modification time
+                Ok(existing) => {
+                    existing.len() != metadata.len()
+                        || existing.modified()? < metadata.modified()?
+                }
+                Err(_) => true,
+            };
+            if outdated {
+                fs::copy(&src_path, &dst_path)?;
+                changed = true;
+            }
+        }
+    }
+    for entry in fs::read_dir(dst)? {
+        let entry = entry?;
+        if !names.contains(&entry.file_name()) {
+            if entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+/// Background thread keeping a shared directory in sync with a host directory
+pub struct DirWatcher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl DirWatcher {
+    /// Copy `src` to `dst` and keep copying changes every `interval`
+    pub fn start(src: PathBuf, dst: PathBuf, interval: Duration) -> Result<Self> {
+        sync_dir(&src, &dst)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    std::thread::sleep(interval);
+                    sync_dir(&src, &dst).map_err(|err| {
+                        Error::msg(format!(
+                            "Unable to sync {} into the shared directory: {}",
+                            src.display(),
+                            err
+                        ))
+                    })?;
+                }
+                Ok(())
+            })
+        };
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Stop watching and report the error that stopped the synchronization, if any
+    pub fn stop(mut self) -> Result<()> {
+        self.stop.store(true, Ordering::SeqCst);
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| Error::msg("Directory watcher panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for DirWatcher {
+    fn drop(&mut self) {
+        // Make sure the shared directory is not written after it was removed
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_dir() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (temp_dir.path().join("src"), temp_dir.path().join("dst"));
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("app.efi"), b"v1").unwrap();
+        fs::write(src.join("sub/data"), b"data").unwrap();
+        assert!(sync_dir(&src, &dst).unwrap());
+        assert_eq!(fs::read(dst.join("sub/data")).unwrap(), b"data");
+        assert!(!sync_dir(&src, &dst).unwrap());
+
+        fs::write(src.join("app.efi"), b"v2 longer").unwrap();
+        fs::remove_dir_all(src.join("sub")).unwrap();
+        assert!(sync_dir(&src, &dst).unwrap());
+        assert_eq!(fs::read(dst.join("app.efi")).unwrap(), b"v2 longer");
+        assert!(!dst.join("sub").exists());
+    }
+}
@@ -0,0 +1,402 @@
+//! Editing of EDK2 variable stores (e.g. `OVMF_VARS.fd`)
+//!
+//! Only authenticated variable stores as used by firmware built with secure boot support are
+//! supported. Variables are appended to the store and older instances are marked as deleted, just
+//! like the firmware does it.
+use super::*;
+use std::fs;
+use std::path::Path;
+
+/// Build a GUID in its on-disk (mixed endian) representation
+const fn guid(d1: u32, d2: u16, d3: u16, d4: [u8; 8]) -> [u8; 16] {
+    let d1 = d1.to_le_bytes();
+    let d2 = d2.to_le_bytes();
+    let d3 = d3.to_le_bytes();
+    [
+        d1[0], d1[1], d1[2], d1[3], d2[0], d2[1], d3[0], d3[1], d4[0], d4[1], d4[2], d4[3], d4[4],
+        d4[5], d4[6], d4[7],
+    ]
+}
+
+/// `gEfiAuthenticatedVariableGuid`, the signature of authenticated variable stores
+const AUTHENTICATED_VARIABLE_GUID: [u8; 16] = guid(
+    0xaaf32c78,
+    0x947b,
+    0x439a,
+    [0xa1, 0x80, 0x2e, 0x14, 0x4e, 0xc3, 0x77, 0x92],
+);
+/// Vendor GUID of `PK` and `KEK`
+pub const EFI_GLOBAL_VARIABLE: [u8; 16] = guid(
+    0x8be4df61,
+    0x93ca,
+    0x11d2,
+    [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c],
+);
+/// Vendor GUID of `db` and `dbx`
+pub const EFI_IMAGE_SECURITY_DATABASE: [u8; 16] = guid(
+    0xd719b2cb,
+    0x3d3a,
+    0x4596,
+    [0xa3, 0xbc, 0xda, 0xd0, 0x0e, 0x67, 0x65, 0x6f],
+);
+/// Vendor GUID of the `SecureBootEnable` variable of OVMF
+const SECURE_BOOT_ENABLE_GUID: [u8; 16] = guid(
+    0xf0a30bc7,
+    0xaf08,
+    0x4556,
+    [0x99, 0xc4, 0x00, 0x10, 0x09, 0xc9, 0x3a, 0x44],
+);
+/// Signature type of X.509 certificates in signature lists
+const EFI_CERT_X509_GUID: [u8; 16] = guid(
+    0xa5c059a1,
+    0x94e4,
+    0x4aa7,
+    [0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72],
+);
+/// Owner of the signatures enrolled by uefi-run
+const SIGNATURE_OWNER_GUID: [u8; 16] = guid(
+    0x6ad3e8a1,
+    0x4f1b,
+    0x4c5e,
+    [0x9b, 0x6d, 0x2e, 0x37, 0x88, 0x0c, 0x51, 0xd4],
+);
+
+/// Variable attributes
+pub const EFI_VARIABLE_NON_VOLATILE: u32 = 0x01;
+pub const EFI_VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x02;
+pub const EFI_VARIABLE_RUNTIME_ACCESS: u32 = 0x04;
+pub const EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS: u32 = 0x20;
+
+/// Offset of the header length in the firmware volume header
+const FV_HEADER_LENGTH_OFFSET: usize = 48;
+/// Size of the variable store header
+const VARIABLE_STORE_HEADER_SIZE: usize = 28;
+/// Size of an authenticated variable header
+const VARIABLE_HEADER_SIZE: usize = 60;
+/// Marker at the start of every variable
+const VARIABLE_START_ID: u16 = 0x55aa;
+/// State of a valid variable
+const VAR_ADDED: u8 = 0x3f;
+/// Bit cleared in the state of a variable when it is deleted
+const VAR_DELETED: u8 = 0xfd;
+
+/// EDK2 variable store loaded into memory
+pub struct VarStore {
+    data: Vec<u8>,
+    /// Offset of the first variable
+    start: usize,
+    /// End of the variable store
+    end: usize,
+}
+
+impl VarStore {
+    /// Parse a variable store
+    pub fn new(data: Vec<u8>) -> Result<Self> {
+        let invalid = |reason: &str| Error::msg(format!("Invalid variable store: {}", reason));
+        if data.len() < FV_HEADER_LENGTH_OFFSET + 2 || &data[40..44] != b"_FVH" {
+            return Err(invalid("no firmware volume header"));
+        }
+        let header_length = usize::from(u16::from_le_bytes([
+            data[FV_HEADER_LENGTH_OFFSET],
+            data[FV_HEADER_LENGTH_OFFSET + 1],
+        ]));
+        let store = data
+            .get(header_length..header_length + VARIABLE_STORE_HEADER_SIZE)
+            .ok_or_else(|| invalid("truncated variable store header"))?;
+        if store[..16] != AUTHENTICATED_VARIABLE_GUID {
+            return Err(invalid("not an authenticated variable store"));
+        }
+        let size = u32::from_le_bytes([store[16], store[17], store[18], store[19]]) as usize;
+        let end = header_length + size;
+        if end > data.len() {
+            return Err(invalid("variable store exceeds the file"));
+        }
+        Ok(Self {
+            data,
+            start: align4(header_length + VARIABLE_STORE_HEADER_SIZE),
+            end,
+        })
+    }
+
+    /// Read a variable store from a file
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::new(fs::read(path)?)
+    }
+
+    /// Write the variable store to a file
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, &self.data)?;
+        Ok(())
+    }
+
+    /// Raw contents of the variable store
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Offsets of all variable headers followed by the offset of the free space
+    fn variable_offsets(&self) -> (Vec<usize>, usize) {
+        let mut offsets = Vec::new();
+        let mut offset = self.start;
+        while offset + VARIABLE_HEADER_SIZE <= self.end
+            && u16_at(&self.data, offset) == VARIABLE_START_ID
+        {
+            offsets.push(offset);
+            let name_size = u32_at(&self.data, offset + 36) as usize;
+            let data_size = u32_at(&self.data, offset + 40) as usize;
+            offset = align4(offset + VARIABLE_HEADER_SIZE + name_size + data_size);
+        }
+        (offsets, offset)
+    }
+
+    /// Name and vendor GUID of the variable at `offset`
+    fn variable_key(&self, offset: usize) -> (&[u8], &[u8]) {
+        let name_size = u32_at(&self.data, offset + 36) as usize;
+        let name_start = offset + VARIABLE_HEADER_SIZE;
+        (
+            &self.data[name_start..name_start + name_size],
+            &self.data[offset + 44..offset + 60],
+        )
+    }
+
+    /// Data of the valid variable with the given name and vendor GUID
+    pub fn get_variable(&self, name: &str, vendor: &[u8; 16]) -> Option<(u32, &[u8])> {
+        let name = encode_name(name);
+        let (offsets, _) = self.variable_offsets();
+        offsets.into_iter().find_map(|offset| {
+            if self.data[offset + 2] != VAR_ADDED || self.variable_key(offset) != (&name, vendor) {
+                return None;
+            }
+            let name_size = u32_at(&self.data, offset + 36) as usize;
+            let data_size = u32_at(&self.data, offset + 40) as usize;
+            let data_start = offset + VARIABLE_HEADER_SIZE + name_size;
+            Some((
+                u32_at(&self.data, offset + 4),
+                &self.data[data_start..data_start + data_size],
+            ))
+        })
+    }
+
+    /// Add a variable, replacing any previous instance
+    pub fn set_variable(
+        &mut self,
+        name: &str,
+        vendor: &[u8; 16],
+        attributes: u32,
+        data: &[u8],
+    ) -> Result<()> {
+        let name = encode_name(name);
+        let (offsets, free) = self.variable_offsets();
+        let size = VARIABLE_HEADER_SIZE + name.len() + data.len();
+        if free + size > self.end {
+            return Err(Error::msg("Variable store is full"));
+        }
+        for offset in offsets {
+            if self.data[offset + 2] == VAR_ADDED && self.variable_key(offset) == (&name, vendor) {
+                self.data[offset + 2] &= VAR_DELETED;
+            }
+        }
+
+        let mut variable = Vec::with_capacity(size);
+        variable.extend_from_slice(&VARIABLE_START_ID.to_le_bytes());
+        variable.push(VAR_ADDED);
+        variable.push(0);
+        variable.extend_from_slice(&attributes.to_le_bytes());
+        // Monotonic count, timestamp and public key index are left zero. Any later authenticated
+        // update has a newer timestamp.
+        variable.extend_from_slice(&[0; 8 + 16 + 4]);
+        variable.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        variable.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        variable.extend_from_slice(vendor);
+        variable.extend_from_slice(&name);
+        variable.extend_from_slice(data);
+        self.data[free..free + size].copy_from_slice(&variable);
+        Ok(())
+    }
+
+    /// Enroll an X.509 certificate (DER) as platform key, key exchange key and in the signature
+    /// database and enable secure boot
+    pub fn enroll_certificate(&mut self, certificate: &[u8]) -> Result<()> {
+        let attributes = EFI_VARIABLE_NON_VOLATILE
+            | EFI_VARIABLE_BOOTSERVICE_ACCESS
+            | EFI_VARIABLE_RUNTIME_ACCESS
+            | EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS;
+        let signature_list = x509_signature_list(certificate);
+        self.set_variable(
+            "db",
+            &EFI_IMAGE_SECURITY_DATABASE,
+            attributes,
+            &signature_list,
+        )?;
+        self.set_variable("KEK", &EFI_GLOBAL_VARIABLE, attributes, &signature_list)?;
+        // Enrolling the platform key ends setup mode, so it comes last
+        self.set_variable("PK", &EFI_GLOBAL_VARIABLE, attributes, &signature_list)?;
+        self.set_variable(
+            "SecureBootEnable",
+            &SECURE_BOOT_ENABLE_GUID,
+            EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS,
+            &[1],
+        )
+    }
+}
+
+/// `EFI_SIGNATURE_LIST` holding a single X.509 certificate
+fn x509_signature_list(certificate: &[u8]) -> Vec<u8> {
+    let signature_size = 16 + certificate.len();
+    let mut list = Vec::with_capacity(28 + signature_size);
+    list.extend_from_slice(&EFI_CERT_X509_GUID);
+    list.extend_from_slice(&((28 + signature_size) as u32).to_le_bytes());
+    list.extend_from_slice(&0u32.to_le_bytes());
+    list.extend_from_slice(&(signature_size as u32).to_le_bytes());
+    list.extend_from_slice(&SIGNATURE_OWNER_GUID);
+    list.extend_from_slice(certificate);
+    list
+}
+
+/// Load an X.509 certificate in DER or PEM format and return it as DER
+pub fn load_certificate<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    use base64::Engine;
+
+    let path = path.as_ref();
+    let contents = fs::read(path)?;
+    let invalid = || Error::msg(format!("{} is not an X.509 certificate", path.display()));
+    let der = match std::str::from_utf8(&contents) {
+        Ok(text) if text.contains("-----BEGIN CERTIFICATE-----") => {
+            let body = text
+                .split("-----BEGIN CERTIFICATE-----")
+                .nth(1)
+                .and_then(|rest| rest.split("-----END CERTIFICATE-----").next())
+                .ok_or_else(invalid)?;
+            let body: String = body.chars().filter(|c| !c.is_whitespace()).collect();
+            base64::engine::general_purpose::STANDARD
+                .decode(body)
+                .map_err(|_| invalid())?
+        }
+        _ => contents,
+    };
+    // A certificate is a DER sequence
+    if der.first() != Some(&0x30) {
+        return Err(invalid());
+    }
+    Ok(der)
+}
+
+/// Encode a variable name as NUL terminated UCS-2
+fn encode_name(name: &str) -> Vec<u8> {
+    name.encode_utf16()
+        .chain(std::iter::once(0))
+        .flat_map(|c| c.to_le_bytes())
+        .collect()
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty variable store laid out like `OVMF_VARS.fd`
+    fn empty_store() -> Vec<u8> {
+        let header_length = 0x48;
+        let mut data = vec![0xff; 0x4000];
+        data[..header_length].fill(0);
+        data[40..44].copy_from_slice(b"_FVH");
+        data[48..50].copy_from_slice(&(header_length as u16).to_le_bytes());
+        let store = &mut data[header_length..header_length + VARIABLE_STORE_HEADER_SIZE];
+        store[..16].copy_from_slice(&AUTHENTICATED_VARIABLE_GUID);
+        store[16..20].copy_from_slice(&0x2000u32.to_le_bytes());
+        store[20] = 0x5a;
+        store[21] = 0xfe;
+        store[22..].fill(0);
+        data
+    }
+
+    #[test]
+    fn test_set_variable() {
+        let mut store = VarStore::new(empty_store()).unwrap();
+        store
+            .set_variable("Foo", &EFI_GLOBAL_VARIABLE, 7, b"first")
+            .unwrap();
+        store
+            .set_variable("Foo", &EFI_GLOBAL_VARIABLE, 7, b"second")
+            .unwrap();
+        assert_eq!(
+            store.get_variable("Foo", &EFI_GLOBAL_VARIABLE),
+            Some((7, &b"second"[..]))
+        );
+        assert_eq!(
+            store.get_variable("Foo", &EFI_IMAGE_SECURITY_DATABASE),
+            None
+        );
+
+        // The first instance is marked as deleted and the second one follows aligned
+        let first = 0x48 + VARIABLE_STORE_HEADER_SIZE;
+        assert_eq!(store.as_bytes()[first + 2], VAR_ADDED & VAR_DELETED);
+        let second = align4(first + VARIABLE_HEADER_SIZE + 8 + 5);
+        assert_eq!(u16_at(store.as_bytes(), second), VARIABLE_START_ID);
+
+        // Reparsing finds the same variables
+        let mut store = VarStore::new(store.as_bytes().to_vec()).unwrap();
+        assert_eq!(
+            store.get_variable("Foo", &EFI_GLOBAL_VARIABLE),
+            Some((7, &b"second"[..]))
+        );
+        assert!(store
+            .set_variable("Bar", &EFI_GLOBAL_VARIABLE, 7, &[0; 0x2000])
+            .is_err());
+    }
+
+    #[test]
+    fn test_enroll_certificate() {
+        let mut store = VarStore::new(empty_store()).unwrap();
+        let certificate = [0x30, 0x03, 0x02, 0x01, 0x00];
+        store.enroll_certificate(&certificate).unwrap();
+        for (name, vendor) in [
+            ("PK", EFI_GLOBAL_VARIABLE),
+            ("KEK", EFI_GLOBAL_VARIABLE),
+            ("db", EFI_IMAGE_SECURITY_DATABASE),
+        ] {
+            let (attributes, data) = store.get_variable(name, &vendor).unwrap();
+            assert_eq!(attributes, 0x27);
+            assert_eq!(&data[..16], &EFI_CERT_X509_GUID);
+            assert_eq!(u32_at(data, 16) as usize, data.len());
+            assert_eq!(&data[44..], &certificate);
+        }
+        assert!(VarStore::new(vec![0; 0x100]).is_err());
+    }
+
+    #[test]
+    fn test_load_certificate() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let pem = temp_dir.path().join("cert.pem");
+        fs::write(
+            &pem,
+            "-----BEGIN CERTIFICATE-----\nMAMCAQA=\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+        assert_eq!(
+            load_certificate(&pem).unwrap(),
+            [0x30, 0x03, 0x02, 0x01, 0x00]
+        );
+        let der = temp_dir.path().join("cert.der");
+        fs::write(&der, [0x30, 0x00]).unwrap();
+        assert_eq!(load_certificate(&der).unwrap(), [0x30, 0x00]);
+        fs::write(&der, b"garbage").unwrap();
+        assert!(load_certificate(&der).is_err());
+    }
+}
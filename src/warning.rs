@@ -0,0 +1,27 @@
+//! Warnings that can be promoted to errors
+use super::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether warnings are treated as errors
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// Treat all warnings emitted by uefi-run as errors
+pub fn set_strict(strict: bool) {
+    STRICT.store(strict, Ordering::SeqCst);
+}
+
+/// Whether warnings are treated as errors
+pub fn is_strict() -> bool {
+    STRICT.load(Ordering::SeqCst)
+}
+
+/// Print a warning or, in strict mode, return it as an error
+pub fn warn<S: Into<String>>(message: S) -> Result<()> {
+    let message = message.into();
+    if is_strict() {
+        Err(Error::msg(message))
+    } else {
+        eprintln!("Warning: {}", message);
+        Ok(())
+    }
+}